@@ -3,10 +3,16 @@ use clap::{
         styling::{AnsiColor, Effects},
         Styles,
     },
-    Parser, Subcommand,
+    ArgAction, Parser, Subcommand,
 };
 
-use crate::commands::csv::Delimiter;
+use fxtools::{
+    commands::{
+        count::StatsFormat, csv::Delimiter, trim::Untrimmed, umi::UmiMethod, unique::Keep,
+        OutputFormat, OutputOptions, PairFilter, PairedOptions, RecordFormat,
+    },
+    report::Stats,
+};
 
 // Configures Clap v3-style help menu colors
 const STYLES: Styles = Styles::styled()
@@ -29,6 +35,97 @@ pub struct Cli {
     /// Compression level to use for output files if applicable
     #[clap(global = true, short = 'Z', long)]
     pub compression_level: Option<usize>,
+
+    /// Compression format to use for output files
+    /// [default: inferred from the file extension (.gz, .bgz, .zst)]
+    #[clap(global = true, long, value_enum)]
+    pub output_format: Option<OutputFormat>,
+
+    /// Write a `.gzi` index alongside BGZF output files
+    #[clap(global = true, long)]
+    pub bgzf_index: bool,
+
+    /// Record format to write instead of the format of the input records
+    #[clap(global = true, long, value_enum)]
+    pub out_format: Option<RecordFormat>,
+
+    /// Quality character used when writing FASTA records as FASTQ
+    #[clap(global = true, long, default_value = "I", value_parser = parse_quality)]
+    pub fake_qual: char,
+
+    /// Wrap FASTA sequences to lines of at most this many nucleotides
+    #[clap(global = true, long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub wrap: Option<u64>,
+
+    /// Write a JSON report of the run (inputs, parameters, record counts) to this path
+    #[clap(global = true, long)]
+    pub report: Option<String>,
+
+    /// Worker threads to use for per-record commands (clip, filter, fix, reverse, trim, upper)
+    /// and for the inputs of count
+    #[clap(global = true, long, default_value = "1")]
+    pub threads: usize,
+}
+impl Cli {
+    /// Collects the global output options, counting the run in `stats`
+    pub fn output_options(&self, stats: &Stats) -> OutputOptions {
+        OutputOptions {
+            compression_threads: self.compression_threads,
+            compression_level: self.compression_level,
+            output_format: self.output_format,
+            bgzf_index: self.bgzf_index,
+            record_format: self.out_format,
+            fake_qual: Some(self.fake_qual),
+            wrap: self.wrap.map(|width| width as usize),
+            stats: stats.clone(),
+        }
+    }
+}
+
+/// Parses a single printable ASCII quality character
+fn parse_quality(value: &str) -> Result<char, String> {
+    let mut chars = value.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if c.is_ascii_graphic() => Ok(c),
+        _ => Err(format!(
+            "expected a single printable ASCII character, found: {value}"
+        )),
+    }
+}
+
+/// Parses an error rate between 0 and 1
+fn parse_error_rate(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(rate) if (0.0..=1.0).contains(&rate) => Ok(rate),
+        _ => Err(format!("expected a number between 0 and 1, found: {value}")),
+    }
+}
+
+/// Parses a memory size in bytes with an optional `K`, `M`, or `G` suffix
+fn parse_memory_size(value: &str) -> Result<usize, String> {
+    let upper = value.trim().to_ascii_uppercase();
+    let upper = upper.strip_suffix('B').unwrap_or(&upper);
+    let (number, scale) = match upper.chars().last() {
+        Some('K') => (&upper[..upper.len() - 1], 1 << 10),
+        Some('M') => (&upper[..upper.len() - 1], 1 << 20),
+        Some('G') => (&upper[..upper.len() - 1], 1 << 30),
+        _ => (upper, 1),
+    };
+    match number.parse::<f64>() {
+        Ok(size) if size > 0.0 => Ok((size * scale as f64) as usize),
+        _ => Err(format!(
+            "expected a memory size such as 512M or 2G, found: {value}"
+        )),
+    }
+}
+
+/// Parses a non-empty string of nucleotides
+fn parse_bases(value: &str) -> Result<String, String> {
+    if !value.is_empty() && value.bytes().all(|b| b"ACGTNacgtn".contains(&b)) {
+        Ok(value.to_string())
+    } else {
+        Err(format!("expected nucleotides, found: {value}"))
+    }
 }
 
 #[derive(Subcommand)]
@@ -64,9 +161,33 @@ pub enum Commands {
 
     /// Counts the number of records in a Fastx file
     Count {
+        #[clap(short, long, value_parser, num_args = 1..)]
+        /// Input FASTA/Qs to count [default: stdin]
+        input: Vec<String>,
+
         #[clap(short, long, value_parser)]
-        /// Input FASTA/Q to Count
-        input: Option<String>,
+        /// Filepath to write output to [default: stdout]
+        output: Option<String>,
+
+        #[clap(short, long)]
+        /// Report sequence statistics for each input instead of only the record count
+        stats: bool,
+
+        #[clap(short, long, value_enum, default_value = "tsv", requires = "stats")]
+        /// Format of the statistics table
+        format: StatsFormat,
+
+        #[clap(long, conflicts_with = "stats")]
+        /// Fully parse every record instead of counting record delimiters
+        validate: bool,
+
+        #[clap(long, conflicts_with_all = ["stats", "group_by_seq"])]
+        /// Count records per group given by the first capture group of a regex on the header
+        group_by_header: Option<String>,
+
+        #[clap(long, conflicts_with = "stats")]
+        /// Count records per group given by the sequence in a range ([start]..[end])
+        group_by_seq: Option<String>,
     },
 
     /// Clip nucleotide sequences between two indices
@@ -91,6 +212,45 @@ pub enum Commands {
         /// Range of nucleotides to accept (everything else is clipped)
         /// Format: [start]..[end]
         range: Option<String>,
+
+        #[clap(short, long)]
+        /// Phred cutoff for BWA-style quality trimming of the 3' end
+        quality_cutoff: Option<u8>,
+
+        #[clap(long)]
+        /// Phred cutoff for BWA-style quality trimming of the 5' end
+        quality_cutoff_5p: Option<u8>,
+
+        #[clap(long, requires = "window_quality", value_parser = clap::value_parser!(u64).range(1..))]
+        /// Size of the sliding window used to trim the 3' end once its mean quality drops
+        window_size: Option<u64>,
+
+        #[clap(long, requires = "window_size")]
+        /// Mean Phred cutoff of the sliding window
+        window_quality: Option<f64>,
+
+        #[clap(long, value_parser = parse_bases)]
+        /// Bases whose homopolymer tails are trimmed from the 3' end, in order (e.g. `GA`)
+        poly_3p: Option<String>,
+
+        #[clap(long, value_parser = parse_bases)]
+        /// Bases whose homopolymer tails are trimmed from the 5' end, in order
+        poly_5p: Option<String>,
+
+        #[clap(long, default_value = "10")]
+        /// Minimum length of a trimmed homopolymer tail
+        poly_min_length: usize,
+
+        #[clap(long, default_value = "0", value_parser = parse_error_rate)]
+        /// Maximum fraction of other bases within a trimmed homopolymer tail
+        poly_error_rate: f64,
+
+        #[clap(short, long, default_value = "0")]
+        /// Minimum sequence length after clipping (shorter records are dropped)
+        min_length: usize,
+
+        #[clap(flatten)]
+        paired: PairedOptions,
     },
 
     /// Converts a CSV file to a FASTA file
@@ -171,6 +331,13 @@ pub enum Commands {
         #[clap(short = 'H', long, value_parser, default_value = "false")]
         /// Whether to search for the pattern in the header
         header: bool,
+
+        #[clap(long, value_enum, default_value = "either")]
+        /// Whether a pair is kept if either or both mates pass
+        pair_filter: PairFilter,
+
+        #[clap(flatten)]
+        paired: PairedOptions,
     },
 
     /// Fix a fastx file by replacing invalid characters with N
@@ -256,6 +423,8 @@ pub enum Commands {
         #[clap(short, long, value_parser, default_value = "false")]
         /// Don't write number of records sampled to stderr
         quiet: bool,
+        #[clap(flatten)]
+        paired: PairedOptions,
     },
 
     /// Creates a mapping of sgRNAs to their parent gene
@@ -297,18 +466,47 @@ pub enum Commands {
         r2: Option<String>,
 
         #[clap(short, long, value_parser)]
-        /// Prefix to write sorted files to
+        /// Prefix to write sorted files to, named by mate and input format
+        /// (e.g. `<prefix>_R1.fastq.gz`)
         /// if single-end [default: stdout]
         /// if paired-end [default: sorted]
         prefix: Option<String>,
 
-        #[clap(short, long, value_parser, default_value = "true")]
-        /// Whether to gzip the output files
+        #[clap(long, value_parser, requires_all = ["r2", "output_r2"], conflicts_with = "prefix")]
+        /// Filepath to write sorted R1 records to
+        output_r1: Option<String>,
+
+        #[clap(long, value_parser, requires = "output_r1")]
+        /// Filepath to write sorted R2 records to
+        output_r2: Option<String>,
+
+        #[clap(long, requires = "r2", conflicts_with_all = ["prefix", "output_r1"])]
+        /// Write sorted pairs interleaved to stdout
+        interleaved: bool,
+
+        #[clap(short, long, action = ArgAction::Set, num_args = 0..=1, default_value = "true", default_missing_value = "true")]
+        /// Whether to gzip the prefixed output files (`--gzip false` to disable)
         gzip: bool,
 
         #[clap(short, long, value_parser, default_value = "false")]
         /// Whether to sort by R1 or R2
         sort_by_r1: bool,
+
+        #[clap(short, long = "key", value_delimiter = ',')]
+        /// Keys to sort by in order of precedence, each optionally suffixed by `:rev` to
+        /// reverse it (sequence, header, natural, length, gc, quality, capture)
+        /// [default: sequence]
+        keys: Vec<String>,
+
+        #[clap(long, value_parser)]
+        /// Regex on the header whose first capture group (or whole match) is sorted by with
+        /// the `capture` key
+        capture: Option<String>,
+
+        #[clap(long, value_parser = parse_memory_size)]
+        /// Sort chunks of at most this much memory (e.g. 2G) and merge them from
+        /// compressed temporary files [default: sort all records in memory]
+        max_memory: Option<usize>,
     },
 
     /// Extracts the transcript to gene mapping from an ensembl cdna fasta file
@@ -349,6 +547,8 @@ pub enum Commands {
         #[clap(short, long, value_parser, default_value = "0")]
         /// How many records to skip before taking the first n
         skip: usize,
+        #[clap(flatten)]
+        paired: PairedOptions,
     },
 
     /// Trims adapter sequences that are dynamically placed within the sequence.
@@ -357,9 +557,14 @@ pub enum Commands {
         /// Input FASTA/Q to trim sequences
         input: Option<String>,
 
-        #[clap(short, long, value_parser)]
-        /// Adapater sequence to trim
-        adapter: String,
+        #[clap(short, long, value_parser, num_args = 1.., required_unless_present = "adapter_file")]
+        /// Adapter sequences to trim (`^ADAPTER` and `ADAPTER$` are anchored to the start
+        /// and end of the sequence, `FIVE...THREE` keeps only the insert between them)
+        adapter: Vec<String>,
+
+        #[clap(long, value_parser)]
+        /// FASTA file of named adapter sequences to trim
+        adapter_file: Option<String>,
 
         #[clap(short, long, value_parser)]
         /// Filepath to write output to [default: stdout]
@@ -368,6 +573,53 @@ pub enum Commands {
         #[clap(short, long, value_parser, default_value = "false")]
         /// Trim the adapter off the sequence
         trim_adapter: bool,
+
+        #[clap(long, conflicts_with = "trim_adapter")]
+        /// Treat single adapters as 3' adapters, keeping the sequence preceding them
+        /// (the adapter and the sequence following it are trimmed)
+        three_prime: bool,
+
+        #[clap(short, long, default_value = "0", value_parser = parse_error_rate)]
+        /// Maximum number of errors per aligned adapter nucleotide
+        error_rate: f64,
+
+        #[clap(long)]
+        /// Count insertions and deletions as errors (otherwise only mismatches are allowed)
+        indels: bool,
+
+        #[clap(long)]
+        /// Minimum overlap of a partial 3' adapter (`--three-prime` or `...THREE`) at the
+        /// 3' end of the sequence [default: only full adapters are matched]
+        min_overlap: Option<usize>,
+
+        #[clap(long)]
+        /// Append the number of adapter errors to the header of trimmed records
+        tag_errors: bool,
+
+        #[clap(
+            long,
+            value_enum,
+            default_value = "discard",
+            conflicts_with = "untrimmed_output"
+        )]
+        /// Whether records without an adapter are dropped or written unchanged
+        untrimmed: Untrimmed,
+
+        #[clap(long, value_parser)]
+        /// Filepath to write records without an adapter to
+        untrimmed_output: Option<String>,
+
+        #[clap(long, value_parser, requires_all = ["untrimmed_output", "pairing"])]
+        /// Filepath to write R2 mates of pairs without an adapter to [default: interleaved
+        /// with the R1 untrimmed output]
+        untrimmed_output_r2: Option<String>,
+
+        #[clap(long, value_enum, default_value = "either")]
+        /// Whether a pair is kept if the adapter is found in either or both mates
+        pair_filter: PairFilter,
+
+        #[clap(flatten)]
+        paired: PairedOptions,
     },
 
     /// Filters the Fastx file for Unique Sequences
@@ -387,6 +639,67 @@ pub enum Commands {
         #[clap(short, long, value_parser)]
         /// Allow invalid nucleotides in output
         allow_invalid: bool,
+
+        #[clap(short, long, conflicts_with = "null")]
+        /// Write one record per distinct sequence with its copy number appended to the
+        /// header as `count=<n>`
+        collapse: bool,
+
+        #[clap(long, value_parser, requires = "collapse")]
+        /// Filepath to write a table of sequences, copy numbers and first record ids to
+        /// (headers are left unchanged)
+        counts: Option<String>,
+
+        #[clap(long, requires = "collapse")]
+        /// Sort collapsed records by decreasing copy number
+        sort_by_abundance: bool,
+
+        #[clap(long, conflicts_with_all = ["max_memory", "sort_by_abundance"])]
+        /// Hold only 128-bit sequence fingerprints in memory and read the input twice
+        fingerprint: bool,
+
+        #[clap(long, value_parser = parse_memory_size, conflicts_with = "sort_by_abundance")]
+        /// Spill records to temporary files once they exceed this much memory (e.g. 2G)
+        max_memory: Option<usize>,
+
+        #[clap(long, conflicts_with = "by_range")]
+        /// Identify duplicates by their record id instead of their sequence (the id of the
+        /// first mate for pairs)
+        by_id: bool,
+
+        #[clap(long, value_parser)]
+        /// Identify duplicates by a range of their sequence (Format: [start]..[end])
+        by_range: Option<String>,
+
+        #[clap(long, value_enum, conflicts_with = "collapse")]
+        /// Write one record of each group of duplicates to the output and the remaining
+        /// ones to the null output
+        keep: Option<Keep>,
+
+        #[clap(long, conflicts_with_all = ["umi_range", "null", "collapse", "fingerprint", "max_memory", "by_id", "by_range", "keep"])]
+        /// Deduplicate on UMIs taken from the first capture group (or the whole match) of a
+        /// regex on the header, and the sequence (holds every record in memory)
+        umi_header: Option<String>,
+
+        #[clap(long, conflicts_with_all = ["null", "collapse", "fingerprint", "max_memory", "by_id", "by_range", "keep"])]
+        /// Deduplicate on UMIs taken from a range of the sequence, and the remaining
+        /// sequence (Format: [start]..[end]; holds every record in memory)
+        umi_range: Option<String>,
+
+        #[clap(long, default_value = "1")]
+        /// Maximum Hamming distance between UMIs merged into a cluster
+        umi_distance: usize,
+
+        #[clap(long, value_enum, default_value = "directional")]
+        /// How UMIs within the Hamming distance are merged into clusters
+        umi_method: UmiMethod,
+
+        #[clap(long, value_parser)]
+        /// Filepath to write the UMI cluster and representative of each record to
+        umi_clusters: Option<String>,
+
+        #[clap(flatten)]
+        paired: PairedOptions,
     },
 
     /// Converts all lowercase nucleotides to uppercase
@@ -403,5 +716,7 @@ pub enum Commands {
         #[clap(short, long, value_parser)]
         /// Allow invalid nucleotides in output
         allow_invalid: bool,
+        #[clap(flatten)]
+        paired: PairedOptions,
     },
 }
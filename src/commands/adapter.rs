@@ -0,0 +1,636 @@
+use anyhow::{bail, Result};
+use fxread::Record;
+use memchr::memmem;
+use std::ops::Range;
+
+/// Location of an adapter match within a sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdapterMatch {
+    /// Start of the match in the sequence
+    pub start: usize,
+    /// End of the match in the sequence (exclusive)
+    pub end: usize,
+    /// Number of adapter nucleotides aligned (shorter than the adapter for partial matches)
+    pub overlap: usize,
+    /// Number of mismatches and indels in the alignment
+    pub errors: usize,
+}
+
+/// Parameters of the adapter search
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MatchOptions {
+    /// Maximum number of errors per aligned adapter nucleotide
+    pub error_rate: f64,
+    /// Whether insertions and deletions are counted as errors (otherwise only mismatches)
+    pub indels: bool,
+    /// Minimum overlap of a partial adapter overhanging the 3' end of the sequence
+    ///
+    /// Partial matches are disabled if this is at least the adapter length.
+    pub min_overlap: usize,
+}
+impl Default for MatchOptions {
+    /// Exact matches of the full adapter
+    fn default() -> Self {
+        Self {
+            error_rate: 0.0,
+            indels: false,
+            min_overlap: usize::MAX,
+        }
+    }
+}
+impl MatchOptions {
+    /// Maximum number of errors allowed for an alignment of `overlap` adapter nucleotides
+    fn max_errors(&self, overlap: usize) -> usize {
+        (self.error_rate * overlap as f64).floor() as usize
+    }
+}
+
+/// Finds the adapter in the sequence
+///
+/// Matches of the full adapter are preferred, choosing the match with the fewest errors
+/// and then the leftmost one. Otherwise the longest partial adapter prefix overhanging the
+/// 3' end of the sequence is returned.
+pub fn find_adapter(seq: &[u8], adapter: &[u8], options: &MatchOptions) -> Option<AdapterMatch> {
+    if adapter.is_empty() {
+        return None;
+    }
+    let max_errors = options.max_errors(adapter.len());
+    if options.indels && max_errors > 0 {
+        return align_semiglobal(seq, adapter, options);
+    }
+    let full = if max_errors == 0 {
+        memmem::find(seq, adapter).map(|start| AdapterMatch {
+            start,
+            end: start + adapter.len(),
+            overlap: adapter.len(),
+            errors: 0,
+        })
+    } else {
+        find_hamming(seq, adapter, max_errors)
+    };
+    full.or_else(|| find_partial(seq, adapter, options))
+}
+
+/// Number of mismatching positions between two sequences of equal length, stopping
+/// early once `limit` is exceeded
+pub fn hamming(x: &[u8], y: &[u8], limit: usize) -> usize {
+    let mut errors = 0;
+    for (a, b) in x.iter().zip(y) {
+        if a != b {
+            errors += 1;
+            if errors > limit {
+                break;
+            }
+        }
+    }
+    errors
+}
+
+/// Finds the full adapter allowing only mismatches
+fn find_hamming(seq: &[u8], adapter: &[u8], max_errors: usize) -> Option<AdapterMatch> {
+    let mut best: Option<AdapterMatch> = None;
+    for start in 0..=seq.len().checked_sub(adapter.len())? {
+        let limit = best.map_or(max_errors, |x| x.errors.saturating_sub(1));
+        let errors = hamming(&seq[start..start + adapter.len()], adapter, limit);
+        if errors <= limit && best.is_none_or(|x| errors < x.errors) {
+            best = Some(AdapterMatch {
+                start,
+                end: start + adapter.len(),
+                overlap: adapter.len(),
+                errors,
+            });
+            if errors == 0 {
+                break;
+            }
+        }
+    }
+    best
+}
+
+/// Finds the longest adapter prefix overhanging the 3' end allowing only mismatches
+fn find_partial(seq: &[u8], adapter: &[u8], options: &MatchOptions) -> Option<AdapterMatch> {
+    let longest = (adapter.len() - 1).min(seq.len());
+    (options.min_overlap.max(1)..=longest)
+        .rev()
+        .find_map(|overlap| {
+            let start = seq.len() - overlap;
+            let max_errors = options.max_errors(overlap);
+            let errors = hamming(&seq[start..], &adapter[..overlap], max_errors);
+            (errors <= max_errors).then_some(AdapterMatch {
+                start,
+                end: seq.len(),
+                overlap,
+                errors,
+            })
+        })
+}
+
+/// Aligns the adapter along the sequence with unit costs for mismatches, insertions, and
+/// deletions, calling `visit` with the column of costs after each sequence nucleotide
+///
+/// Each cell holds the cost of aligning the adapter prefix of its index to a stretch of
+/// the sequence ending at the current nucleotide, and the start of that stretch. The
+/// start of the sequence may be skipped freely unless the alignment is `anchored`.
+/// Returns the final column.
+fn align<F>(seq: &[u8], adapter: &[u8], anchored: bool, mut visit: F) -> Vec<(usize, usize)>
+where
+    F: FnMut(usize, &[(usize, usize)]),
+{
+    let mut column = (0..=adapter.len()).map(|i| (i, 0)).collect::<Vec<_>>();
+    for (j, base) in seq.iter().enumerate() {
+        let mut diagonal = column[0];
+        column[0] = if anchored { (j + 1, 0) } else { (0, j + 1) };
+        for i in 1..=adapter.len() {
+            let substitution = (
+                diagonal.0 + usize::from(adapter[i - 1] != *base),
+                diagonal.1,
+            );
+            let insertion = (column[i].0 + 1, column[i].1);
+            let deletion = (column[i - 1].0 + 1, column[i - 1].1);
+            diagonal = column[i];
+            column[i] = [insertion, deletion]
+                .into_iter()
+                .fold(substitution, |x, y| if y.0 < x.0 { y } else { x });
+        }
+        visit(j + 1, &column);
+    }
+    column
+}
+
+/// Semi-global alignment of the adapter against the sequence
+///
+/// The adapter must be aligned entirely while the sequence may be skipped freely on both
+/// ends, and adapter prefixes may overhang the 3' end of the sequence.
+fn align_semiglobal(seq: &[u8], adapter: &[u8], options: &MatchOptions) -> Option<AdapterMatch> {
+    let m = adapter.len();
+    let max_errors = options.max_errors(m);
+    let mut best: Option<AdapterMatch> = None;
+    let column = align(seq, adapter, false, |end, column| {
+        let (errors, start) = column[m];
+        if errors <= max_errors && best.is_none_or(|x| errors < x.errors) {
+            best = Some(AdapterMatch {
+                start,
+                end,
+                overlap: m,
+                errors,
+            });
+        }
+    });
+    if best.is_some() {
+        return best;
+    }
+
+    // Partial adapter prefixes ending at the 3' end of the sequence
+    let longest = (m - 1).min(seq.len());
+    (options.min_overlap.max(1)..=longest)
+        .rev()
+        .find_map(|overlap| {
+            let (errors, start) = column[overlap];
+            (errors <= options.max_errors(overlap)).then_some(AdapterMatch {
+                start,
+                end: seq.len(),
+                overlap,
+                errors,
+            })
+        })
+}
+
+/// Finds the full adapter at the start of the sequence
+fn find_anchored_start(seq: &[u8], adapter: &[u8], options: &MatchOptions) -> Option<AdapterMatch> {
+    let m = adapter.len();
+    let max_errors = options.max_errors(m);
+    let mut best: Option<AdapterMatch> = None;
+    if options.indels && max_errors > 0 {
+        align(seq, adapter, true, |end, column| {
+            let (errors, _) = column[m];
+            if errors <= max_errors && best.is_none_or(|x| errors < x.errors) {
+                best = Some(AdapterMatch {
+                    start: 0,
+                    end,
+                    overlap: m,
+                    errors,
+                });
+            }
+        });
+    } else if seq.len() >= m {
+        let errors = hamming(&seq[..m], adapter, max_errors);
+        if errors <= max_errors {
+            best = Some(AdapterMatch {
+                start: 0,
+                end: m,
+                overlap: m,
+                errors,
+            });
+        }
+    }
+    best
+}
+
+/// Finds the full adapter at the end of the sequence
+fn find_anchored_end(seq: &[u8], adapter: &[u8], options: &MatchOptions) -> Option<AdapterMatch> {
+    let m = adapter.len();
+    let max_errors = options.max_errors(m);
+    let (errors, start) = if options.indels && max_errors > 0 {
+        align(seq, adapter, false, |_, _| {})[m]
+    } else {
+        let start = seq.len().checked_sub(m)?;
+        (hamming(&seq[start..], adapter, max_errors), start)
+    };
+    (errors <= max_errors && !seq.is_empty()).then_some(AdapterMatch {
+        start,
+        end: seq.len(),
+        overlap: m,
+        errors,
+    })
+}
+
+/// Position of the sequence an adapter is anchored to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Anchor {
+    /// The adapter may occur anywhere
+    None,
+    /// The adapter must occur at the start of the sequence (`^ADAPTER`)
+    Start,
+    /// The adapter must occur at the end of the sequence (`ADAPTER$`)
+    End,
+}
+
+/// An adapter sequence with its anchoring
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdapterSeq {
+    /// Nucleotides of the adapter
+    pub seq: Vec<u8>,
+    /// Anchoring of the adapter
+    pub anchor: Anchor,
+}
+impl AdapterSeq {
+    /// Parses an adapter sequence optionally prefixed by `^` or suffixed by `$`
+    pub fn parse(spec: &str) -> Result<Self> {
+        let (spec, anchor) = if let Some(spec) = spec.strip_prefix('^') {
+            (spec, Anchor::Start)
+        } else if let Some(spec) = spec.strip_suffix('$') {
+            (spec, Anchor::End)
+        } else {
+            (spec, Anchor::None)
+        };
+        if spec.is_empty() || !spec.bytes().all(|b| b.is_ascii_alphabetic()) {
+            bail!("Invalid adapter sequence: {spec:?}");
+        }
+        Ok(Self {
+            seq: spec.as_bytes().to_vec(),
+            anchor,
+        })
+    }
+
+    /// Finds the adapter in the sequence respecting its anchoring
+    pub fn find(&self, seq: &[u8], options: &MatchOptions) -> Option<AdapterMatch> {
+        match self.anchor {
+            Anchor::None => find_adapter(seq, &self.seq, options),
+            Anchor::Start => find_anchored_start(seq, &self.seq, options),
+            Anchor::End => find_anchored_end(seq, &self.seq, options),
+        }
+    }
+}
+
+/// Part of the sequence kept around a single adapter
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeepSide {
+    /// The adapter and the sequence following it (5' adapters)
+    #[default]
+    WithAdapter,
+    /// The sequence following the adapter (5' adapters)
+    AfterAdapter,
+    /// The sequence preceding the adapter (3' adapters), which is the only side for which
+    /// partial adapters overhanging the 3' end are matched
+    BeforeAdapter,
+}
+impl KeepSide {
+    /// Selects the side from the `--trim-adapter` and `--three-prime` flags
+    pub fn new(trim_adapter: bool, three_prime: bool) -> Self {
+        match (three_prime, trim_adapter) {
+            (true, _) => Self::BeforeAdapter,
+            (false, true) => Self::AfterAdapter,
+            (false, false) => Self::WithAdapter,
+        }
+    }
+}
+
+/// How an adapter is matched and trimmed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdapterKind {
+    /// An adapter whose preceding sequence is trimmed
+    Single(AdapterSeq),
+    /// A 5' and/or 3' adapter (`FIVE...THREE`) of which only the insert between them
+    /// is kept
+    ///
+    /// Both adapters must be found, with the 3' adapter following the 5' adapter.
+    Linked {
+        five: Option<AdapterSeq>,
+        three: Option<AdapterSeq>,
+    },
+}
+
+/// A named adapter
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Adapter {
+    /// Name of the adapter used in statistics
+    pub name: String,
+    /// How the adapter is matched and trimmed
+    pub kind: AdapterKind,
+}
+
+/// The part of a sequence kept after trimming an adapter
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdapterHit {
+    /// Range of the sequence to keep
+    pub keep: Range<usize>,
+    /// Start of the first matched adapter in the sequence
+    pub position: usize,
+    /// Total number of errors across the matched adapters
+    pub errors: usize,
+    /// Total number of aligned adapter nucleotides which are not errors
+    pub score: usize,
+}
+
+impl Adapter {
+    /// Parses an adapter specification
+    ///
+    /// Adapters are given as `ADAPTER`, anchored as `^ADAPTER` or `ADAPTER$`, or linked
+    /// as `FIVE...THREE` where either side may be omitted or anchored.
+    pub fn parse(name: &str, spec: &str) -> Result<Self> {
+        let kind = match spec.split_once("...") {
+            Some((five, three)) => {
+                if five.is_empty() && three.is_empty() {
+                    bail!("Linked adapters require at least one sequence: {spec:?}");
+                }
+                let parse = |x: &str| (!x.is_empty()).then(|| AdapterSeq::parse(x)).transpose();
+                AdapterKind::Linked {
+                    five: parse(five)?,
+                    three: parse(three)?,
+                }
+            }
+            None => AdapterKind::Single(AdapterSeq::parse(spec)?),
+        };
+        Ok(Self {
+            name: name.to_string(),
+            kind,
+        })
+    }
+
+    /// Length of the longest possible alignment of the adapter
+    pub fn len(&self) -> usize {
+        match &self.kind {
+            AdapterKind::Single(adapter) => adapter.seq.len(),
+            AdapterKind::Linked { five, three } => [five, three]
+                .into_iter()
+                .flatten()
+                .map(|x| x.seq.len())
+                .sum(),
+        }
+    }
+
+    /// Whether the adapter has no nucleotides
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Finds the adapter in the sequence and returns the part of the sequence to keep
+    ///
+    /// Single adapters keep the side of the match selected by `side`. Partial adapters
+    /// overhanging the 3' end are only matched when keeping the preceding sequence, since
+    /// nothing follows them.
+    pub fn find(&self, seq: &[u8], options: &MatchOptions, side: KeepSide) -> Option<AdapterHit> {
+        let score = |x: &AdapterMatch| x.overlap.saturating_sub(x.errors);
+        match &self.kind {
+            AdapterKind::Single(adapter) => {
+                let hit = if side == KeepSide::BeforeAdapter {
+                    adapter.find(seq, options)?
+                } else {
+                    let full = MatchOptions {
+                        min_overlap: usize::MAX,
+                        ..*options
+                    };
+                    adapter.find(seq, &full)?
+                };
+                let keep = match side {
+                    KeepSide::WithAdapter => hit.start..seq.len(),
+                    KeepSide::AfterAdapter => hit.end..seq.len(),
+                    KeepSide::BeforeAdapter => 0..hit.start,
+                };
+                Some(AdapterHit {
+                    keep,
+                    position: hit.start,
+                    errors: hit.errors,
+                    score: score(&hit),
+                })
+            }
+            AdapterKind::Linked { five, three } => {
+                // the 5' adapter must be present in full
+                let five_options = MatchOptions {
+                    min_overlap: usize::MAX,
+                    ..*options
+                };
+                let five = match five {
+                    Some(adapter) => Some(adapter.find(seq, &five_options)?),
+                    None => None,
+                };
+                let offset = five.map_or(0, |x| x.end);
+                let three = match three {
+                    Some(adapter) => {
+                        let hit = adapter.find(&seq[offset..], options)?;
+                        Some(AdapterMatch {
+                            start: hit.start + offset,
+                            end: hit.end + offset,
+                            ..hit
+                        })
+                    }
+                    None => None,
+                };
+                let hits = [five, three].into_iter().flatten();
+                Some(AdapterHit {
+                    keep: offset..three.map_or(seq.len(), |x| x.start),
+                    position: five.or(three).map_or(0, |x| x.start),
+                    errors: hits.clone().map(|x| x.errors).sum(),
+                    score: hits.map(|x| score(&x)).sum(),
+                })
+            }
+        }
+    }
+}
+
+/// Reads named adapters from FASTA records
+pub fn read_adapters<I: Iterator<Item = Record>>(reader: I) -> Result<Vec<Adapter>> {
+    reader
+        .map(|record| Adapter::parse(record.id_str(), record.seq_str()))
+        .collect()
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    fn options(error_rate: f64, indels: bool, min_overlap: usize) -> MatchOptions {
+        MatchOptions {
+            error_rate,
+            indels,
+            min_overlap,
+        }
+    }
+
+    #[test]
+    fn exact_match() {
+        let hit = find_adapter(b"AAGGTTCC", b"GGT", &MatchOptions::default()).unwrap();
+        assert_eq!((hit.start, hit.end, hit.errors), (2, 5, 0));
+        assert!(find_adapter(b"AAGCTTCC", b"GGT", &MatchOptions::default()).is_none());
+    }
+
+    #[test]
+    fn mismatch_match() {
+        let adapter = b"ACGTACGTAC";
+        let options = options(0.1, false, usize::MAX);
+        let hit = find_adapter(b"TTTTACGTTCGTACTT", adapter, &options).unwrap();
+        assert_eq!((hit.start, hit.end, hit.errors), (4, 14, 1));
+        assert!(find_adapter(b"TTTTACGTTCGTTCTT", adapter, &options).is_none());
+
+        // the match with fewer errors is preferred over the leftmost one
+        let hit = find_adapter(b"ACGTTCGTACGGACGTACGTAC", adapter, &options).unwrap();
+        assert_eq!((hit.start, hit.errors), (12, 0));
+    }
+
+    #[test]
+    fn indel_match() {
+        let adapter = b"ACGTACGTAC";
+        let mismatches = options(0.1, false, usize::MAX);
+        let indels = options(0.1, true, usize::MAX);
+
+        // deletion of a single adapter nucleotide
+        let seq = b"TTTTACGTCGTACTT";
+        assert!(find_adapter(seq, adapter, &mismatches).is_none());
+        let hit = find_adapter(seq, adapter, &indels).unwrap();
+        assert_eq!((hit.start, hit.end, hit.errors), (4, 13, 1));
+
+        // insertion of a single nucleotide
+        let seq = b"TTTTACGTAACGTACTT";
+        let hit = find_adapter(seq, adapter, &indels).unwrap();
+        assert_eq!((hit.start, hit.end, hit.errors), (4, 15, 1));
+    }
+
+    #[test]
+    fn partial_match() {
+        let adapter = b"ACGTACGTAC";
+        let hit = find_adapter(b"TTTTTTACGT", adapter, &options(0.0, false, 3)).unwrap();
+        assert_eq!((hit.start, hit.end, hit.overlap), (6, 10, 4));
+        assert!(find_adapter(b"TTTTTTTTAC", adapter, &options(0.0, false, 3)).is_none());
+        assert!(find_adapter(b"TTTTTTACGT", adapter, &MatchOptions::default()).is_none());
+
+        // errors are allowed relative to the overlap
+        let hit = find_adapter(b"TTTTTACCTACGT", adapter, &options(0.2, false, 3)).unwrap();
+        assert_eq!((hit.start, hit.overlap, hit.errors), (5, 8, 1));
+        let hit = find_adapter(b"TTTTTACCTACGT", adapter, &options(0.2, true, 3)).unwrap();
+        assert_eq!((hit.start, hit.overlap, hit.errors), (5, 8, 1));
+    }
+
+    #[test]
+    fn anchored_match() {
+        let exact = MatchOptions::default();
+        let start = AdapterSeq::parse("^ACGT").unwrap();
+        assert_eq!(start.anchor, Anchor::Start);
+        assert_eq!(start.find(b"ACGTTT", &exact).unwrap().end, 4);
+        assert!(start.find(b"TACGTT", &exact).is_none());
+
+        let end = AdapterSeq::parse("ACGT$").unwrap();
+        assert_eq!(end.anchor, Anchor::End);
+        assert_eq!(end.find(b"TTACGT", &exact).unwrap().start, 2);
+        assert!(end.find(b"TACGTT", &exact).is_none());
+
+        // an insertion at the start of an anchored adapter
+        let indels = options(0.25, true, usize::MAX);
+        let hit = start.find(b"AACGTTT", &indels).unwrap();
+        assert_eq!((hit.start, hit.end, hit.errors), (0, 5, 1));
+        let hit = end.find(b"TTACGTT", &indels).unwrap();
+        assert_eq!((hit.end, hit.errors), (7, 1));
+
+        assert!(AdapterSeq::parse("^").is_err());
+        assert!(AdapterSeq::parse("AC-GT").is_err());
+    }
+
+    #[test]
+    fn linked_adapter() {
+        let exact = MatchOptions::default();
+        let adapter = Adapter::parse("linked", "^AAAA...CCCC").unwrap();
+        let hit = adapter
+            .find(b"AAAAGTGTCCCCTT", &exact, KeepSide::WithAdapter)
+            .unwrap();
+        assert_eq!(hit.keep, 4..8);
+        assert_eq!((hit.position, hit.errors, hit.score), (0, 0, 8));
+
+        // both adapters are required and must be in order
+        assert!(adapter
+            .find(b"AAAAGTGTGTTT", &exact, KeepSide::WithAdapter)
+            .is_none());
+        assert!(adapter
+            .find(b"CCCCAAAAGTGT", &exact, KeepSide::WithAdapter)
+            .is_none());
+
+        // the 3' adapter may be partial at the end of the sequence
+        let partial = options(0.0, false, 2);
+        let hit = adapter
+            .find(b"AAAAGTGTCC", &partial, KeepSide::WithAdapter)
+            .unwrap();
+        assert_eq!(hit.keep, 4..8);
+
+        let three = Adapter::parse("three", "...CCCC").unwrap();
+        assert_eq!(
+            three
+                .find(b"GTGTCCCCTT", &exact, KeepSide::WithAdapter)
+                .unwrap()
+                .keep,
+            0..4
+        );
+        assert!(Adapter::parse("none", "...").is_err());
+    }
+
+    #[test]
+    fn single_adapter() {
+        let exact = MatchOptions::default();
+        let adapter = Adapter::parse("single", "GGT").unwrap();
+        assert_eq!(adapter.len(), 3);
+        assert_eq!(
+            adapter
+                .find(b"AAGGTTCC", &exact, KeepSide::WithAdapter)
+                .unwrap()
+                .keep,
+            2..8
+        );
+        let after = KeepSide::AfterAdapter;
+        assert_eq!(adapter.find(b"AAGGTTCC", &exact, after).unwrap().keep, 5..8);
+        let before = KeepSide::BeforeAdapter;
+        assert_eq!(
+            adapter.find(b"AAGGTTCC", &exact, before).unwrap().keep,
+            0..2
+        );
+
+        // partial adapters at the 3' end are only matched as 3' adapters
+        let partial = options(0.0, false, 2);
+        let adapter = Adapter::parse("single", "ACGTACGT").unwrap();
+        let seq = b"GGGGCCCCAAAATTTTAC";
+        assert!(adapter.find(seq, &partial, KeepSide::WithAdapter).is_none());
+        assert!(adapter.find(seq, &partial, after).is_none());
+        let hit = adapter.find(seq, &partial, before).unwrap();
+        assert_eq!(hit.keep, 0..16);
+        assert_eq!(hit.position, 16);
+
+        // full matches are preferred over partial ones
+        let hit = adapter.find(b"GGACGTACGTCCAC", &partial, before).unwrap();
+        assert_eq!(hit.keep, 0..2);
+    }
+
+    #[test]
+    fn adapters_from_fasta() {
+        let fasta: &[u8] = b">first\nACGT\n>second\n^AAA...CCC\n";
+        let adapters = read_adapters(fxread::FastaReader::new(fasta)).unwrap();
+        assert_eq!(adapters.len(), 2);
+        assert_eq!(adapters[1].name, "second");
+        assert_eq!(adapters[1].len(), 6);
+    }
+}
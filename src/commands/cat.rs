@@ -1,20 +1,28 @@
-use super::match_output_stream;
+use super::{match_fastx_reader, match_output_stream, write_record, OutputOptions};
+use crate::report::Stats;
 use anyhow::Result;
-use fxread::{initialize_reader, Record};
+use fxread::Record;
 use std::io::Write;
 
-fn write_file<R, W>(reader: R, writer: &mut W) -> Result<()>
+/// Writes all records of the reader to the writer
+pub fn write_file<R, W>(reader: R, writer: &mut W, options: &OutputOptions) -> Result<()>
 where
     R: Iterator<Item = Record>,
     W: Write,
 {
     for record in reader {
-        write!(writer, "{}", record.as_str())?;
+        write_record(writer, &record, options)?;
     }
     Ok(())
 }
 
-fn write_sequences<R, W>(reader: R, writer: &mut W, single_line: bool) -> Result<()>
+/// Writes only the sequences of the reader to the writer, counting them in `stats`
+pub fn write_sequences<R, W>(
+    reader: R,
+    writer: &mut W,
+    single_line: bool,
+    stats: &Stats,
+) -> Result<()>
 where
     R: Iterator<Item = Record>,
     W: Write,
@@ -25,17 +33,20 @@ where
         } else {
             writeln!(writer, "{}", record.seq_str())?;
         }
+        stats.written(record.seq().len());
     }
     Ok(())
 }
 
-fn write_headers<R, W>(reader: R, writer: &mut W) -> Result<()>
+/// Writes only the headers of the reader to the writer, counting them in `stats`
+pub fn write_headers<R, W>(reader: R, writer: &mut W, stats: &Stats) -> Result<()>
 where
     R: Iterator<Item = Record>,
     W: Write,
 {
     for record in reader {
         writeln!(writer, "{}", record.id_str())?;
+        stats.written(0);
     }
     Ok(())
 }
@@ -47,19 +58,18 @@ pub fn run(
     sequence_only: bool,
     headers_only: bool,
     single_line: bool,
-    compression_threads: Option<usize>,
-    compression_level: Option<usize>,
+    options: OutputOptions,
 ) -> Result<()> {
-    let mut writer = match_output_stream(output, compression_threads, compression_level)?;
+    let mut writer = match_output_stream(output, options.clone())?;
     for filename in inputs {
-        let reader = initialize_reader(&filename)?;
+        let reader = match_fastx_reader(Some(filename), &options.stats)?;
         if sequence_only {
-            write_sequences(reader, &mut writer, single_line)?;
+            write_sequences(reader, &mut writer, single_line, &options.stats)?;
         } else if headers_only {
-            write_headers(reader, &mut writer)?;
+            write_headers(reader, &mut writer, &options.stats)?;
         } else {
-            write_file(reader, &mut writer)?;
+            write_file(reader, &mut writer, &options)?;
         }
     }
-    Ok(())
+    writer.finish()
 }
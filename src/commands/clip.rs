@@ -1,8 +1,12 @@
-use super::match_output_stream;
-use anyhow::Result;
-use fxread::{initialize_reader, initialize_stdin_reader, Record};
-use std::io::stdin;
+use super::{
+    match_fastx_reader, match_output_stream, match_paired_reader, match_paired_writer,
+    process_pairs, process_records, OutputOptions, PairedOptions, PHRED_OFFSET,
+};
+use anyhow::{bail, Result};
+use fxread::Record;
+use std::io::Write;
 
+/// Clips a number of nucleotides from the start and end of the record
 pub fn clip(record: Record, start: Option<usize>, end: Option<usize>) -> Result<Record> {
     let mut record = record;
     if let Some(start) = start {
@@ -14,12 +18,11 @@ pub fn clip(record: Record, start: Option<usize>, end: Option<usize>) -> Result<
     Ok(record)
 }
 
+/// Clips the record to the nucleotides between the start and end indices
 pub fn clip_to_range(record: Record, start: Option<usize>, end: Option<usize>) -> Result<Record> {
     let mut record = record;
 
-    if start.is_some() && end.is_some() {
-        let start = start.unwrap();
-        let end = end.unwrap();
+    if let (Some(start), Some(end)) = (start, end) {
         let left_idx = record.seq().len() - start - end;
         record.trim_left(start)?;
         record.trim_right(left_idx)?;
@@ -36,50 +39,281 @@ pub fn clip_to_range(record: Record, start: Option<usize>, end: Option<usize>) -
     }
 }
 
-fn parse_range(range: String) -> Result<(Option<usize>, Option<usize>)> {
+/// Trimming of homopolymer tails such as poly-A tails and poly-G artifacts
+#[derive(Debug, Clone, Default)]
+pub struct PolyTrim {
+    /// Bases whose runs are trimmed from the 3' end, in order
+    pub bases_3p: Vec<u8>,
+    /// Bases whose runs are trimmed from the 5' end, in order
+    pub bases_5p: Vec<u8>,
+    /// Minimum length of a trimmed run
+    pub min_length: usize,
+    /// Maximum fraction of other bases within a trimmed run
+    pub error_rate: f64,
+}
+impl PolyTrim {
+    /// Returns the range of the sequence left after trimming all homopolymer tails
+    pub fn range(&self, seq: &[u8]) -> (usize, usize) {
+        let mut start = 0;
+        for base in &self.bases_5p {
+            start += poly_tail_5p(&seq[start..], *base, self.min_length, self.error_rate);
+        }
+        let mut end = seq.len();
+        for base in &self.bases_3p {
+            end = start + poly_tail_3p(&seq[start..end], *base, self.min_length, self.error_rate);
+        }
+        (start, end)
+    }
+}
+
+/// Quality and homopolymer trimming and length filtering applied after the fixed clipping
+#[derive(Debug, Clone, Default)]
+pub struct QualityTrim {
+    /// Phred cutoff of the BWA-style trimming of the 3' end
+    pub cutoff_3p: Option<u8>,
+    /// Phred cutoff of the BWA-style trimming of the 5' end
+    pub cutoff_5p: Option<u8>,
+    /// Window size and mean Phred cutoff of the sliding window trimming
+    pub window: Option<(usize, f64)>,
+    /// Homopolymer tail trimming applied after the quality trimming
+    pub poly: PolyTrim,
+    /// Minimum length of the clipped sequences to keep
+    pub min_length: usize,
+}
+impl QualityTrim {
+    /// Trims the record by base quality and homopolymer tails and returns it if it is
+    /// long enough
+    ///
+    /// Records without qualities are not quality trimmed.
+    pub fn apply(&self, mut record: Record) -> Result<Option<Record>> {
+        if let Some(qual) = record.qual() {
+            let qual = qual
+                .iter()
+                .map(|q| q.saturating_sub(PHRED_OFFSET))
+                .collect::<Vec<_>>();
+            let start = self
+                .cutoff_5p
+                .map_or(0, |cutoff| quality_trim_5p(&qual, cutoff));
+            let mut end = self
+                .cutoff_3p
+                .map_or(qual.len(), |cutoff| quality_trim_3p(&qual, cutoff))
+                .max(start);
+            if let Some((size, cutoff)) = self.window {
+                end = start + sliding_window_trim(&qual[start..end], size, cutoff);
+            }
+            record.trim_right(qual.len() - end)?;
+            record.trim_left(start)?;
+        }
+        let (start, end) = self.poly.range(record.seq());
+        record.trim_right(record.seq().len() - end)?;
+        record.trim_left(start)?;
+        Ok((record.seq().len() >= self.min_length).then_some(record))
+    }
+}
+
+/// Returns the number of bases to keep after BWA-style trimming of the 3' end
+///
+/// The sequence is cut at the position maximizing the sum of `cutoff - quality` over
+/// the removed bases.
+pub fn quality_trim_3p(qual: &[u8], cutoff: u8) -> usize {
+    let mut sum = 0;
+    let mut max = 0;
+    let mut keep = qual.len();
+    for (idx, q) in qual.iter().enumerate().rev() {
+        sum += i64::from(cutoff) - i64::from(*q);
+        if sum < 0 {
+            break;
+        }
+        if sum > max {
+            max = sum;
+            keep = idx;
+        }
+    }
+    keep
+}
+
+/// Returns the number of bases to remove by BWA-style trimming of the 5' end
+pub fn quality_trim_5p(qual: &[u8], cutoff: u8) -> usize {
+    let mut sum = 0;
+    let mut max = 0;
+    let mut trim = 0;
+    for (idx, q) in qual.iter().enumerate() {
+        sum += i64::from(cutoff) - i64::from(*q);
+        if sum < 0 {
+            break;
+        }
+        if sum > max {
+            max = sum;
+            trim = idx + 1;
+        }
+    }
+    trim
+}
+
+/// Returns the number of bases to keep before the first window whose mean quality
+/// falls below the cutoff
+///
+/// Sequences shorter than the window are evaluated as a single window.
+pub fn sliding_window_trim(qual: &[u8], size: usize, cutoff: f64) -> usize {
+    let size = size.clamp(1, qual.len().max(1));
+    let threshold = cutoff * size as f64;
+    let mut sum = qual.iter().take(size).map(|q| f64::from(*q)).sum::<f64>();
+    for start in 0..=qual.len().saturating_sub(size) {
+        if start > 0 {
+            sum += f64::from(qual[start + size - 1]) - f64::from(qual[start - 1]);
+        }
+        if sum < threshold {
+            return start;
+        }
+    }
+    qual.len()
+}
+
+/// Returns the length of the longest run of the base from the start of the iterator
+/// which is at least `min_length` long, ends with the base, and contains at most
+/// `floor(error_rate * length)` other bases
+///
+/// The first base of the run may be one of the other bases, so a tail is still found
+/// when the outermost base of the read is a sequencing error.
+fn poly_run<'a, I>(seq: I, base: u8, min_length: usize, error_rate: f64) -> usize
+where
+    I: ExactSizeIterator<Item = &'a u8>,
+{
+    let max_errors = (error_rate * seq.len() as f64).floor() as usize;
+    let mut errors = 0;
+    let mut run = 0;
+    for (idx, b) in seq.enumerate() {
+        let length = idx + 1;
+        if b.eq_ignore_ascii_case(&base) {
+            if length >= min_length && errors as f64 <= error_rate * length as f64 {
+                run = length;
+            }
+        } else {
+            errors += 1;
+            if errors > max_errors {
+                break;
+            }
+        }
+    }
+    run
+}
+
+/// Returns the number of bases to keep after trimming a homopolymer tail of the base
+/// from the 3' end
+pub fn poly_tail_3p(seq: &[u8], base: u8, min_length: usize, error_rate: f64) -> usize {
+    seq.len() - poly_run(seq.iter().rev(), base, min_length.max(1), error_rate)
+}
+
+/// Returns the number of bases to remove by trimming a homopolymer tail of the base
+/// from the 5' end
+pub fn poly_tail_5p(seq: &[u8], base: u8, min_length: usize, error_rate: f64) -> usize {
+    poly_run(seq.iter(), base, min_length.max(1), error_rate)
+}
+
+/// Parses a range string of the format `[start]..[end]`
+pub fn parse_range(range: String) -> Result<(Option<usize>, Option<usize>)> {
     if let Some(end) = range.strip_prefix("..") {
-        let end = end.parse::<usize>().unwrap();
+        let end = end.parse::<usize>()?;
         Ok((None, Some(end)))
     } else if let Some(start) = range.strip_suffix("..") {
-        let start = start.parse::<usize>().unwrap();
+        let start = start.parse::<usize>()?;
         Ok((Some(start), None))
     } else {
-        let mut range = range.split("..");
-        let start = range.next().unwrap().parse::<usize>()?;
-        let end = range.next().unwrap().parse::<usize>()?;
-        Ok((Some(start), Some(end)))
+        let Some((start, end)) = range.split_once("..") else {
+            bail!("Invalid range (expected [start]..[end]): {}", range);
+        };
+        Ok((Some(start.parse::<usize>()?), Some(end.parse::<usize>()?)))
     }
 }
 
+/// Clips and quality trims all records of the reader on `threads` worker threads and
+/// writes those which are long enough to the writer
+#[allow(clippy::too_many_arguments)]
+pub fn clip_records<I, W>(
+    reader: I,
+    writer: &mut W,
+    start: Option<usize>,
+    end: Option<usize>,
+    quality: &QualityTrim,
+    threads: usize,
+    options: &OutputOptions,
+) -> Result<()>
+where
+    I: Iterator<Item = Record>,
+    W: Write,
+{
+    process_records(reader, writer, threads, options, |record| {
+        quality.apply(clip(record, start, end)?)
+    })
+}
+
+/// Clips all records of the reader to a range and quality trims them on `threads`
+/// worker threads and writes those which are long enough to the writer
+#[allow(clippy::too_many_arguments)]
+pub fn clip_records_to_range<I, W>(
+    reader: I,
+    writer: &mut W,
+    start: Option<usize>,
+    end: Option<usize>,
+    quality: &QualityTrim,
+    threads: usize,
+    options: &OutputOptions,
+) -> Result<()>
+where
+    I: Iterator<Item = Record>,
+    W: Write,
+{
+    process_records(reader, writer, threads, options, |record| {
+        quality.apply(clip_to_range(record, start, end)?)
+    })
+}
+
 /// Runs the `clip` command.
+#[allow(clippy::too_many_arguments)]
 pub fn run(
     input: Option<String>,
     output: Option<String>,
     start: Option<usize>,
     end: Option<usize>,
     range: Option<String>,
-    compression_threads: Option<usize>,
-    compression_level: Option<usize>,
+    quality: QualityTrim,
+    paired: PairedOptions,
+    options: OutputOptions,
+    threads: usize,
 ) -> Result<()> {
-    let reader = if let Some(path) = input {
-        initialize_reader(&path)
-    } else {
-        initialize_stdin_reader(stdin().lock())
-    }?;
-    let mut writer = match_output_stream(output, compression_threads, compression_level)?;
+    if paired.is_paired() {
+        let reader = match_paired_reader(input, &paired, &options.stats)?;
+        let mut writer = match_paired_writer(output, &paired, options.clone())?;
+        let (start, end, to_range) = match range {
+            Some(range) => {
+                let (start, end) = parse_range(range)?;
+                (start, end, true)
+            }
+            None => (start, end, false),
+        };
+        let clip_mate = |record| {
+            let record = if to_range {
+                clip_to_range(record, start, end)?
+            } else {
+                clip(record, start, end)?
+            };
+            quality.apply(record)
+        };
+        // pairs are dropped if either mate becomes too short
+        process_pairs(reader, &mut writer, threads, &options, |(r1, r2)| {
+            Ok(clip_mate(r1)?.zip(clip_mate(r2)?))
+        })?;
+        return writer.finish();
+    }
+    let reader = match_fastx_reader(input, &options.stats)?;
+    let mut writer = match_output_stream(output, options.clone())?;
     if let Some(range) = range {
-        let (start, end) = parse_range(range.clone())?;
-        for record in reader {
-            let record = clip_to_range(record, start, end)?;
-            write!(writer, "{}", record.as_str())?;
-        }
+        let (start, end) = parse_range(range)?;
+        clip_records_to_range(reader, &mut writer, start, end, &quality, threads, &options)?;
     } else {
-        for record in reader {
-            let record = clip(record, start, end)?;
-            write!(writer, "{}", record.as_str())?;
-        }
+        clip_records(reader, &mut writer, start, end, &quality, threads, &options)?;
     }
-    Ok(())
+    writer.finish()
 }
 
 #[cfg(test)]
@@ -114,6 +348,89 @@ mod test {
         let (start, end) = parse_range(range).unwrap();
         assert_eq!(start, None);
         assert_eq!(end, Some(2));
+
+        assert!(parse_range("12".to_string()).is_err());
+        assert!(parse_range("a..".to_string()).is_err());
+    }
+
+    /// Encodes phred scores as quality characters
+    fn phred(scores: &[u8]) -> Vec<u8> {
+        scores.iter().map(|q| q + PHRED_OFFSET).collect()
+    }
+
+    fn fastq_record(seq: &str, scores: &[u8]) -> Record {
+        let qual = String::from_utf8(phred(scores)).unwrap();
+        let data = format!("@seq\n{seq}\n+\n{qual}\n");
+        FastqReader::new(data.as_bytes()).next().unwrap()
+    }
+
+    #[test]
+    fn bwa_quality_trim() {
+        // the low quality tail is removed despite the single high quality base in it
+        let qual = [30, 30, 30, 30, 5, 5, 30, 5, 5];
+        assert_eq!(quality_trim_3p(&qual, 20), 4);
+        assert_eq!(quality_trim_3p(&qual, 4), 9);
+        assert_eq!(quality_trim_3p(&[5, 5], 20), 0);
+        assert_eq!(quality_trim_3p(&[], 20), 0);
+
+        let qual = [5, 5, 30, 5, 30, 30, 30];
+        assert_eq!(quality_trim_5p(&qual, 20), 4);
+        assert_eq!(quality_trim_5p(&qual, 4), 0);
+    }
+
+    #[test]
+    fn sliding_window() {
+        let qual = [30, 30, 30, 30, 10, 10, 30, 30];
+        assert_eq!(sliding_window_trim(&qual, 2, 20.0), 4);
+        assert_eq!(sliding_window_trim(&qual, 4, 20.0), 8);
+        assert_eq!(sliding_window_trim(&qual, 4, 25.0), 2);
+        assert_eq!(sliding_window_trim(&qual, 20, 30.0), 0);
+        assert_eq!(sliding_window_trim(&[], 4, 20.0), 0);
+    }
+
+    #[test]
+    fn quality_trim_record() {
+        let quality = QualityTrim {
+            cutoff_3p: Some(20),
+            cutoff_5p: Some(20),
+            ..Default::default()
+        };
+        let record = fastq_record("ACGTACGT", &[5, 30, 30, 30, 30, 30, 5, 5]);
+        let record = quality.apply(record).unwrap().unwrap();
+        assert_eq!(record.seq(), b"CGTAC");
+        assert_eq!(record.qual().unwrap(), phred(&[30; 5]));
+
+        let quality = QualityTrim {
+            window: Some((2, 20.0)),
+            min_length: 3,
+            ..Default::default()
+        };
+        let record = fastq_record("ACGTACGT", &[30, 30, 30, 10, 10, 30, 30, 30]);
+        let record = quality.apply(record).unwrap().unwrap();
+        assert_eq!(record.seq(), b"ACG");
+        let record = fastq_record("ACGTACGT", &[30, 30, 30, 30, 10, 10, 30, 30]);
+        let record = quality.apply(record).unwrap();
+        assert!(record.is_some());
+        let record = fastq_record("ACGTACGT", &[30, 10, 10, 30, 30, 30, 30, 30]);
+        assert!(quality.apply(record).unwrap().is_none());
+    }
+
+    #[test]
+    fn quality_trim_fasta_length() {
+        let quality = QualityTrim {
+            cutoff_3p: Some(20),
+            min_length: 4,
+            ..Default::default()
+        };
+        let record = fasta_reader().next().unwrap();
+        assert!(quality.apply(record).unwrap().is_none());
+        let quality = QualityTrim {
+            cutoff_3p: Some(20),
+            min_length: 3,
+            ..Default::default()
+        };
+        let record = fasta_reader().next().unwrap();
+        assert_eq!(quality.apply(record).unwrap().unwrap().seq(), b"act");
     }
 
     #[test]
@@ -239,4 +556,44 @@ mod test {
         let record = clip_to_range(record, start, end).unwrap();
         assert_eq!(record.seq(), &seq[..2]);
     }
+
+    #[test]
+    fn poly_tails() {
+        assert_eq!(poly_tail_3p(b"ACGTAAAAAA", b'A', 5, 0.0), 4);
+        assert_eq!(poly_tail_3p(b"ACGTAAAAAA", b'A', 8, 0.0), 10);
+        assert_eq!(poly_tail_3p(b"ACGTggggg", b'G', 3, 0.0), 4);
+        assert_eq!(poly_tail_5p(b"TTTTTACGT", b'T', 3, 0.0), 5);
+
+        // a single error is tolerated within ten bases but the tail must start with the base
+        assert_eq!(poly_tail_3p(b"ACGTAAAACAAAAA", b'A', 5, 0.1), 4);
+        assert_eq!(poly_tail_3p(b"ACGTAAAACAAAAA", b'A', 5, 0.0), 9);
+        assert_eq!(poly_tail_3p(b"ACGTCAAAAAAAAA", b'A', 5, 0.1), 5);
+        assert_eq!(poly_tail_3p(b"", b'A', 5, 0.1), 0);
+
+        // the outermost base of the tail may be an error
+        assert_eq!(poly_tail_3p(b"ACGTAAAAAAAAAC", b'A', 5, 0.1), 4);
+        assert_eq!(poly_tail_3p(b"ACGTAAAAAAAAAC", b'A', 5, 0.0), 14);
+        assert_eq!(poly_tail_5p(b"ATTTTTTTTTACGT", b'T', 5, 0.1), 10);
+    }
+
+    #[test]
+    fn poly_trim_record() {
+        let quality = QualityTrim {
+            poly: PolyTrim {
+                bases_3p: b"GA".to_vec(),
+                bases_5p: b"T".to_vec(),
+                min_length: 3,
+                error_rate: 0.0,
+            },
+            ..Default::default()
+        };
+        let record = fastq_record("TTTACGTAAAGGGG", &[30; 14]);
+        let record = quality.apply(record).unwrap().unwrap();
+        assert_eq!(record.seq(), b"ACGT");
+        assert_eq!(record.qual().unwrap(), phred(&[30; 4]));
+
+        let fasta: &'static [u8] = b">seq\nACGTGGGG\n";
+        let record = FastaReader::new(fasta).next().unwrap();
+        assert_eq!(quality.apply(record).unwrap().unwrap().seq(), b"ACGT");
+    }
 }
@@ -1,22 +1,494 @@
-use anyhow::Result;
-use fxread::{initialize_reader, initialize_stdin_reader, Record};
-use std::io::stdin;
+use super::{
+    clip::parse_range, filter::capture_regex, io::match_input_stream, match_fastx_reader,
+    match_output_stream, OutputOptions, PHRED_OFFSET,
+};
+use crate::error::FxError;
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use fxread::{FastxRead, Record};
+use hashbrown::HashMap;
+use memchr::memchr_iter;
+use regex::bytes::Regex;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    io::{ErrorKind, Read, Write},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+/// Size of the decompressed buffers scanned when counting without parsing
+const BUFFER_SIZE: usize = 1 << 20;
+
+/// Format of the statistics table
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum StatsFormat {
+    /// Tab-separated table with a header
+    #[default]
+    Tsv,
+    /// JSON array with one object per input
+    Json,
+}
 
 /// Counts the records
 pub fn count<R: Iterator<Item = Record>>(input: R) -> usize {
     input.count()
 }
 
+/// Key by which records are grouped when counting
+#[derive(Debug, Clone)]
+pub enum GroupBy {
+    /// The first capture group (or the whole match) of a regex on the header
+    Header(Regex),
+    /// The sequence between two positions, truncated to the sequence length
+    Sequence {
+        start: Option<usize>,
+        end: Option<usize>,
+    },
+}
+impl GroupBy {
+    /// Creates the grouping from either a header regex or a `[start]..[end]` range
+    pub fn new(header: Option<String>, sequence: Option<String>) -> Result<Option<Self>> {
+        match (header, sequence) {
+            (Some(pattern), None) => Ok(Some(Self::Header(Regex::new(&pattern)?))),
+            (None, Some(range)) => {
+                let (start, end) = parse_range(range.clone())
+                    .with_context(|| format!("Invalid sequence range: {range}"))?;
+                Ok(Some(Self::Sequence { start, end }))
+            }
+            (None, None) => Ok(None),
+            (Some(_), Some(_)) => bail!("Records can only be grouped by header or sequence"),
+        }
+    }
+
+    /// Returns the group of the record or `None` if the header does not match
+    pub fn key<'a>(&self, record: &'a Record) -> Option<&'a [u8]> {
+        match self {
+            Self::Header(regex) => capture_regex(record, regex, true),
+            Self::Sequence { start, end } => {
+                let seq = record.seq();
+                let end = end.map_or(seq.len(), |end| end.min(seq.len()));
+                let start = start.unwrap_or(0).min(end);
+                Some(&seq[start..end])
+            }
+        }
+    }
+}
+
+/// Counts the records of each group, skipping records without a group
+///
+/// Groups are sorted by decreasing count and then by name.
+pub fn count_groups<R: Iterator<Item = Record>>(
+    reader: R,
+    group_by: &GroupBy,
+) -> Vec<(Vec<u8>, usize)> {
+    let mut counts = HashMap::new();
+    for record in reader {
+        if let Some(key) = group_by.key(&record) {
+            match counts.get_mut(key) {
+                Some(count) => *count += 1,
+                None => {
+                    counts.insert(key.to_vec(), 1);
+                }
+            }
+        }
+    }
+    let mut counts = counts.into_iter().collect::<Vec<_>>();
+    counts.sort_unstable_by(|(ka, ca), (kb, cb)| cb.cmp(ca).then_with(|| ka.cmp(kb)));
+    counts
+}
+
+/// Counts the records by fully parsing them, failing on malformed records
+pub fn count_validated<R: FastxRead<Item = Record> + ?Sized>(reader: &mut R) -> Result<usize> {
+    let mut num_records = 0;
+    while reader.next_record()?.is_some() {
+        num_records += 1;
+    }
+    Ok(num_records)
+}
+
+/// Counts the records of a decompressed stream without parsing them
+///
+/// FASTA records are counted by the `>` markers starting a line and FASTQ records by
+/// their number of lines, which requires single-line FASTQ records. Trailing empty lines
+/// are ignored unless the last record ends in an empty quality line.
+pub fn count_raw<R: Read>(mut reader: R) -> Result<usize> {
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut marker = None;
+    let mut line_start = true;
+    let mut num_headers = 0;
+    let mut num_lines = 0;
+    let mut num_trailing_empty = 0;
+    // first byte of the current line and of the last non-empty line
+    let mut line_first = None;
+    let mut last_line_first = None;
+    loop {
+        let chunk = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => &buffer[..n],
+            Err(why) if why.kind() == ErrorKind::Interrupted => continue,
+            Err(why) => return Err(FxError::from(why).into()),
+        };
+        if marker.is_none() {
+            marker = chunk.iter().copied().find(|b| !b.is_ascii_whitespace());
+        }
+        match marker {
+            Some(b'>') => {
+                num_headers += memchr_iter(b'>', chunk)
+                    .filter(|&idx| match idx {
+                        0 => line_start,
+                        _ => chunk[idx - 1] == b'\n',
+                    })
+                    .count();
+            }
+            _ => {
+                if line_start {
+                    line_first = chunk.first().copied();
+                }
+                for idx in memchr_iter(b'\n', chunk) {
+                    let empty = match idx {
+                        0 => line_start,
+                        _ => chunk[idx - 1] == b'\n',
+                    };
+                    num_lines += 1;
+                    if empty {
+                        num_trailing_empty += 1;
+                    } else {
+                        num_trailing_empty = 0;
+                        last_line_first = line_first;
+                    }
+                    line_first = chunk.get(idx + 1).copied();
+                }
+            }
+        }
+        line_start = chunk[chunk.len() - 1] == b'\n';
+    }
+    if !line_start {
+        num_lines += 1;
+        num_trailing_empty = 0;
+        last_line_first = line_first;
+    }
+    // the first trailing empty line is the quality of a final record with an empty
+    // sequence if the last non-empty line is its `+` separator
+    let num_filled = num_lines - num_trailing_empty;
+    let num_record_lines = match num_filled % 4 {
+        0 => Some(num_filled),
+        3 if num_trailing_empty > 0 && last_line_first == Some(b'+') => Some(num_filled + 1),
+        _ => None,
+    };
+    match (marker, num_record_lines) {
+        (None, _) => Ok(0),
+        (Some(b'>'), _) => Ok(num_headers),
+        (Some(b'@'), Some(num_record_lines)) => Ok(num_record_lines / 4),
+        (Some(b'@'), None) => bail!(
+            "FASTQ input has {num_lines} lines which is not a multiple of four (use --validate for multi-line records)"
+        ),
+        (Some(_), _) => bail!("Unable to determine the record format of the input"),
+    }
+}
+
+/// Applies `f` to every input on up to `threads` worker threads and returns the
+/// results in input order
+fn map_inputs<T, F>(inputs: &[String], threads: usize, f: F) -> Result<Vec<T>>
+where
+    T: Send,
+    F: Fn(&str) -> Result<T> + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let results = inputs.iter().map(|_| Mutex::new(None)).collect::<Vec<_>>();
+    thread::scope(|scope| {
+        for _ in 0..threads.clamp(1, inputs.len().max(1)) {
+            scope.spawn(|| loop {
+                let idx = next.fetch_add(1, Ordering::Relaxed);
+                let Some(input) = inputs.get(idx) else {
+                    break;
+                };
+                *results[idx].lock().unwrap() = Some(f(input));
+            });
+        }
+    });
+    results
+        .into_iter()
+        .map(|result| {
+            result
+                .into_inner()
+                .unwrap()
+                .expect("every input is processed")
+        })
+        .collect()
+}
+
+/// Sequence statistics of a single input
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct SeqStats {
+    /// Input path (`-` for stdin)
+    pub file: String,
+    /// Record format of the input (`FASTA`, `FASTQ`, or `-` if empty)
+    pub format: String,
+    /// Number of records
+    pub num_records: usize,
+    /// Total number of nucleotides
+    pub num_bases: usize,
+    /// Shortest sequence length
+    pub min_length: usize,
+    /// Mean sequence length
+    pub mean_length: f64,
+    /// Longest sequence length
+    pub max_length: usize,
+    /// Length of the shortest sequence among the longest covering half of the bases
+    pub n50: usize,
+    /// Number of the longest sequences covering half of the bases
+    pub l50: usize,
+    /// Percentage of G and C nucleotides
+    pub gc_percent: f64,
+    /// Percentage of N nucleotides
+    pub n_percent: f64,
+    /// Percentage of base qualities of at least 20 (FASTQ only)
+    pub q20_percent: Option<f64>,
+    /// Percentage of base qualities of at least 30 (FASTQ only)
+    pub q30_percent: Option<f64>,
+    /// Mean base quality (FASTQ only)
+    pub mean_quality: Option<f64>,
+}
+impl SeqStats {
+    /// Column names of the TSV table
+    const COLUMNS: [&'static str; 14] = [
+        "file",
+        "format",
+        "num_records",
+        "num_bases",
+        "min_length",
+        "mean_length",
+        "max_length",
+        "n50",
+        "l50",
+        "gc_percent",
+        "n_percent",
+        "q20_percent",
+        "q30_percent",
+        "mean_quality",
+    ];
+
+    /// Calculates the statistics of all records in the reader
+    pub fn from_reader<R: Iterator<Item = Record>>(file: &str, reader: R) -> Self {
+        let mut builder = StatsBuilder::default();
+        reader.for_each(|record| builder.add(&record));
+        builder.build(file)
+    }
+
+    /// Writes the statistics as a TSV row
+    fn write_tsv<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let optional = |x: Option<f64>| x.map_or("-".to_string(), |x| format!("{x:.2}"));
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{}\t{:.2}\t{}\t{}\t{}\t{:.2}\t{:.2}\t{}\t{}\t{}",
+            self.file,
+            self.format,
+            self.num_records,
+            self.num_bases,
+            self.min_length,
+            self.mean_length,
+            self.max_length,
+            self.n50,
+            self.l50,
+            self.gc_percent,
+            self.n_percent,
+            optional(self.q20_percent),
+            optional(self.q30_percent),
+            optional(self.mean_quality),
+        )?;
+        Ok(())
+    }
+}
+
+/// Accumulates the statistics of records one at a time
+#[derive(Debug, Default)]
+struct StatsBuilder {
+    is_fastq: Option<bool>,
+    /// Number of records observed for each sequence length
+    lengths: BTreeMap<usize, usize>,
+    num_records: usize,
+    num_bases: usize,
+    num_gc: usize,
+    num_n: usize,
+    num_q20: usize,
+    num_q30: usize,
+    qual_sum: usize,
+}
+impl StatsBuilder {
+    fn add(&mut self, record: &Record) {
+        let seq = record.seq();
+        self.is_fastq.get_or_insert(record.qual().is_some());
+        *self.lengths.entry(seq.len()).or_default() += 1;
+        self.num_records += 1;
+        self.num_bases += seq.len();
+        for base in seq {
+            match base {
+                b'G' | b'C' | b'g' | b'c' => self.num_gc += 1,
+                b'N' | b'n' => self.num_n += 1,
+                _ => {}
+            }
+        }
+        if let Some(qual) = record.qual() {
+            for q in qual.iter().map(|q| q.saturating_sub(PHRED_OFFSET) as usize) {
+                self.qual_sum += q;
+                self.num_q20 += usize::from(q >= 20);
+                self.num_q30 += usize::from(q >= 30);
+            }
+        }
+    }
+
+    /// Returns the N50 and L50 of the observed lengths
+    fn n50(&self) -> (usize, usize) {
+        let mut covered = 0;
+        let mut num_sequences = 0;
+        for (length, count) in self.lengths.iter().rev() {
+            for _ in 0..*count {
+                covered += length;
+                num_sequences += 1;
+                if 2 * covered >= self.num_bases {
+                    return (*length, num_sequences);
+                }
+            }
+        }
+        (0, 0)
+    }
+
+    fn build(self, file: &str) -> SeqStats {
+        let per_base = |x: usize| {
+            if self.num_bases == 0 {
+                0.0
+            } else {
+                x as f64 / self.num_bases as f64
+            }
+        };
+        let percent = |x: usize| 100.0 * per_base(x);
+        let is_fastq = self.is_fastq == Some(true);
+        let (n50, l50) = self.n50();
+        SeqStats {
+            file: file.to_string(),
+            format: match self.is_fastq {
+                Some(true) => "FASTQ",
+                Some(false) => "FASTA",
+                None => "-",
+            }
+            .to_string(),
+            num_records: self.num_records,
+            num_bases: self.num_bases,
+            min_length: self.lengths.keys().next().copied().unwrap_or(0),
+            mean_length: if self.num_records == 0 {
+                0.0
+            } else {
+                self.num_bases as f64 / self.num_records as f64
+            },
+            max_length: self.lengths.keys().next_back().copied().unwrap_or(0),
+            n50,
+            l50,
+            gc_percent: percent(self.num_gc),
+            n_percent: percent(self.num_n),
+            q20_percent: is_fastq.then(|| percent(self.num_q20)),
+            q30_percent: is_fastq.then(|| percent(self.num_q30)),
+            mean_quality: is_fastq.then(|| per_base(self.qual_sum)),
+        }
+    }
+}
+
+/// Writes the statistics table in the requested format
+pub fn write_stats<W: Write>(
+    writer: &mut W,
+    stats: &[SeqStats],
+    format: StatsFormat,
+) -> Result<()> {
+    match format {
+        StatsFormat::Tsv => {
+            writeln!(writer, "{}", SeqStats::COLUMNS.join("\t"))?;
+            for row in stats {
+                row.write_tsv(writer)?;
+            }
+        }
+        StatsFormat::Json => {
+            serde_json::to_writer_pretty(&mut *writer, stats)?;
+            writeln!(writer)?;
+        }
+    }
+    Ok(())
+}
+
 /// Runs the `count` command.
-pub fn run(input: Option<String>) -> Result<()> {
-    let reader = if let Some(path) = input {
-        initialize_reader(&path)
+///
+/// Reads from stdin if no inputs are provided. Multiple inputs are processed on up
+/// to `threads` worker threads.
+#[allow(clippy::too_many_arguments)]
+pub fn run(
+    inputs: Vec<String>,
+    output: Option<String>,
+    stats: bool,
+    format: StatsFormat,
+    validate: bool,
+    group_by: Option<GroupBy>,
+    options: OutputOptions,
+    threads: usize,
+) -> Result<()> {
+    let inputs = if inputs.is_empty() {
+        vec!["-".to_string()]
     } else {
-        initialize_stdin_reader(stdin().lock())
-    }?;
-    let num_records = count(reader);
-    println!("{}", num_records);
-    Ok(())
+        inputs
+    };
+    let path = |input: &str| (input != "-").then(|| input.to_string());
+
+    if stats {
+        let table = map_inputs(&inputs, threads, |input| {
+            Ok(SeqStats::from_reader(
+                input,
+                match_fastx_reader(path(input), &options.stats)?,
+            ))
+        })?;
+        let mut writer = match_output_stream(output, options)?;
+        write_stats(&mut writer, &table, format)?;
+        return writer.finish();
+    }
+
+    if let Some(group_by) = group_by {
+        let tables = map_inputs(&inputs, threads, |input| {
+            Ok(count_groups(
+                match_fastx_reader(path(input), &options.stats)?,
+                &group_by,
+            ))
+        })?;
+        let mut writer = match_output_stream(output, options)?;
+        for (input, table) in inputs.iter().zip(tables) {
+            for (group, num_records) in table {
+                if inputs.len() > 1 {
+                    write!(writer, "{}\t", input)?;
+                }
+                writer.write_all(&group)?;
+                writeln!(writer, "\t{}", num_records)?;
+            }
+        }
+        return writer.finish();
+    }
+
+    let counts = map_inputs(&inputs, threads, |input| {
+        if validate {
+            count_validated(&mut *match_fastx_reader(path(input), &options.stats)?)
+        } else {
+            let num_records = count_raw(match_input_stream(path(input), &options.stats)?)?;
+            options.stats.read_unparsed(num_records);
+            Ok(num_records)
+        }
+    })?;
+    let mut writer = match_output_stream(output, options)?;
+    if let [num_records] = counts.as_slice() {
+        writeln!(writer, "{}", num_records)?;
+    } else {
+        for (input, num_records) in inputs.iter().zip(counts) {
+            writeln!(writer, "{}\t{}", input, num_records)?;
+        }
+    }
+    writer.finish()
 }
 
 #[cfg(test)]
@@ -48,4 +520,158 @@ mod test {
         let num_records = count(reader);
         assert_eq!(num_records, 3);
     }
+
+    fn close(x: f64, y: f64) -> bool {
+        (x - y).abs() < 1e-9
+    }
+
+    #[test]
+    fn test_count_raw() {
+        let fasta: &[u8] = b">a\nACGT\nAC\n>b desc>x\nAAAA\n>c\nTT";
+        assert_eq!(count_raw(fasta).unwrap(), 3);
+        let fastq: &[u8] = b"@a\nACGT\n+\n@@@@\n@b\nAC\n+\n>>\n";
+        assert_eq!(count_raw(fastq).unwrap(), 2);
+        let unterminated: &[u8] = b"@a\nACGT\n+\n@@@@";
+        assert_eq!(count_raw(unterminated).unwrap(), 1);
+        assert_eq!(count_raw(b"" as &[u8]).unwrap(), 0);
+        assert!(count_raw(b"@a\nACGT\n+\n" as &[u8]).is_err());
+        assert!(count_raw(b"ACGT\n" as &[u8]).is_err());
+    }
+
+    #[test]
+    fn test_count_raw_trailing_empty_lines() {
+        assert_eq!(count_raw(b"@a\nA\n+\nI\n\n" as &[u8]).unwrap(), 1);
+        assert_eq!(count_raw(b"@a\nA\n+\nI\n\n\n\n" as &[u8]).unwrap(), 1);
+        // empty sequences and qualities are not mistaken for trailing lines
+        assert_eq!(count_raw(b"@a\n\n+\n\n" as &[u8]).unwrap(), 1);
+        assert_eq!(count_raw(b"@a\n\n+\n\n\n" as &[u8]).unwrap(), 1);
+        assert_eq!(count_raw(b"@a\n\n+\n\n\n\n\n\n\n" as &[u8]).unwrap(), 1);
+        let fastq = b"@a\nA\n+\nI\n@b\nC\n+\nI\n@c\nG\n+\nI\n";
+        for num_empty in [4, 5, 8] {
+            let input = [fastq.as_slice(), &b"\n".repeat(num_empty)].concat();
+            assert_eq!(count_raw(input.as_slice()).unwrap(), 3);
+        }
+        assert!(count_raw(b"@a\nA\n\n\n+\nI\n" as &[u8]).is_err());
+    }
+
+    #[test]
+    fn test_count_raw_across_buffers() {
+        let record = b">seq\nACGT\n";
+        let fasta = record.repeat(3 * BUFFER_SIZE / record.len());
+        assert_eq!(
+            count_raw(fasta.as_slice()).unwrap(),
+            3 * BUFFER_SIZE / record.len()
+        );
+    }
+
+    #[test]
+    fn test_count_groups_header() {
+        let group_by = GroupBy::new(Some(r"^(\w+?)_".to_string()), None)
+            .unwrap()
+            .unwrap();
+        let counts = count_groups(fasta_reader(), &group_by);
+        assert_eq!(counts, vec![(b"ap2s1".to_vec(), 2), (b"ap2s2".to_vec(), 1)]);
+
+        // records without a match are skipped
+        let group_by = GroupBy::new(Some("s2_(.*)".to_string()), None)
+            .unwrap()
+            .unwrap();
+        let counts = count_groups(fastq_reader(), &group_by);
+        assert_eq!(counts, vec![(b"aosdjiasj".to_vec(), 1)]);
+    }
+
+    #[test]
+    fn test_count_groups_sequence() {
+        let group_by = GroupBy::new(None, Some("1..".to_string()))
+            .unwrap()
+            .unwrap();
+        let counts = count_groups(fasta_reader(), &group_by);
+        assert_eq!(counts, vec![(b"ct".to_vec(), 2), (b"cc".to_vec(), 1)]);
+
+        // ranges are truncated to the sequence length
+        let group_by = GroupBy::new(None, Some("..10".to_string()))
+            .unwrap()
+            .unwrap();
+        let counts = count_groups(fasta_reader(), &group_by);
+        assert_eq!(counts, vec![(b"act".to_vec(), 2), (b"acc".to_vec(), 1)]);
+
+        assert!(GroupBy::new(None, Some("1-2".to_string())).is_err());
+        assert!(GroupBy::new(None, None).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_count_validated() {
+        assert_eq!(count_validated(&mut *fastq_reader()).unwrap(), 3);
+        let mut malformed = FastqReader::new(b"@a\nACGT\n+\n1234\nb\nAC\n+\n12\n" as &[u8]);
+        assert!(count_validated(&mut malformed).is_err());
+    }
+
+    #[test]
+    fn test_map_inputs_order() {
+        let inputs = (0..20).map(|x| x.to_string()).collect::<Vec<_>>();
+        let lengths = map_inputs(&inputs, 4, |x| Ok(x.parse::<usize>()?)).unwrap();
+        assert_eq!(lengths, (0..20).collect::<Vec<_>>());
+        let failed = map_inputs(&inputs, 4, |x| match x {
+            "7" => bail!("failed on {x}"),
+            _ => Ok(()),
+        });
+        assert!(failed.is_err());
+    }
+
+    #[test]
+    fn test_stats_fasta() {
+        let sequence: &'static [u8] = b">a\nACGTACGTAC\n>b\nGGGGNN\n>c\nAAAA\n>d\nTT\n";
+        let stats = SeqStats::from_reader("a.fa", FastaReader::new(sequence));
+        assert_eq!(stats.format, "FASTA");
+        assert_eq!(stats.num_records, 4);
+        assert_eq!(stats.num_bases, 22);
+        assert_eq!(stats.min_length, 2);
+        assert_eq!(stats.mean_length, 5.5);
+        assert_eq!(stats.max_length, 10);
+        assert_eq!((stats.n50, stats.l50), (6, 2));
+        assert!(close(stats.gc_percent, 100.0 * 9.0 / 22.0));
+        assert!(close(stats.n_percent, 100.0 * 2.0 / 22.0));
+        assert_eq!(stats.q20_percent, None);
+        assert_eq!(stats.mean_quality, None);
+    }
+
+    #[test]
+    fn test_stats_fastq() {
+        // qualities: '5' = 20, '?' = 30, '+' = 10
+        let sequence: &'static [u8] = b"@a\nACGT\n+\n5?++\n@b\nAC\n+\n??\n";
+        let stats = SeqStats::from_reader("a.fq", FastqReader::new(sequence));
+        assert_eq!(stats.format, "FASTQ");
+        assert_eq!(stats.num_bases, 6);
+        assert!(close(stats.q20_percent.unwrap(), 400.0 / 6.0));
+        assert!(close(stats.q30_percent.unwrap(), 300.0 / 6.0));
+        assert!(close(stats.mean_quality.unwrap(), 130.0 / 6.0));
+    }
+
+    #[test]
+    fn test_stats_empty() {
+        let stats = SeqStats::from_reader("-", FastaReader::new(b"" as &[u8]));
+        assert_eq!(stats.format, "-");
+        assert_eq!(stats.num_records, 0);
+        assert_eq!((stats.n50, stats.l50), (0, 0));
+        assert_eq!(stats.gc_percent, 0.0);
+    }
+
+    #[test]
+    fn test_write_stats() {
+        let stats = SeqStats::from_reader("a.fa", fasta_reader());
+        let mut tsv = Vec::new();
+        write_stats(&mut tsv, std::slice::from_ref(&stats), StatsFormat::Tsv).unwrap();
+        let tsv = String::from_utf8(tsv).unwrap();
+        let lines = tsv.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("file\tformat\tnum_records"));
+        assert!(lines[1].starts_with("a.fa\tFASTA\t3\t9\t3\t3.00\t3\t"));
+        assert!(lines[1].ends_with("\t-\t-\t-"));
+
+        let mut json = Vec::new();
+        write_stats(&mut json, &[stats], StatsFormat::Json).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(value[0]["num_records"], 3);
+        assert!(value[0]["q20_percent"].is_null());
+    }
 }
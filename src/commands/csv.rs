@@ -1,7 +1,8 @@
-use super::{io::match_input_stream, match_output_stream};
+use super::{io::match_input_stream, match_output_stream, write_fastx, OutputOptions};
 use anyhow::{anyhow, Result};
 use clap::ValueEnum;
 use csv::StringRecord;
+use std::io::{Read, Write};
 
 #[derive(Debug, Clone, Copy, ValueEnum)]
 pub enum Delimiter {
@@ -27,27 +28,30 @@ fn header_index(headers: &StringRecord, column: &str) -> Result<usize> {
         ))
 }
 
-pub fn run(
-    input: Option<String>,
-    output: Option<String>,
-    header_col: String,
-    sequence_col: String,
+/// Converts a delimited table to FASTA using the provided header and sequence columns
+///
+/// Records are written as FASTQ with fake qualities if requested by the options.
+pub fn convert<R, W>(
+    reader: R,
+    writer: &mut W,
+    header_col: &str,
+    sequence_col: &str,
     delim: Delimiter,
-    compression_threads: Option<usize>,
-    compression_level: Option<usize>,
-) -> Result<()> {
+    options: &OutputOptions,
+) -> Result<()>
+where
+    R: Read,
+    W: Write,
+{
     // Initializes the CSV reader
     let mut reader = csv::ReaderBuilder::new()
         .delimiter(delim.try_into()?)
-        .from_reader(match_input_stream(input)?);
+        .from_reader(reader);
 
     // Get the headers and the index of the header columns
     let headers = reader.headers()?;
-    let header_idx = header_index(headers, &header_col)?;
-    let sequence_idx = header_index(headers, &sequence_col)?;
-
-    // Initializes the FASTA writer
-    let mut writer = match_output_stream(output, compression_threads, compression_level)?;
+    let header_idx = header_index(headers, header_col)?;
+    let sequence_idx = header_index(headers, sequence_col)?;
 
     // Iterate through the CSV records and select the header and sequence columns
     for (idx, record) in reader.records().enumerate() {
@@ -60,7 +64,34 @@ pub fn run(
             .ok_or(anyhow!("Missing sequence in row {idx}"))?;
 
         // Write the FASTA record
-        writeln!(writer, ">{header}\n{sequence}")?;
+        write_fastx(
+            writer,
+            header.as_bytes(),
+            sequence.as_bytes(),
+            None,
+            options,
+        )?;
     }
     Ok(())
 }
+
+pub fn run(
+    input: Option<String>,
+    output: Option<String>,
+    header_col: String,
+    sequence_col: String,
+    delim: Delimiter,
+    options: OutputOptions,
+) -> Result<()> {
+    let reader = match_input_stream(input, &options.stats)?;
+    let mut writer = match_output_stream(output, options.clone())?;
+    convert(
+        reader,
+        &mut writer,
+        &header_col,
+        &sequence_col,
+        delim,
+        &options,
+    )?;
+    writer.finish()
+}
@@ -1,9 +1,9 @@
-use super::match_output_stream;
+use super::{match_fastx_reader, match_output_stream, write_fastx, OutputOptions};
 use anyhow::Result;
 use disambiseq::Disambibyte;
-use fxread::{initialize_reader, initialize_stdin_reader, Record};
+use fxread::Record;
 use hashbrown::HashMap;
-use std::io::{stdin, Write};
+use std::io::Write;
 
 type Sequence = Vec<u8>;
 type Header = Vec<u8>;
@@ -31,20 +31,17 @@ where
     map
 }
 
-pub fn run(
-    input: Option<String>,
-    output: Option<String>,
+/// Writes all unambiguous one-off mutants of the reader's sequences to the writer
+pub fn disambiguate<I, W>(
+    reader: I,
+    writer: &mut W,
     include_parents: bool,
-    compression_threads: Option<usize>,
-    compression_level: Option<usize>,
-) -> Result<()> {
-    // Initialize reader
-    let reader = if let Some(path) = input {
-        initialize_reader(&path)
-    } else {
-        initialize_stdin_reader(stdin().lock())
-    }?;
-
+    options: &OutputOptions,
+) -> Result<()>
+where
+    I: Iterator<Item = Record>,
+    W: Write,
+{
     // Create the parent sequence to header name mapping table
     let seq_map = seq_to_header(reader);
 
@@ -55,16 +52,11 @@ pub fn run(
     let key_slice = seq_map.keys().cloned().collect::<Vec<Sequence>>();
     let dq = Disambibyte::from_slice(key_slice.as_slice());
 
-    // Match the output stream
-    let mut writer = match_output_stream(output, compression_threads, compression_level)?;
-
     if include_parents {
         // Iterate through parent sequences
         for parent in seq_map.keys() {
             let header = seq_map.get(parent).unwrap();
-            let header_str = std::str::from_utf8(header)?;
-            let parent_str = std::str::from_utf8(parent)?;
-            writeln!(writer, ">{header_str}\n{parent_str}")?;
+            write_fastx(writer, header, parent, None, options)?;
         }
     }
 
@@ -76,12 +68,28 @@ pub fn run(
         // Retrieve the header count for the parent sequence
         let count = header_counts.get(header).unwrap();
 
-        let header_str = std::str::from_utf8(header)?;
-        let mutant_str = std::str::from_utf8(mutant.sequence())?;
-        writeln!(writer, ">{header_str}_{count}\n{mutant_str}")?;
+        let mut mutant_header = header.clone();
+        write!(mutant_header, "_{count}")?;
+        write_fastx(writer, &mutant_header, mutant.sequence(), None, options)?;
 
         // Increment the header count for the parent sequence
         *header_counts.get_mut(header).unwrap() += 1;
     }
     Ok(())
 }
+
+pub fn run(
+    input: Option<String>,
+    output: Option<String>,
+    include_parents: bool,
+    options: OutputOptions,
+) -> Result<()> {
+    // Initialize reader
+    let reader = match_fastx_reader(input, &options.stats)?;
+
+    // Match the output stream
+    let mut writer = match_output_stream(output, options.clone())?;
+
+    disambiguate(reader, &mut writer, include_parents, &options)?;
+    writer.finish()
+}
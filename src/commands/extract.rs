@@ -1,13 +1,17 @@
-use super::match_output_stream;
+use super::{
+    io::{fastx_reader, match_input_stream},
+    match_fastx_reader, match_output_stream, write_fastx, OutputOptions,
+};
 use anyhow::{bail, Result};
-use fxread::{initialize_reader, FastxRead, Record};
+use fxread::Record;
 use ndarray::{s, Array1, Array2, Axis};
 use ndarray_stats::{EntropyExt, QuantileExt};
+use serde::Serialize;
 use spinoff::{spinners::Dots12, Color, Spinner, Streams};
-use std::{io::Write, str::from_utf8};
+use std::io::Write;
 
 /// Retrieves the sequence size of the first item in the reader
-fn get_sequence_size(reader: &mut Box<dyn FastxRead<Item = Record>>) -> Result<usize> {
+fn get_sequence_size<I: Iterator<Item = Record>>(reader: &mut I) -> Result<usize> {
     if let Some(record) = reader.next() {
         Ok(record.seq().len())
     } else {
@@ -49,10 +53,7 @@ fn normalize_counts(matrix: Array2<f64>) -> Array2<f64> {
 }
 
 /// Calculates the number of nucleotide occurences at each position in the sequences
-fn position_counts(
-    reader: &mut Box<dyn FastxRead<Item = Record>>,
-    num_samples: usize,
-) -> Array2<f64> {
+fn position_counts<I: Iterator<Item = Record>>(reader: &mut I, num_samples: usize) -> Array2<f64> {
     let size = get_sequence_size(reader).unwrap();
     reader
         .take(num_samples)
@@ -75,8 +76,8 @@ fn zscore(array: &Array1<f64>) -> Array1<f64> {
 }
 
 /// Calculates the positional entropy of the nucleotides for a provided set of records
-fn calculate_positional_entropy(
-    reader: &mut Box<dyn FastxRead<Item = Record>>,
+fn calculate_positional_entropy<I: Iterator<Item = Record>>(
+    reader: &mut I,
     num_samples: usize,
 ) -> Array1<f64> {
     let pos_prob = normalize_counts(position_counts(reader, num_samples));
@@ -156,36 +157,73 @@ fn border(array: &Array1<usize>) -> Result<(usize, usize)> {
 
 /// Writes the record as either fasta or fastq and applies the record sequence trimming to the
 /// variable region
-fn format_print(record: &Record, pos_min: usize, pos_max: usize) -> String {
-    match record.qual() {
-        Some(_) => {
-            format!(
-                "@{}\n{}\n{}\n{}\n",
-                from_utf8(record.id()).expect("invalid utf8"),
-                from_utf8(&record.seq()[pos_min..pos_max]).expect("invalid utf8"),
-                from_utf8(record.plus().unwrap()).expect("invalid utf8"),
-                from_utf8(record.qual().unwrap()).expect("invalid utf8"),
-            )
-        }
-        None => {
-            format!(
-                ">{}\n{}\n",
-                from_utf8(record.id()).expect("invalid utf8"),
-                from_utf8(&record.seq()[pos_min..pos_max]).expect("invalid utf8")
-            )
-        }
-    }
+fn write_region<W: Write>(
+    writer: &mut W,
+    record: &Record,
+    pos_min: usize,
+    pos_max: usize,
+    options: &OutputOptions,
+) -> Result<()> {
+    write_fastx(
+        writer,
+        record.id(),
+        &record.seq()[pos_min..pos_max],
+        record.qual().map(|qual| &qual[pos_min..pos_max]),
+        options,
+    )
+}
+
+/// The variable region found across a set of records
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct VariableRegion {
+    /// Start of the variable region
+    pub pos_min: usize,
+    /// End of the variable region
+    pub pos_max: usize,
+    /// Average positional entropy
+    pub mean_entropy: f64,
+    /// Minimum positional entropy
+    pub min_entropy: f64,
+    /// Maximum positional entropy
+    pub max_entropy: f64,
+}
+
+/// Finds the variable region by calculating the positional entropy on a sample of records
+pub fn find_variable_region<I: Iterator<Item = Record>>(
+    reader: &mut I,
+    num_samples: usize,
+    zscore_threshold: f64,
+) -> Result<VariableRegion> {
+    let positional_entropy = calculate_positional_entropy(reader, num_samples);
+    let high_entropy_positions =
+        select_high_entropy_positions(&positional_entropy, zscore_threshold);
+    let contiguous_positions = assign_contiguous(high_entropy_positions)?;
+    let (pos_min, pos_max) = border(&contiguous_positions)?;
+    Ok(VariableRegion {
+        pos_min,
+        pos_max,
+        mean_entropy: positional_entropy.mean().unwrap_or(0.),
+        min_entropy: *positional_entropy.min()?,
+        max_entropy: *positional_entropy.max()?,
+    })
 }
 
 /// Writes results to output stream
-fn write_to_output<W, I>(writer: &mut W, reader: I, pos_min: usize, pos_max: usize)
+pub fn write_to_output<W, I>(
+    writer: &mut W,
+    reader: I,
+    pos_min: usize,
+    pos_max: usize,
+    options: &OutputOptions,
+) -> Result<()>
 where
     W: Write,
     I: Iterator<Item = Record>,
 {
-    reader
-        .map(|record| format_print(&record, pos_min, pos_max))
-        .for_each(|x| write!(writer, "{}", x).expect("Error writing to file"));
+    for record in reader {
+        write_region(writer, &record, pos_min, pos_max, options)?;
+    }
+    Ok(())
 }
 
 /// Runs the variable region extraction
@@ -194,8 +232,7 @@ pub fn run(
     output: Option<String>,
     num_samples: usize,
     zscore_threshold: f64,
-    compression_threads: Option<usize>,
-    compression_level: Option<usize>,
+    options: OutputOptions,
 ) -> Result<()> {
     let mut spinner = Spinner::new_with_stream(
         Dots12,
@@ -205,27 +242,32 @@ pub fn run(
     );
 
     // Calculate Positional Entropy && Select High Entropy Positions
-    let mut reader = initialize_reader(input)?;
-    let positional_entropy = calculate_positional_entropy(&mut reader, num_samples);
-    let high_entropy_positions =
-        select_high_entropy_positions(&positional_entropy, zscore_threshold);
-    let contiguous_positions = assign_contiguous(high_entropy_positions)?;
-    let (pos_min, pos_max) = border(&contiguous_positions)?;
+    // (the sampled records are not counted in the run report as they are read again below)
+    let mut reader = fastx_reader(match_input_stream(Some(input.to_string()), &options.stats)?)?;
+    let region = find_variable_region(&mut reader, num_samples, zscore_threshold)?;
 
     spinner.stop_with_message(
         &format!(
             "✔ Average Entropy: {:.3}\n✔ Minimum Entropy: {:.3}\n✔ Maximum Entropy: {:.3}\n✔ Bounds found: [{}, {}]",
-            positional_entropy.mean().unwrap(),
-            positional_entropy.min().unwrap(),
-            positional_entropy.max().unwrap(),
-            pos_min,
-            pos_max));
+            region.mean_entropy,
+            region.min_entropy,
+            region.max_entropy,
+            region.pos_min,
+            region.pos_max));
+
+    options.stats.section("extract_variable", region)?;
 
     // Reinitialize reader and write to output
-    let reader = initialize_reader(input)?;
-    let mut writer = match_output_stream(output, compression_threads, compression_level)?;
-    write_to_output(&mut writer, reader, pos_min, pos_max);
-    Ok(())
+    let reader = match_fastx_reader(Some(input.to_string()), &options.stats)?;
+    let mut writer = match_output_stream(output, options.clone())?;
+    write_to_output(
+        &mut writer,
+        reader,
+        region.pos_min,
+        region.pos_max,
+        &options,
+    )?;
+    writer.finish()
 }
 
 #[cfg(test)]
@@ -1,9 +1,13 @@
-use super::match_output_stream;
+use super::{
+    match_fastx_reader, match_output_stream, match_paired_reader, match_paired_writer,
+    process_pairs, process_records, OutputOptions, PairFilter, PairedOptions,
+};
 use anyhow::Result;
-use fxread::{initialize_reader, initialize_stdin_reader, Record};
+use fxread::Record;
 use regex::bytes::Regex;
-use std::io::stdin;
+use std::io::Write;
 
+/// Checks whether the record matches the regex on either its sequence or header
 pub fn match_regex(record: &Record, regex: &Regex, invert: bool, header: bool) -> bool {
     let pred = if header {
         regex.is_match(record.id())
@@ -17,29 +21,74 @@ pub fn match_regex(record: &Record, regex: &Regex, invert: bool, header: bool) -
     }
 }
 
+/// Returns the first capture group of the regex (or the whole match if it has no groups)
+/// on either the sequence or header of the record
+pub fn capture_regex<'a>(record: &'a Record, regex: &Regex, header: bool) -> Option<&'a [u8]> {
+    let haystack = if header { record.id() } else { record.seq() };
+    let captures = regex.captures(haystack)?;
+    captures
+        .get(1)
+        .or_else(|| captures.get(0))
+        .map(|m| m.as_bytes())
+}
+
+/// Writes all records matching the regex to the writer using `threads` worker threads
+pub fn filter_records<I, W>(
+    reader: I,
+    writer: &mut W,
+    regex: &Regex,
+    invert: bool,
+    header: bool,
+    threads: usize,
+    options: &OutputOptions,
+) -> Result<()>
+where
+    I: Iterator<Item = Record>,
+    W: Write,
+{
+    process_records(reader, writer, threads, options, |record| {
+        Ok(match_regex(&record, regex, invert, header).then_some(record))
+    })
+}
+
 /// Runs Filtering
+#[allow(clippy::too_many_arguments)]
 pub fn run(
     input: Option<String>,
     output: Option<String>,
     pattern: String,
     invert: bool,
     header: bool,
-    compression_threads: Option<usize>,
-    compression_level: Option<usize>,
+    pair_filter: PairFilter,
+    paired: PairedOptions,
+    options: OutputOptions,
+    threads: usize,
 ) -> Result<()> {
-    let reader = if let Some(path) = input {
-        initialize_reader(&path)
-    } else {
-        initialize_stdin_reader(stdin().lock())
-    }?;
-    let mut writer = match_output_stream(output, compression_threads, compression_level)?;
     let regex = Regex::new(&pattern)?;
-    for record in reader {
-        if match_regex(&record, &regex, invert, header) {
-            write!(writer, "{}", record.as_str(),)?;
-        }
+    if paired.is_paired() {
+        let reader = match_paired_reader(input, &paired, &options.stats)?;
+        let mut writer = match_paired_writer(output, &paired, options.clone())?;
+        process_pairs(reader, &mut writer, threads, &options, |pair| {
+            let keep = pair_filter.keep(
+                match_regex(&pair.0, &regex, invert, header),
+                match_regex(&pair.1, &regex, invert, header),
+            );
+            Ok(keep.then_some(pair))
+        })?;
+        return writer.finish();
     }
-    Ok(())
+    let reader = match_fastx_reader(input, &options.stats)?;
+    let mut writer = match_output_stream(output, options.clone())?;
+    filter_records(
+        reader,
+        &mut writer,
+        &regex,
+        invert,
+        header,
+        threads,
+        &options,
+    )?;
+    writer.finish()
 }
 
 #[cfg(test)]
@@ -58,6 +107,16 @@ mod test {
         Box::new(FastqReader::new(sequence))
     }
 
+    #[test]
+    fn test_capture_regex() {
+        let record = fasta_reader().next().unwrap();
+        let regex = Regex::new(r"^(\w+?)_").unwrap();
+        assert_eq!(capture_regex(&record, &regex, true), Some(&b"ap2s1"[..]));
+        assert_eq!(capture_regex(&record, &regex, false), None);
+        let regex = Regex::new("ct").unwrap();
+        assert_eq!(capture_regex(&record, &regex, false), Some(&b"ct"[..]));
+    }
+
     #[test]
     fn test_fasta_sequence() {
         let reader = fasta_reader();
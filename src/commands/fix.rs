@@ -1,35 +1,33 @@
-use std::io::stdin;
-
-use super::{match_output_stream, write_mut_output_with_invalid};
+use super::{match_fastx_reader, match_output_stream, process_records, OutputOptions};
 use anyhow::Result;
-use fxread::{initialize_reader, initialize_stdin_reader, Record};
+use fxread::Record;
 
 /// Format prints the sequence as uppercase
-fn format_print(record: &mut Record) -> &str {
+pub fn format_print(record: &mut Record) -> &str {
     record.fix();
     record.as_str()
 }
 
-/// Runs uppercase
+/// Runs fix
 pub fn run(
     input: Option<String>,
     output: Option<String>,
-    compression_threads: Option<usize>,
-    compression_level: Option<usize>,
+    options: OutputOptions,
+    threads: usize,
 ) -> Result<()> {
-    let reader = if let Some(path) = input {
-        initialize_reader(&path)
-    } else {
-        initialize_stdin_reader(stdin().lock())
-    }?;
-    let mut writer = match_output_stream(output, compression_threads, compression_level)?;
-    write_mut_output_with_invalid(&mut writer, reader, &format_print);
-    Ok(())
+    let reader = match_fastx_reader(input, &options.stats)?;
+    let mut writer = match_output_stream(output, options.clone())?;
+    process_records(reader, &mut writer, threads, &options, |mut record| {
+        record.fix();
+        Ok(Some(record))
+    })?;
+    writer.finish()
 }
 
 #[cfg(test)]
 mod test {
-    use super::{format_print, write_mut_output_with_invalid};
+    use super::{format_print, OutputOptions};
+    use crate::commands::io::write_mut_output_with_invalid;
     use fxread::{FastaReader, FastqReader, FastxRead, Record};
     use std::fs::File;
 
@@ -91,13 +89,15 @@ mod test {
     fn run_invalid_fasta_allow_invalid() {
         let reader = invalid_fasta_reader();
         let mut writer = File::create("/dev/null").unwrap();
-        write_mut_output_with_invalid(&mut writer, reader, &format_print)
+        write_mut_output_with_invalid(&mut writer, reader, &Record::fix, &OutputOptions::default())
+            .unwrap();
     }
 
     #[test]
     fn run_invalid_fastq_allow_invalid() {
         let reader = invalid_fastq_reader();
         let mut writer = File::create("/dev/null").unwrap();
-        write_mut_output_with_invalid(&mut writer, reader, &format_print)
+        write_mut_output_with_invalid(&mut writer, reader, &Record::fix, &OutputOptions::default())
+            .unwrap();
     }
 }
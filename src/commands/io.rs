@@ -1,118 +1,966 @@
-use anyhow::Result;
-use flate2::read::MultiGzDecoder;
-use fxread::Record;
-use gzp::deflate::Gzip;
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use fxread::{initialize_stdin_reader, FastxRead, Record};
+use gzp::deflate::{Bgzf, Gzip};
 use gzp::par::compress::{ParCompress, ParCompressBuilder};
-use gzp::Compression;
+use gzp::{Compression, ZWriter};
 use std::borrow::{Borrow, BorrowMut};
-use std::io::{stdin, BufReader, Read, Write};
-use std::{fs::File, io::stdout, str::from_utf8};
+use std::io::{stdin, BufReader, BufWriter, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::{
+    fs::{self, File},
+    io::stdout,
+};
+
+use crate::error::FxError;
+use crate::report::{CountingReader, Stats};
+
+/// Nucleotides accepted by record validation
+const VALID_NUCLEOTIDES: &[u8] = b"ACGTNUacgtnu";
+
+/// Number of leading bytes used to detect the compression format of a stream
+const MAGIC_SIZE: usize = 5;
+
+/// Wraps a reader in a transparent decompressor chosen from its leading magic bytes
+///
+/// Recognizes gzip (including BGZF), zstd, bzip2 and xz streams. Anything else is
+/// passed through unchanged.
+pub fn decompress_stream<'a, R: Read + 'a>(mut reader: R) -> Result<Box<dyn Read + 'a>> {
+    // Read the magic bytes (fewer if the stream is shorter)
+    let mut magic = Vec::with_capacity(MAGIC_SIZE);
+    (&mut reader)
+        .take(MAGIC_SIZE as u64)
+        .read_to_end(&mut magic)?;
+
+    // Streams shorter than the magic bytes cannot be compressed
+    let too_short = magic.len() < MAGIC_SIZE;
+
+    // Stitch the magic bytes back onto the stream
+    let stream: Box<dyn Read + 'a> = Box::new(Cursor::new(magic).chain(reader));
+    if too_short {
+        return Ok(stream);
+    }
+    let (stream, _format) = niffler::get_reader(stream)?;
+    Ok(stream)
+}
 
 /// Matches the input to a reader stream
-pub fn match_input_stream(input: Option<String>) -> Result<Box<dyn Read>> {
-    match input {
-        Some(path) => {
-            if path.ends_with(".gz") {
-                let file = File::open(path)?;
-                let buffer = BufReader::new(file);
-                let reader = MultiGzDecoder::new(buffer);
-                Ok(Box::new(reader))
-            } else {
-                let file = File::open(path)?;
-                let buffer = BufReader::new(file);
-                Ok(Box::new(buffer))
-            }
+///
+/// The compression format is detected from the content of the stream (for both
+/// files and stdin) rather than the file extension. The input is registered in `stats`.
+pub fn match_input_stream(input: Option<String>, stats: &Stats) -> Result<Box<dyn Read>> {
+    stats.input(input.as_deref().unwrap_or("-"));
+    let stream: Box<dyn Read> = match input {
+        Some(path) => Box::new(BufReader::new(
+            File::open(&path).with_context(|| format!("Unable to open input: {path}"))?,
+        )),
+        None => Box::new(BufReader::new(stdin())),
+    };
+    decompress_stream(stream)
+}
+
+/// Initializes a fastx reader from any generic reader
+pub fn fastx_reader<R: Read + 'static>(reader: R) -> Result<Box<dyn FastxRead<Item = Record>>> {
+    initialize_stdin_reader(BufReader::new(decompress_stream(reader)?))
+}
+
+/// Matches the input to a fastx reader
+///
+/// Reads from stdin if no path is provided and transparently decompresses the input.
+/// Records read are counted in `stats`.
+pub fn match_fastx_reader(
+    input: Option<String>,
+    stats: &Stats,
+) -> Result<Box<dyn FastxRead<Item = Record>>> {
+    let reader = initialize_stdin_reader(BufReader::new(match_input_stream(input, stats)?))?;
+    Ok(Box::new(CountingReader::new(reader, stats.clone())))
+}
+
+/// Compression format of an output stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Uncompressed
+    Plain,
+    /// Multithreaded gzip
+    Gzip,
+    /// Blocked gzip (BGZF) which is readable by htslib-based indexers
+    Bgzf,
+    /// Multithreaded zstandard
+    Zstd,
+}
+impl OutputFormat {
+    /// Determines the output format from the extension of a path
+    pub fn from_path(path: &str) -> Self {
+        if path.ends_with(".gz") {
+            Self::Gzip
+        } else if path.ends_with(".bgz") || path.ends_with(".bgzf") {
+            Self::Bgzf
+        } else if path.ends_with(".zst") || path.ends_with(".zstd") {
+            Self::Zstd
+        } else {
+            Self::Plain
         }
-        None => Ok(Box::new(BufReader::new(stdin()))),
+    }
+}
+
+/// Record format of written records
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum RecordFormat {
+    /// FASTA records (qualities are dropped)
+    Fasta,
+    /// FASTQ records (missing qualities are filled with the fake quality)
+    Fastq,
+}
+
+/// Quality character used when converting FASTA records to FASTQ
+pub const DEFAULT_FAKE_QUAL: char = 'I';
+
+/// Offset of the phred quality encoding
+pub const PHRED_OFFSET: u8 = 33;
+
+/// Mean Phred quality of the record (zero without qualities)
+pub fn mean_quality(record: &Record) -> f64 {
+    match record.qual() {
+        Some(qual) if !qual.is_empty() => {
+            let sum = qual
+                .iter()
+                .map(|q| u64::from(q.saturating_sub(PHRED_OFFSET)))
+                .sum::<u64>();
+            sum as f64 / qual.len() as f64
+        }
+        _ => 0.0,
+    }
+}
+
+/// Options shared by all output streams
+#[derive(Debug, Clone, Default)]
+pub struct OutputOptions {
+    /// Compression threads to use for output files if applicable
+    pub compression_threads: Option<usize>,
+    /// Compression level to use for output files if applicable
+    pub compression_level: Option<usize>,
+    /// Compression format to use instead of the one inferred from the output path
+    pub output_format: Option<OutputFormat>,
+    /// Whether to write a `.gzi` index alongside BGZF outputs
+    pub bgzf_index: bool,
+    /// Record format to write instead of the format of the input records
+    pub record_format: Option<RecordFormat>,
+    /// Quality character used for FASTA records written as FASTQ [default: `I`]
+    pub fake_qual: Option<char>,
+    /// Line width of FASTA sequences (unwrapped if not provided)
+    pub wrap: Option<usize>,
+    /// Run statistics counting the inputs opened and the records read and written
+    pub stats: Stats,
+}
+impl OutputOptions {
+    /// Resolves the compression format of the provided output path
+    pub fn format(&self, output: Option<&str>) -> OutputFormat {
+        match (self.output_format, output) {
+            (Some(format), _) => format,
+            (None, Some(path)) => OutputFormat::from_path(path),
+            (None, None) => OutputFormat::Plain,
+        }
+    }
+
+    /// Returns the quality byte used for FASTA records written as FASTQ
+    pub fn fake_qual(&self) -> u8 {
+        self.fake_qual.unwrap_or(DEFAULT_FAKE_QUAL) as u8
+    }
+
+    fn gzip_level(&self) -> Compression {
+        if let Some(level) = self.compression_level {
+            Compression::new(level as u32)
+        } else {
+            Compression::default()
+        }
+    }
+
+    fn zstd_level(&self) -> i32 {
+        self.compression_level
+            .map_or(zstd::DEFAULT_COMPRESSION_LEVEL, |level| level as i32)
+    }
+}
+
+/// Output path which is written through a temporary sibling file
+///
+/// The temporary file is moved into place by [`TempPath::commit`] and removed if the
+/// guard is dropped before then, so partial outputs never appear at the final path.
+#[derive(Debug)]
+pub struct TempPath {
+    temp: PathBuf,
+    path: PathBuf,
+    committed: bool,
+}
+impl TempPath {
+    /// Creates a guard for the final path and returns it with its opened temporary file
+    ///
+    /// Paths which already exist and are not regular files (e.g. `/dev/null` or named
+    /// pipes) are opened directly instead.
+    pub fn create(path: &str) -> Result<(Self, File)> {
+        let path = PathBuf::from(path);
+        let special = fs::metadata(&path).is_ok_and(|meta| !meta.is_file());
+        let temp = if special {
+            path.clone()
+        } else {
+            let name = path
+                .file_name()
+                .with_context(|| format!("Invalid output path: {}", path.display()))?;
+            path.with_file_name(format!(
+                ".{}.{}.tmp",
+                name.to_string_lossy(),
+                std::process::id()
+            ))
+        };
+        let file = File::create(&temp)
+            .with_context(|| format!("Unable to create output: {}", path.display()))?;
+        let guard = Self {
+            temp,
+            path,
+            committed: special,
+        };
+        Ok((guard, file))
+    }
+
+    /// Returns the path the output is currently written to
+    pub fn temp_path(&self) -> &Path {
+        &self.temp
+    }
+
+    /// Moves the temporary file to the final path
+    pub fn commit(mut self) -> Result<()> {
+        if !self.committed {
+            fs::rename(&self.temp, &self.path).with_context(|| {
+                format!("Unable to move output into place: {}", self.path.display())
+            })?;
+            self.committed = true;
+        }
+        Ok(())
+    }
+}
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.temp);
+        }
+    }
+}
+
+/// Writers which need to complete their stream once all data is written
+trait FinishWrite: Write {
+    /// Flushes and completes the stream
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+impl FinishWrite for BufWriter<Box<dyn Write + Send>> {
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.flush().map_err(FxError::from)?;
+        Ok(())
+    }
+}
+impl FinishWrite for ParCompress<Gzip> {
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        ZWriter::finish(&mut *self)?;
+        Ok(())
+    }
+}
+impl FinishWrite for zstd::Encoder<'static, Box<dyn Write + Send>> {
+    fn finish(self: Box<Self>) -> Result<()> {
+        let mut stream = (*self).finish()?;
+        stream.flush().map_err(FxError::from)?;
+        Ok(())
+    }
+}
+impl FinishWrite for BgzfWriter {
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        BgzfWriter::finish(&mut self)
+    }
+}
+
+/// Output stream returned by [`match_output_stream`]
+///
+/// File outputs are written to a temporary sibling path and only moved into place by
+/// [`OutputStream::finish`]. Dropping the stream without finishing it (e.g. after an
+/// error) removes the partial output.
+pub struct OutputStream {
+    writer: Option<Box<dyn FinishWrite>>,
+    /// Final paths of the output and its side files (e.g. a `.gzi` index)
+    targets: Vec<TempPath>,
+}
+impl OutputStream {
+    /// Completes the stream and moves all written files into place
+    pub fn finish(mut self) -> Result<()> {
+        if let Some(writer) = self.writer.take() {
+            writer.finish()?;
+        }
+        for target in self.targets.drain(..) {
+            target.commit()?;
+        }
+        Ok(())
+    }
+}
+impl Write for OutputStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self.writer.as_mut() {
+            Some(writer) => writer.write(buf),
+            None => Err(std::io::Error::other("write called after finish")),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.writer.as_mut() {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
+impl Drop for OutputStream {
+    fn drop(&mut self) {
+        // Drop the writer before the targets so temporary files are closed before removal
+        self.writer.take();
     }
 }
 
 /// Matches the output to a writer stream
-pub fn match_output_stream(
-    output: Option<String>,
-    compression_threads: Option<usize>,
-    compression_level: Option<usize>,
-) -> Result<Box<dyn Write>> {
-    match output {
+///
+/// The compression format is taken from the options if provided and otherwise
+/// inferred from the extension of the output path. The returned stream must be
+/// completed with [`OutputStream::finish`] for file outputs to be kept.
+pub fn match_output_stream(output: Option<String>, options: OutputOptions) -> Result<OutputStream> {
+    let format = options.format(output.as_deref());
+    if options.bgzf_index && format == OutputFormat::Bgzf && output.is_none() {
+        bail!("A BGZF index cannot be written when writing to stdout");
+    }
+    let mut targets = Vec::new();
+    let stream: Box<dyn Write + Send> = match &output {
         Some(path) => {
-            if path.ends_with(".gz") {
-                let file = File::create(path)?;
-                let writer: ParCompress<Gzip> = ParCompressBuilder::new()
-                    .num_threads(compression_threads.unwrap_or(1))?
-                    .compression_level(if let Some(level) = compression_level {
-                        Compression::new(level as u32)
-                    } else {
-                        Compression::default()
-                    })
-                    .from_writer(file);
-                Ok(Box::new(writer))
-            } else {
-                Ok(Box::new(File::create(path)?))
+            let (target, file) = TempPath::create(path)?;
+            targets.push(target);
+            Box::new(file)
+        }
+        None => Box::new(stdout()),
+    };
+    let writer: Box<dyn FinishWrite> = match format {
+        OutputFormat::Plain => Box::new(BufWriter::new(stream)),
+        OutputFormat::Gzip => {
+            let writer: ParCompress<Gzip> = ParCompressBuilder::new()
+                .num_threads(options.compression_threads.unwrap_or(1))?
+                .compression_level(options.gzip_level())
+                .from_writer(stream);
+            Box::new(writer)
+        }
+        OutputFormat::Bgzf => {
+            let index = match output {
+                Some(path) if options.bgzf_index => {
+                    let (target, file) = TempPath::create(&format!("{path}.gzi"))?;
+                    targets.push(target);
+                    Some(file)
+                }
+                _ => None,
+            };
+            Box::new(BgzfWriter::new(stream, index, options)?)
+        }
+        OutputFormat::Zstd => {
+            let mut writer = zstd::Encoder::new(stream, options.zstd_level())?;
+            let threads = options.compression_threads.unwrap_or(1);
+            if threads > 1 {
+                writer.multithread(threads as u32)?;
+            }
+            Box::new(writer)
+        }
+    };
+    Ok(OutputStream {
+        writer: Some(writer),
+        targets,
+    })
+}
+
+/// Offsets of the BGZF blocks seen in a compressed stream
+#[derive(Debug, Default)]
+struct GziIndex {
+    /// Bytes of an incomplete block
+    pending: Vec<u8>,
+    /// Compressed offset of the next block
+    compressed: u64,
+    /// Uncompressed offset of the next block
+    uncompressed: u64,
+    /// Compressed and uncompressed offsets of every block after the first
+    offsets: Vec<(u64, u64)>,
+}
+impl GziIndex {
+    /// Size of a BGZF block header
+    const HEADER_SIZE: usize = 18;
+
+    /// Consumes compressed bytes and records every completed block
+    fn update(&mut self, bytes: &[u8]) {
+        self.pending.extend_from_slice(bytes);
+        let mut consumed = 0;
+        while self.pending.len() - consumed >= Self::HEADER_SIZE {
+            let block = &self.pending[consumed..];
+            let block_size = u16::from_le_bytes([block[16], block[17]]) as usize + 1;
+            if block.len() < block_size {
+                break;
+            }
+            let isize_bytes = &block[block_size - 4..block_size];
+            let block_isize = u32::from_le_bytes(isize_bytes.try_into().unwrap()) as u64;
+
+            // the first block and the empty EOF block are implicit in the index
+            if self.compressed > 0 && block_isize > 0 {
+                self.offsets.push((self.compressed, self.uncompressed));
+            }
+            self.compressed += block_size as u64;
+            self.uncompressed += block_isize;
+            consumed += block_size;
+        }
+        self.pending.drain(..consumed);
+    }
+
+    /// Writes the index in the `.gzi` format used by htslib
+    fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&(self.offsets.len() as u64).to_le_bytes())?;
+        for (compressed, uncompressed) in &self.offsets {
+            writer.write_all(&compressed.to_le_bytes())?;
+            writer.write_all(&uncompressed.to_le_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// Passes a compressed BGZF stream through while recording its block offsets
+struct GziTracker<W: Write> {
+    inner: W,
+    index: Arc<Mutex<GziIndex>>,
+}
+impl<W: Write> Write for GziTracker<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let size = self.inner.write(buf)?;
+        self.index.lock().unwrap().update(&buf[..size]);
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Multithreaded BGZF writer with an optional `.gzi` index
+struct BgzfWriter {
+    writer: Option<ParCompress<Bgzf>>,
+    index: Arc<Mutex<GziIndex>>,
+    index_file: Option<File>,
+}
+impl BgzfWriter {
+    fn new(
+        stream: Box<dyn Write + Send>,
+        index_file: Option<File>,
+        options: OutputOptions,
+    ) -> Result<Self> {
+        let index = Arc::new(Mutex::new(GziIndex::default()));
+        let tracker = GziTracker {
+            inner: stream,
+            index: index.clone(),
+        };
+        let writer: ParCompress<Bgzf> = ParCompressBuilder::new()
+            .num_threads(options.compression_threads.unwrap_or(1))?
+            .compression_level(options.gzip_level())
+            .from_writer(tracker);
+        Ok(Self {
+            writer: Some(writer),
+            index,
+            index_file,
+        })
+    }
+
+    /// Completes the compressed stream and writes the index if requested
+    fn finish(&mut self) -> Result<()> {
+        if let Some(mut writer) = self.writer.take() {
+            writer.finish()?;
+            if let Some(file) = self.index_file.take() {
+                let mut index_writer = BufWriter::new(file);
+                self.index.lock().unwrap().write(&mut index_writer)?;
+                index_writer.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+impl Write for BgzfWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.writer
+            .as_mut()
+            .expect("write called after finish")
+            .write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.writer.as_mut() {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
+impl Drop for BgzfWriter {
+    fn drop(&mut self) {
+        if let Err(why) = self.finish() {
+            eprintln!("Error finishing BGZF output: {why}");
+        }
+    }
+}
+
+/// Validates that the record only contains valid nucleotides
+pub fn validate_record(record: &Record) -> Result<()> {
+    if record.valid() {
+        return Ok(());
+    }
+    let index = record
+        .seq()
+        .iter()
+        .position(|b| !VALID_NUCLEOTIDES.contains(b))
+        .unwrap_or_default();
+    Err(FxError::InvalidNucleotide {
+        id: String::from_utf8_lossy(record.id()).to_string(),
+        index,
+    }
+    .into())
+}
+
+/// Writes a single record in the record format requested by the options
+///
+/// Records are written unchanged unless the options convert them between FASTA
+/// and FASTQ, in which case qualities are dropped or filled with the fake quality,
+/// or wrap FASTA sequences.
+pub fn write_record<W: Write + ?Sized>(
+    writer: &mut W,
+    record: &Record,
+    options: &OutputOptions,
+) -> Result<()> {
+    let is_fastq = record.qual().is_some();
+    let unchanged = match options.record_format {
+        None => is_fastq || options.wrap.is_none(),
+        Some(RecordFormat::Fastq) => is_fastq,
+        Some(RecordFormat::Fasta) => !is_fastq && options.wrap.is_none(),
+    };
+    if unchanged {
+        writer.write_all(record.data()).map_err(FxError::from)?;
+        options.stats.written(record.seq().len());
+        Ok(())
+    } else {
+        write_fastx(writer, record.id(), record.seq(), record.qual(), options)
+    }
+}
+
+/// Appends a tag to the header of the record
+pub fn tag_record(record: &Record, tag: &str) -> Result<Record> {
+    let mut id = record.id().to_vec();
+    id.push(b' ');
+    id.extend_from_slice(tag.as_bytes());
+    match record.qual() {
+        Some(qual) => Record::new_fastq_from_parts(&id, record.seq(), qual),
+        None => Record::new_fasta_from_parts(&id, record.seq()),
+    }
+}
+
+/// Writes a record from its parts in the record format requested by the options
+///
+/// If no record format is requested the record is written as FASTQ if it has
+/// qualities and as FASTA otherwise.
+pub fn write_fastx<W: Write + ?Sized>(
+    writer: &mut W,
+    id: &[u8],
+    seq: &[u8],
+    qual: Option<&[u8]>,
+    options: &OutputOptions,
+) -> Result<()> {
+    let format = options.record_format.unwrap_or(if qual.is_some() {
+        RecordFormat::Fastq
+    } else {
+        RecordFormat::Fasta
+    });
+    match format {
+        RecordFormat::Fasta => write_fasta(writer, id, seq, options.wrap),
+        RecordFormat::Fastq => match qual {
+            Some(qual) => write_fastq(writer, id, seq, qual),
+            None => write_fastq(writer, id, seq, &vec![options.fake_qual(); seq.len()]),
+        },
+    }
+    .map_err(FxError::from)?;
+    options.stats.written(seq.len());
+    Ok(())
+}
+
+fn write_fasta<W: Write + ?Sized>(
+    writer: &mut W,
+    id: &[u8],
+    seq: &[u8],
+    wrap: Option<usize>,
+) -> std::io::Result<()> {
+    writer.write_all(b">")?;
+    writer.write_all(id)?;
+    writer.write_all(b"\n")?;
+    match wrap {
+        Some(width) if width > 0 => {
+            for line in seq.chunks(width) {
+                writer.write_all(line)?;
+                writer.write_all(b"\n")?;
             }
+            Ok(())
+        }
+        _ => {
+            writer.write_all(seq)?;
+            writer.write_all(b"\n")
         }
-        None => Ok(Box::new(stdout())),
     }
 }
 
-/// Writes to the output stream with a provided closure
-pub fn write_output<W, I, R>(writer: &mut W, reader: I, f: &dyn Fn(&Record) -> &str)
+fn write_fastq<W: Write + ?Sized>(
+    writer: &mut W,
+    id: &[u8],
+    seq: &[u8],
+    qual: &[u8],
+) -> std::io::Result<()> {
+    writer.write_all(b"@")?;
+    writer.write_all(id)?;
+    writer.write_all(b"\n")?;
+    writer.write_all(seq)?;
+    writer.write_all(b"\n+\n")?;
+    writer.write_all(qual)?;
+    writer.write_all(b"\n")
+}
+
+/// Writes all records to the output stream
+pub fn write_output<W, I, R>(writer: &mut W, reader: I, options: &OutputOptions) -> Result<()>
 where
     W: Write,
     I: Iterator<Item = R>,
     R: Borrow<Record>,
 {
-    reader.for_each(|x| {
-        assert!(
-            x.borrow().valid(),
-            "Invalid Nucleotides in record: {}",
-            from_utf8(x.borrow().id()).expect("invalid utf8")
-        );
-        write!(writer, "{}", f(x.borrow())).expect("Error Writing to File");
-    });
+    for x in reader {
+        validate_record(x.borrow())?;
+        write_record(writer, x.borrow(), options)?;
+    }
+    Ok(())
 }
 
-/// Writes to the output stream with a provided closure that mutates the record
-pub fn write_mut_output<W, I, R>(writer: &mut W, reader: I, f: &dyn Fn(&mut Record) -> &str)
+/// Writes all records to the output stream after applying a provided closure that
+/// mutates the record
+pub fn write_mut_output<W, I, R>(
+    writer: &mut W,
+    reader: I,
+    f: &dyn Fn(&mut Record),
+    options: &OutputOptions,
+) -> Result<()>
 where
     W: Write,
     I: Iterator<Item = R>,
     R: BorrowMut<Record>,
 {
-    reader.for_each(|mut x| {
-        assert!(
-            x.borrow().valid(),
-            "Invalid Nucleotides in record: {}",
-            from_utf8(x.borrow().id()).expect("invalid utf8")
-        );
-        write!(writer, "{}", f(x.borrow_mut())).expect("Error Writing to File");
-    });
+    for mut x in reader {
+        validate_record(x.borrow())?;
+        f(x.borrow_mut());
+        write_record(writer, x.borrow(), options)?;
+    }
+    Ok(())
 }
 
-/// Writes to the output stream with a provided closure
+/// Writes all records to the output stream
 /// but does not check for valid nucleotides
-pub fn write_output_with_invalid<W, I, R>(writer: &mut W, reader: I, f: &dyn Fn(&Record) -> &str)
+pub fn write_output_with_invalid<W, I, R>(
+    writer: &mut W,
+    reader: I,
+    options: &OutputOptions,
+) -> Result<()>
 where
     W: Write,
     I: Iterator<Item = R>,
     R: Borrow<Record>,
 {
-    reader.for_each(|x| {
-        write!(writer, "{}", f(x.borrow())).expect("Error Writing to File");
-    });
+    for x in reader {
+        write_record(writer, x.borrow(), options)?;
+    }
+    Ok(())
 }
 
-/// Writes to the output stream with a provided closure that mutates the record
-/// but does not check for valid nucleotides
+/// Writes all records to the output stream after applying a provided closure that
+/// mutates the record but does not check for valid nucleotides
 pub fn write_mut_output_with_invalid<W, I, R>(
     writer: &mut W,
     reader: I,
-    f: &dyn Fn(&mut Record) -> &str,
-) where
+    f: &dyn Fn(&mut Record),
+    options: &OutputOptions,
+) -> Result<()>
+where
     W: Write,
     I: Iterator<Item = R>,
     R: BorrowMut<Record>,
 {
-    reader.for_each(|mut x| {
-        write!(writer, "{}", f(x.borrow_mut())).expect("Error Writing to File");
-    });
+    for mut x in reader {
+        f(x.borrow_mut());
+        write_record(writer, x.borrow(), options)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use niffler::compression::Format;
+    use std::str::from_utf8;
+
+    const FASTA: &[u8] = b">seq.0\nACGT\n>seq.1\nTGCA\n";
+
+    fn compress(data: &[u8], format: Format) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        {
+            let mut writer =
+                niffler::get_writer(Box::new(&mut buffer), format, niffler::Level::One).unwrap();
+            writer.write_all(data).unwrap();
+        }
+        buffer
+    }
+
+    fn decompress(data: Vec<u8>) -> Vec<u8> {
+        let mut contents = Vec::new();
+        decompress_stream(Cursor::new(data))
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        contents
+    }
+
+    #[test]
+    fn decompress_plain() {
+        assert_eq!(decompress(FASTA.to_vec()), FASTA);
+    }
+
+    #[test]
+    fn decompress_short() {
+        assert_eq!(decompress(b">a\n".to_vec()), b">a\n");
+        assert_eq!(decompress(Vec::new()), b"");
+    }
+
+    #[test]
+    fn decompress_gzip() {
+        assert_eq!(decompress(compress(FASTA, Format::Gzip)), FASTA);
+    }
+
+    #[test]
+    fn decompress_multi_member_gzip() {
+        let mut data = compress(&FASTA[..12], Format::Gzip);
+        data.extend(compress(&FASTA[12..], Format::Gzip));
+        assert_eq!(decompress(data), FASTA);
+    }
+
+    #[test]
+    fn decompress_zstd() {
+        assert_eq!(decompress(compress(FASTA, Format::Zstd)), FASTA);
+    }
+
+    #[test]
+    fn decompress_bzip2() {
+        assert_eq!(decompress(compress(FASTA, Format::Bzip)), FASTA);
+    }
+
+    #[test]
+    fn decompress_xz() {
+        assert_eq!(decompress(compress(FASTA, Format::Lzma)), FASTA);
+    }
+
+    #[test]
+    fn output_format_from_path() {
+        assert_eq!(OutputFormat::from_path("out.fq"), OutputFormat::Plain);
+        assert_eq!(OutputFormat::from_path("out.fq.gz"), OutputFormat::Gzip);
+        assert_eq!(OutputFormat::from_path("out.fq.bgz"), OutputFormat::Bgzf);
+        assert_eq!(OutputFormat::from_path("out.fq.zst"), OutputFormat::Zstd);
+    }
+
+    #[test]
+    fn output_format_override() {
+        let options = OutputOptions {
+            output_format: Some(OutputFormat::Zstd),
+            ..Default::default()
+        };
+        assert_eq!(options.format(Some("out.fq.gz")), OutputFormat::Zstd);
+        assert_eq!(options.format(None), OutputFormat::Zstd);
+        assert_eq!(
+            OutputOptions::default().format(Some("out.fq.gz")),
+            OutputFormat::Gzip
+        );
+        assert_eq!(OutputOptions::default().format(None), OutputFormat::Plain);
+    }
+
+    #[test]
+    fn gzi_index_offsets() {
+        let data = vec![b'A'; 150_000];
+        let mut compressed = Vec::new();
+        {
+            let mut writer =
+                gzp::bgzf::BgzfSyncWriter::new(&mut compressed, Compression::default());
+            writer.write_all(&data).unwrap();
+            writer.flush().unwrap();
+        }
+        let mut index = GziIndex::default();
+        for chunk in compressed.chunks(1000) {
+            index.update(chunk);
+        }
+        assert_eq!(index.compressed, compressed.len() as u64);
+        assert_eq!(index.uncompressed, data.len() as u64);
+        assert_eq!(index.offsets.len(), 2);
+        assert_eq!(index.offsets[0].1, 65280);
+        assert_eq!(index.offsets[1].1, 130560);
+    }
+
+    #[test]
+    fn write_compressed_outputs() {
+        let directory = std::env::temp_dir();
+        for (name, format) in [
+            ("fxtools_io_test.fa.gz", OutputFormat::Gzip),
+            ("fxtools_io_test.fa.bgz", OutputFormat::Bgzf),
+            ("fxtools_io_test.fa.zst", OutputFormat::Zstd),
+        ] {
+            let path = directory.join(name).to_str().unwrap().to_string();
+            let options = OutputOptions {
+                compression_threads: Some(2),
+                bgzf_index: true,
+                ..Default::default()
+            };
+            assert_eq!(options.format(Some(&path)), format);
+            let mut writer = match_output_stream(Some(path.clone()), options).unwrap();
+            writer.write_all(FASTA).unwrap();
+            writer.finish().unwrap();
+            let mut contents = Vec::new();
+            match_input_stream(Some(path.clone()), &Stats::new())
+                .unwrap()
+                .read_to_end(&mut contents)
+                .unwrap();
+            assert_eq!(contents, FASTA);
+            if format == OutputFormat::Bgzf {
+                let index = std::fs::read(format!("{path}.gzi")).unwrap();
+                assert_eq!(index, 0u64.to_le_bytes());
+                std::fs::remove_file(format!("{path}.gzi")).unwrap();
+            }
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn unfinished_outputs_are_removed() {
+        let path = std::env::temp_dir().join("fxtools_io_test_unfinished.fa.bgz");
+        let path = path.to_str().unwrap().to_string();
+        let options = OutputOptions {
+            bgzf_index: true,
+            ..Default::default()
+        };
+        let mut writer = match_output_stream(Some(path.clone()), options).unwrap();
+        writer.write_all(FASTA).unwrap();
+        drop(writer);
+        assert!(!Path::new(&path).exists());
+        assert!(!Path::new(&format!("{path}.gzi")).exists());
+    }
+
+    #[test]
+    fn temp_path_commit() {
+        let path = std::env::temp_dir().join("fxtools_io_test_commit.fa");
+        let path = path.to_str().unwrap().to_string();
+        let (target, mut file) = TempPath::create(&path).unwrap();
+        let temp = target.temp_path().to_path_buf();
+        assert_ne!(temp, Path::new(&path));
+        file.write_all(FASTA).unwrap();
+        assert!(temp.exists());
+        assert!(!Path::new(&path).exists());
+        target.commit().unwrap();
+        assert!(!temp.exists());
+        assert_eq!(std::fs::read(&path).unwrap(), FASTA);
+        std::fs::remove_file(path).unwrap();
+
+        // special files are written in place
+        let (target, _) = TempPath::create("/dev/null").unwrap();
+        assert_eq!(target.temp_path(), Path::new("/dev/null"));
+        drop(target);
+        assert!(Path::new("/dev/null").exists());
+    }
+
+    fn converted(input: &'static [u8], options: &OutputOptions) -> String {
+        let reader = fastx_reader(input).unwrap();
+        let mut output = Vec::new();
+        write_output(&mut output, reader, options).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn write_record_formats() {
+        let fasta: &'static [u8] = b">seq.0 desc\nACGT\n";
+        let fastq: &'static [u8] = b"@seq.0 desc\nACGT\n+seq.0\n1234\n";
+        let as_fasta = OutputOptions {
+            record_format: Some(RecordFormat::Fasta),
+            ..Default::default()
+        };
+        let as_fastq = OutputOptions {
+            record_format: Some(RecordFormat::Fastq),
+            fake_qual: Some('#'),
+            ..Default::default()
+        };
+
+        // unchanged records keep their original layout
+        assert_eq!(
+            converted(fastq, &OutputOptions::default()),
+            from_utf8(fastq).unwrap()
+        );
+        assert_eq!(converted(fastq, &as_fastq), from_utf8(fastq).unwrap());
+        assert_eq!(converted(fasta, &as_fasta), from_utf8(fasta).unwrap());
+
+        // converted records
+        assert_eq!(converted(fastq, &as_fasta), ">seq.0 desc\nACGT\n");
+        assert_eq!(converted(fasta, &as_fastq), "@seq.0 desc\nACGT\n+\n####\n");
+        let default_qual = OutputOptions {
+            record_format: Some(RecordFormat::Fastq),
+            ..Default::default()
+        };
+        assert_eq!(
+            converted(fasta, &default_qual),
+            "@seq.0 desc\nACGT\n+\nIIII\n"
+        );
+    }
+
+    #[test]
+    fn write_record_wrapped() {
+        let fasta: &'static [u8] = b">seq.0\nACGTACGTAC\n>seq.1\nACGT\n";
+        let fastq: &'static [u8] = b"@seq.0\nACGTACGTAC\n+\n1234567890\n";
+        let wrap = |width| OutputOptions {
+            wrap: Some(width),
+            ..Default::default()
+        };
+        assert_eq!(
+            converted(fasta, &wrap(4)),
+            ">seq.0\nACGT\nACGT\nAC\n>seq.1\nACGT\n"
+        );
+        assert_eq!(converted(fasta, &wrap(10)), from_utf8(fasta).unwrap());
+
+        // FASTQ records are never wrapped unless converted to FASTA
+        assert_eq!(converted(fastq, &wrap(4)), from_utf8(fastq).unwrap());
+        let as_fasta = OutputOptions {
+            record_format: Some(RecordFormat::Fasta),
+            ..wrap(6)
+        };
+        assert_eq!(converted(fastq, &as_fasta), ">seq.0\nACGTAC\nGTAC\n");
+    }
+
+    #[test]
+    fn validate_record_index() {
+        let fasta: &'static [u8] = b">seq.0\nACGT\n>seq.1\nACXT\n";
+        let mut reader = fxread::FastaReader::new(fasta);
+        assert!(validate_record(&reader.next().unwrap()).is_ok());
+        let error = validate_record(&reader.next().unwrap()).unwrap_err();
+        match error.downcast_ref::<FxError>() {
+            Some(FxError::InvalidNucleotide { id, index }) => {
+                assert_eq!(id, "seq.1");
+                assert_eq!(*index, 2);
+            }
+            _ => panic!("unexpected error: {}", error),
+        }
+    }
+
+    #[test]
+    fn fastx_reader_gzip() {
+        let reader = fastx_reader(Cursor::new(compress(FASTA, Format::Gzip))).unwrap();
+        assert_eq!(reader.count(), 2);
+    }
 }
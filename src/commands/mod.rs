@@ -1,3 +1,4 @@
+pub mod adapter;
 pub mod cat;
 pub mod clip;
 pub mod count;
@@ -8,17 +9,27 @@ pub mod filter;
 pub mod fix;
 pub mod io;
 pub mod multiplex;
+pub mod paired;
+pub mod pipeline;
 pub mod reverse;
 pub mod sample;
 pub mod sgrna_table;
 pub mod sort;
+pub mod spill;
 pub mod t2g;
 pub mod take;
 pub mod trim;
+pub mod umi;
 pub mod unique;
 pub mod upper;
 
 pub use io::{
-    match_output_stream, write_mut_output, write_mut_output_with_invalid, write_output,
-    write_output_with_invalid,
+    match_fastx_reader, match_output_stream, tag_record, validate_record, write_fastx,
+    write_mut_output, write_mut_output_with_invalid, write_output, write_output_with_invalid,
+    write_record, OutputFormat, OutputOptions, OutputStream, RecordFormat, TempPath, PHRED_OFFSET,
 };
+pub use paired::{
+    match_paired_reader, match_paired_writer, process_pairs, PairFilter, PairedOptions,
+    PairedReader, PairedWriter, RecordPair,
+};
+pub use pipeline::{process_parallel, process_records};
@@ -1,13 +1,13 @@
-use super::match_output_stream;
+use super::{match_fastx_reader, match_output_stream, write_record, OutputOptions};
 use anyhow::{bail, Result};
-use fxread::initialize_reader;
+use fxread::Record;
 use rand::{Rng, SeedableRng};
 use rand_chacha::ChaCha8Rng;
 use serde::Serialize;
 use std::{
     collections::HashMap,
     fs::File,
-    io::{BufRead, BufReader, BufWriter, Write},
+    io::{BufRead, BufReader, Write},
 };
 
 const LEXICON: [u8; 4] = [b'A', b'C', b'G', b'T'];
@@ -19,7 +19,8 @@ struct MultiplexLog {
     barcodes: HashMap<String, String>,
 }
 
-fn minimum_barcode_size(n_inputs: usize) -> usize {
+/// Calculates the minimum barcode size required to uniquely label all inputs
+pub fn minimum_barcode_size(n_inputs: usize) -> usize {
     let lex_size = LEXICON.len();
     for i in 1.. {
         if lex_size.pow(i) >= n_inputs {
@@ -29,7 +30,8 @@ fn minimum_barcode_size(n_inputs: usize) -> usize {
     unreachable!()
 }
 
-fn generate_barcodes(
+/// Generates a set of unique random barcodes for each input
+pub fn generate_barcodes(
     n_inputs: usize,
     barcode_size: usize,
     seed: Option<u64>,
@@ -56,7 +58,7 @@ fn generate_barcodes(
         }
     }
     if barcodes.len() < n_inputs {
-        panic!("Could not generate enough unique barcodes - try setting another seed or increasing the barcode size")
+        bail!("Could not generate enough unique barcodes - try setting another seed or increasing the barcode size")
     } else {
         Ok(barcodes)
     }
@@ -83,7 +85,8 @@ fn write_whitelist(
         if input_filename == output_filename {
             bail!("Input and output whitelist files must be different")
         }
-        let mut writer = File::create(output_filename).map(BufWriter::new)?;
+        let mut writer =
+            match_output_stream(Some(output_filename.to_string()), OutputOptions::default())?;
         for barcode in barcodes {
             let reader = File::open(&input_filename).map(BufReader::new)?;
             for line in reader.lines() {
@@ -94,6 +97,26 @@ fn write_whitelist(
                 writer.write_all(b"\n")?;
             }
         }
+        writer.finish()?;
+    }
+    Ok(())
+}
+
+/// Prepends the barcode to all records of the reader and writes them to the writer
+pub fn prepend_barcode<I, W>(
+    reader: I,
+    writer: &mut W,
+    barcode: &[u8],
+    options: &OutputOptions,
+) -> Result<()>
+where
+    I: Iterator<Item = Record>,
+    W: Write,
+{
+    for record in reader {
+        let mut record = record;
+        record.insert_seq_left(barcode)?;
+        write_record(writer, &record, options)?;
     }
     Ok(())
 }
@@ -109,14 +132,13 @@ pub fn run(
     barcode_size: Option<usize>,
     seed: Option<u64>,
     timeout: u64,
-    compression_threads: Option<usize>,
-    compression_level: Option<usize>,
+    options: OutputOptions,
 ) -> Result<()> {
     // Verify that inputs are unique
     verify_unique_inputs(inputs.clone())?;
 
     // Initialize output stream
-    let mut writer = match_output_stream(output, compression_threads, compression_level)?;
+    let mut writer = match_output_stream(output, options.clone())?;
 
     // Generate barcodes
     let minimum_barcode_size = minimum_barcode_size(inputs.len());
@@ -135,13 +157,10 @@ pub fn run(
             input.clone(),
             String::from_utf8(sample_barcode.clone()).unwrap(),
         );
-        let reader = initialize_reader(input)?;
-        for record in reader {
-            let mut record = record;
-            record.insert_seq_left(&sample_barcodes[sample_idx])?;
-            write!(writer, "{}", record.as_str())?;
-        }
+        let reader = match_fastx_reader(Some(input.to_string()), &options.stats)?;
+        prepend_barcode(reader, &mut writer, sample_barcode, &options)?;
     }
+    writer.finish()?;
 
     // Append barcodes to whitelist and write to whitelist output if provided
     write_whitelist(whitelist, &sample_barcodes, &output_whitelist)?;
@@ -155,7 +174,13 @@ pub fn run(
 
     // Write log
     eprintln!("Writing log to {}", log);
-    let mut log_writer = match_output_stream(Some(log), compression_threads, compression_level)?;
+    let log_options = OutputOptions {
+        output_format: None,
+        bgzf_index: false,
+        ..options.clone()
+    };
+    let mut log_writer = match_output_stream(Some(log), log_options)?;
     write!(log_writer, "{}", serde_json::to_string(&output_log)?)?;
-    Ok(())
+    log_writer.finish()?;
+    options.stats.section("multiplex", output_log)
 }
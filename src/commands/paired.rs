@@ -0,0 +1,304 @@
+use super::{
+    match_fastx_reader, match_output_stream, process_parallel, write_record, OutputOptions,
+    OutputStream,
+};
+use crate::report::Stats;
+use anyhow::{bail, Result};
+use clap::{Args, ValueEnum};
+use fxread::{FastxRead, Record};
+use std::io::Write;
+
+/// A pair of mates
+pub type RecordPair = (Record, Record);
+
+/// Paired-end input and output options shared by the transforming commands
+#[derive(Debug, Clone, Default, Args)]
+pub struct PairedOptions {
+    /// Input R2 FASTA/Q to process in lockstep with the R1 input
+    #[clap(short = 'I', long, group = "pairing")]
+    pub input_r2: Option<String>,
+
+    /// Treat the input as interleaved paired-end records
+    #[clap(long, group = "pairing")]
+    pub interleaved: bool,
+
+    /// Filepath to write R2 output to [default: interleaved with the R1 output]
+    #[clap(short = 'O', long, requires = "pairing")]
+    pub output_r2: Option<String>,
+}
+impl PairedOptions {
+    /// Whether the input is paired-end
+    pub fn is_paired(&self) -> bool {
+        self.input_r2.is_some() || self.interleaved
+    }
+}
+
+/// Rule deciding whether a pair is kept from the decisions on its mates
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum PairFilter {
+    /// Keep the pair if either mate passes
+    #[default]
+    Either,
+    /// Keep the pair only if both mates pass
+    Both,
+}
+impl PairFilter {
+    /// Combines the decisions on both mates
+    pub fn keep(&self, r1: bool, r2: bool) -> bool {
+        match self {
+            Self::Either => r1 || r2,
+            Self::Both => r1 && r2,
+        }
+    }
+}
+
+/// Reads mates in lockstep from two readers or from a single interleaved reader
+///
+/// Yields an error if the inputs run out of records at different points.
+pub struct PairedReader {
+    r1: Box<dyn FastxRead<Item = Record>>,
+    r2: Option<Box<dyn FastxRead<Item = Record>>>,
+    finished: bool,
+}
+impl PairedReader {
+    /// Creates a reader over two separate mate files
+    pub fn new(
+        r1: Box<dyn FastxRead<Item = Record>>,
+        r2: Box<dyn FastxRead<Item = Record>>,
+    ) -> Self {
+        Self {
+            r1,
+            r2: Some(r2),
+            finished: false,
+        }
+    }
+
+    /// Creates a reader over interleaved mates
+    pub fn interleaved(reader: Box<dyn FastxRead<Item = Record>>) -> Self {
+        Self {
+            r1: reader,
+            r2: None,
+            finished: false,
+        }
+    }
+
+    fn next_pair(&mut self) -> Result<Option<RecordPair>> {
+        let r1 = self.r1.next_record()?;
+        let r2 = match self.r2.as_mut() {
+            Some(reader) => reader.next_record()?,
+            None => self.r1.next_record()?,
+        };
+        match (r1, r2) {
+            (Some(r1), Some(r2)) => Ok(Some((r1, r2))),
+            (None, None) => Ok(None),
+            _ if self.r2.is_none() => bail!("Interleaved input has an odd number of records"),
+            _ => bail!("R1 and R2 inputs have a different number of records"),
+        }
+    }
+}
+impl Iterator for PairedReader {
+    type Item = Result<RecordPair>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        let pair = self.next_pair().transpose();
+        if !matches!(pair, Some(Ok(_))) {
+            self.finished = true;
+        }
+        pair
+    }
+}
+
+/// Matches the paired input to a paired reader
+pub fn match_paired_reader(
+    input: Option<String>,
+    paired: &PairedOptions,
+    stats: &Stats,
+) -> Result<PairedReader> {
+    let reader_r1 = match_fastx_reader(input, stats)?;
+    match &paired.input_r2 {
+        Some(input_r2) => Ok(PairedReader::new(
+            reader_r1,
+            match_fastx_reader(Some(input_r2.clone()), stats)?,
+        )),
+        None => Ok(PairedReader::interleaved(reader_r1)),
+    }
+}
+
+/// Writes mates to two separate writers or interleaved to a single writer
+pub struct PairedWriter<W: Write> {
+    r1: W,
+    r2: Option<W>,
+}
+impl<W: Write> PairedWriter<W> {
+    /// Creates a writer for two separate mate outputs
+    pub fn new(r1: W, r2: W) -> Self {
+        Self { r1, r2: Some(r2) }
+    }
+
+    /// Creates a writer for interleaved mates
+    pub fn interleaved(writer: W) -> Self {
+        Self {
+            r1: writer,
+            r2: None,
+        }
+    }
+
+    /// Writes both mates of a pair
+    pub fn write_pair(&mut self, pair: &RecordPair, options: &OutputOptions) -> Result<()> {
+        write_record(&mut self.r1, &pair.0, options)?;
+        match self.r2.as_mut() {
+            Some(r2) => write_record(r2, &pair.1, options),
+            None => write_record(&mut self.r1, &pair.1, options),
+        }
+    }
+}
+
+impl PairedWriter<OutputStream> {
+    /// Completes both output streams and moves them into place
+    pub fn finish(self) -> Result<()> {
+        self.r1.finish()?;
+        if let Some(r2) = self.r2 {
+            r2.finish()?;
+        }
+        Ok(())
+    }
+}
+
+/// Matches the paired output to a paired writer
+///
+/// Mates are interleaved into the R1 output if no R2 output is provided.
+pub fn match_paired_writer(
+    output: Option<String>,
+    paired: &PairedOptions,
+    options: OutputOptions,
+) -> Result<PairedWriter<OutputStream>> {
+    let writer_r1 = match_output_stream(output, options.clone())?;
+    match &paired.output_r2 {
+        Some(output_r2) => Ok(PairedWriter::new(
+            writer_r1,
+            match_output_stream(Some(output_r2.clone()), options)?,
+        )),
+        None => Ok(PairedWriter::interleaved(writer_r1)),
+    }
+}
+
+/// Applies `f` to every pair of the reader on `threads` worker threads and writes the
+/// resulting pairs to the writer in input order
+pub fn process_pairs<I, W, F>(
+    reader: I,
+    writer: &mut PairedWriter<W>,
+    threads: usize,
+    options: &OutputOptions,
+    f: F,
+) -> Result<()>
+where
+    I: Iterator<Item = Result<RecordPair>>,
+    W: Write,
+    F: Fn(RecordPair) -> Result<Option<RecordPair>> + Sync,
+{
+    process_parallel(
+        reader,
+        threads,
+        |pair| f(pair?),
+        |pair| writer.write_pair(&pair, options),
+    )
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use fxread::{FastaReader, FastqReader};
+
+    fn fasta(data: &'static [u8]) -> Box<dyn FastxRead<Item = Record>> {
+        Box::new(FastaReader::new(data))
+    }
+
+    fn r1() -> Box<dyn FastxRead<Item = Record>> {
+        fasta(b">seq.0/1\nACGT\n>seq.1/1\nAAAA\n")
+    }
+
+    fn r2() -> Box<dyn FastxRead<Item = Record>> {
+        fasta(b">seq.0/2\nTTTT\n>seq.1/2\nCCCC\n")
+    }
+
+    #[test]
+    fn read_pairs() {
+        let pairs = PairedReader::new(r1(), r2())
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(pairs.len(), 2);
+        assert_eq!(pairs[1].0.id(), b"seq.1/1");
+        assert_eq!(pairs[1].1.id(), b"seq.1/2");
+    }
+
+    #[test]
+    fn read_interleaved_pairs() {
+        let reader = Box::new(FastqReader::new(
+            b"@a/1\nACGT\n+\n1234\n@a/2\nTTTT\n+\n1234\n" as &[u8],
+        ));
+        let pairs = PairedReader::interleaved(reader)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(pairs.len(), 1);
+        assert_eq!(pairs[0].1.seq(), b"TTTT");
+    }
+
+    #[test]
+    fn read_unbalanced_pairs() {
+        let short = fasta(b">seq.0/2\nTTTT\n");
+        let mut reader = PairedReader::new(r1(), short);
+        assert!(reader.next().unwrap().is_ok());
+        assert!(reader.next().unwrap().is_err());
+        assert!(reader.next().is_none());
+
+        let odd = fasta(b">seq.0/1\nACGT\n>seq.0/2\nTTTT\n>seq.1/1\nAAAA\n");
+        let result = PairedReader::interleaved(odd).collect::<Result<Vec<_>>>();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn write_pairs() {
+        let options = OutputOptions::default();
+        let pair = PairedReader::new(r1(), r2()).next().unwrap().unwrap();
+
+        let mut writer = PairedWriter::new(Vec::new(), Vec::new());
+        writer.write_pair(&pair, &options).unwrap();
+        assert_eq!(writer.r1, b">seq.0/1\nACGT\n");
+        assert_eq!(writer.r2.unwrap(), b">seq.0/2\nTTTT\n");
+
+        let mut writer = PairedWriter::interleaved(Vec::new());
+        writer.write_pair(&pair, &options).unwrap();
+        assert_eq!(writer.r1, b">seq.0/1\nACGT\n>seq.0/2\nTTTT\n");
+    }
+
+    #[test]
+    fn process_pairs_in_lockstep() {
+        let options = OutputOptions::default();
+        let mut writer = PairedWriter::new(Vec::new(), Vec::new());
+        process_pairs(
+            PairedReader::new(r1(), r2()),
+            &mut writer,
+            2,
+            &options,
+            |pair| {
+                Ok(PairFilter::Either
+                    .keep(pair.0.seq() == b"ACGT", false)
+                    .then_some(pair))
+            },
+        )
+        .unwrap();
+        assert_eq!(writer.r1, b">seq.0/1\nACGT\n");
+        assert_eq!(writer.r2.unwrap(), b">seq.0/2\nTTTT\n");
+    }
+
+    #[test]
+    fn pair_filter_rules() {
+        assert!(PairFilter::Either.keep(true, false));
+        assert!(!PairFilter::Either.keep(false, false));
+        assert!(PairFilter::Both.keep(true, true));
+        assert!(!PairFilter::Both.keep(true, false));
+    }
+}
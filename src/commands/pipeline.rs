@@ -0,0 +1,253 @@
+use super::{write_record, OutputOptions};
+use anyhow::{anyhow, Result};
+use fxread::Record;
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    panic::{self, AssertUnwindSafe},
+    sync::{mpsc, Mutex},
+    thread,
+};
+
+/// Number of records sent to a worker at a time
+pub const BATCH_SIZE: usize = 4096;
+
+/// Number of batches allowed in flight per worker before the reader waits on the writer
+const BATCHES_PER_WORKER: usize = 4;
+
+/// Passes processed batches to a sink in the order they were read
+struct Reorder<U, S> {
+    sink: S,
+    pending: BTreeMap<usize, Vec<U>>,
+    next: usize,
+}
+impl<U, S> Reorder<U, S>
+where
+    S: FnMut(U) -> Result<()>,
+{
+    fn new(sink: S) -> Self {
+        Self {
+            sink,
+            pending: BTreeMap::new(),
+            next: 0,
+        }
+    }
+
+    /// Returns the number of batches passed to the sink
+    fn num_written(&self) -> usize {
+        self.next
+    }
+
+    /// Stores a processed batch and flushes all batches that are next in order
+    fn push(&mut self, index: usize, batch: Vec<U>) -> Result<()> {
+        self.pending.insert(index, batch);
+        while let Some(batch) = self.pending.remove(&self.next) {
+            for item in batch {
+                (self.sink)(item)?;
+            }
+            self.next += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Processes the items of the reader with `f` on a pool of worker threads and passes the
+/// results to `sink` in input order
+///
+/// Items are read and sent to the workers in batches of [`BATCH_SIZE`]. Items for which `f`
+/// returns `None` are dropped. If `threads` is at most one everything is processed on the
+/// calling thread.
+pub fn process_parallel<T, U, I, F, S>(reader: I, threads: usize, f: F, mut sink: S) -> Result<()>
+where
+    T: Send,
+    U: Send,
+    I: Iterator<Item = T>,
+    F: Fn(T) -> Result<Option<U>> + Sync,
+    S: FnMut(U) -> Result<()>,
+{
+    if threads <= 1 {
+        for item in reader {
+            if let Some(result) = f(item)? {
+                sink(result)?;
+            }
+        }
+        return Ok(());
+    }
+
+    let mut reader = reader;
+    let max_in_flight = threads * BATCHES_PER_WORKER;
+    let (work_tx, work_rx) = mpsc::channel::<(usize, Vec<T>)>();
+    let (result_tx, result_rx) = mpsc::channel::<(usize, Result<Vec<U>>)>();
+    let work_rx = Mutex::new(work_rx);
+    let f = &f;
+
+    thread::scope(|scope| {
+        // Spawn workers
+        for _ in 0..threads {
+            let work_rx = &work_rx;
+            let result_tx = result_tx.clone();
+            scope.spawn(move || loop {
+                let message = work_rx.lock().unwrap().recv();
+                let Ok((index, batch)) = message else {
+                    break;
+                };
+                let processed = panic::catch_unwind(AssertUnwindSafe(|| {
+                    batch
+                        .into_iter()
+                        .filter_map(|item| f(item).transpose())
+                        .collect::<Result<Vec<U>>>()
+                }));
+                match processed {
+                    Ok(processed) => {
+                        if result_tx.send((index, processed)).is_err() {
+                            break;
+                        }
+                    }
+                    Err(why) => {
+                        // Release the reader before propagating the panic
+                        let _ = result_tx.send((index, Err(anyhow!("Worker thread panicked"))));
+                        panic::resume_unwind(why);
+                    }
+                }
+            });
+        }
+        drop(result_tx);
+
+        // Read batches and write results as they come back in order
+        let mut reorder = Reorder::new(&mut sink);
+        let mut num_sent = 0;
+        loop {
+            let batch = reader.by_ref().take(BATCH_SIZE).collect::<Vec<T>>();
+            if batch.is_empty() {
+                break;
+            }
+            work_tx
+                .send((num_sent, batch))
+                .map_err(|_| anyhow!("Worker threads disconnected"))?;
+            num_sent += 1;
+
+            // Bound memory by waiting on the writer when too many batches are in flight
+            while num_sent - reorder.num_written() >= max_in_flight {
+                let (index, processed) = result_rx.recv()?;
+                reorder.push(index, processed?)?;
+            }
+        }
+        drop(work_tx);
+
+        // Drain remaining batches
+        for (index, processed) in result_rx {
+            reorder.push(index, processed?)?;
+        }
+        Ok(())
+    })
+}
+
+/// Applies `f` to every record of the reader on `threads` worker threads and writes the
+/// resulting records to the writer in input order
+pub fn process_records<I, W, F>(
+    reader: I,
+    writer: &mut W,
+    threads: usize,
+    options: &OutputOptions,
+    f: F,
+) -> Result<()>
+where
+    I: Iterator<Item = Record>,
+    W: Write,
+    F: Fn(Record) -> Result<Option<Record>> + Sync,
+{
+    process_parallel(reader, threads, f, |record| {
+        write_record(writer, &record, options)
+    })
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use anyhow::bail;
+
+    fn collect(num_items: usize, threads: usize) -> Vec<usize> {
+        let mut results = Vec::new();
+        process_parallel(
+            0..num_items,
+            threads,
+            |x| Ok(if x % 3 == 0 { None } else { Some(x * 2) }),
+            |x| {
+                results.push(x);
+                Ok(())
+            },
+        )
+        .unwrap();
+        results
+    }
+
+    #[test]
+    fn parallel_preserves_order() {
+        let num_items = BATCH_SIZE * 50 + 17;
+        let expected = collect(num_items, 1);
+        assert_eq!(expected.len(), num_items - num_items.div_ceil(3));
+        assert_eq!(collect(num_items, 2), expected);
+        assert_eq!(collect(num_items, 8), expected);
+    }
+
+    #[test]
+    fn parallel_propagates_errors() {
+        let result = process_parallel(
+            0..BATCH_SIZE * 10,
+            4,
+            |x| {
+                if x == BATCH_SIZE * 5 {
+                    bail!("bad item")
+                }
+                Ok(Some(x))
+            },
+            |_| Ok(()),
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    #[should_panic]
+    fn parallel_propagates_panics() {
+        process_parallel(
+            0..BATCH_SIZE * 10,
+            4,
+            |x| {
+                assert!(x != BATCH_SIZE * 5);
+                Ok(Some(x))
+            },
+            |_| Ok(()),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn parallel_stops_on_sink_errors() {
+        let result = process_parallel(
+            0..BATCH_SIZE * 10,
+            4,
+            |x| Ok(Some(x)),
+            |x| {
+                if x == BATCH_SIZE * 2 {
+                    bail!("bad write")
+                }
+                Ok(())
+            },
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn records_written_in_order() {
+        let fasta: &'static [u8] = b">seq.0\nact\n>seq.1\nacc\n>seq.2\nagg\n";
+        let reader = fxread::FastaReader::new(fasta);
+        let mut output = Vec::new();
+        let options = OutputOptions::default();
+        process_records(reader, &mut output, 4, &options, |mut record| {
+            record.upper();
+            Ok(Some(record))
+        })
+        .unwrap();
+        assert_eq!(output, b">seq.0\nACT\n>seq.1\nACC\n>seq.2\nAGG\n");
+    }
+}
@@ -1,11 +1,11 @@
-use std::io::stdin;
-
-use super::{match_output_stream, write_mut_output};
+use super::{
+    match_fastx_reader, match_output_stream, process_records, validate_record, OutputOptions,
+};
 use anyhow::Result;
-use fxread::{initialize_reader, initialize_stdin_reader, Record};
+use fxread::Record;
 
 /// Reverse complement sequence and create a string representation of the record
-fn format_print(record: &mut Record) -> &str {
+pub fn format_print(record: &mut Record) -> &str {
     record.rev_comp();
     record.as_str()
 }
@@ -14,22 +14,24 @@ fn format_print(record: &mut Record) -> &str {
 pub fn run(
     input: Option<String>,
     output: Option<String>,
-    compression_threads: Option<usize>,
-    compression_level: Option<usize>,
+    options: OutputOptions,
+    threads: usize,
 ) -> Result<()> {
-    let reader = if let Some(path) = input {
-        initialize_reader(&path)
-    } else {
-        initialize_stdin_reader(stdin().lock())
-    }?;
-    let mut writer = match_output_stream(output, compression_threads, compression_level)?;
-    write_mut_output(&mut writer, reader, &format_print);
-    Ok(())
+    let reader = match_fastx_reader(input, &options.stats)?;
+    let mut writer = match_output_stream(output, options.clone())?;
+    process_records(reader, &mut writer, threads, &options, |mut record| {
+        validate_record(&record)?;
+        record.rev_comp();
+        Ok(Some(record))
+    })?;
+    writer.finish()
 }
 
 #[cfg(test)]
 mod test {
-    use super::{format_print, match_output_stream, write_mut_output};
+    use super::{format_print, match_output_stream, OutputOptions};
+    use crate::commands::io::write_mut_output;
+    use crate::error::FxError;
     use fxread::{FastaReader, FastqReader, FastxRead, Record};
 
     fn fasta_reader() -> Box<dyn FastxRead<Item = Record>> {
@@ -71,18 +73,34 @@ mod test {
     }
 
     #[test]
-    #[should_panic]
     fn run_invalid_fasta() {
         let reader = invalid_fasta_reader();
-        let mut writer = match_output_stream(None, None, None).unwrap();
-        write_mut_output(&mut writer, reader, &format_print)
+        let mut writer = match_output_stream(None, OutputOptions::default()).unwrap();
+        let result = write_mut_output(
+            &mut writer,
+            reader,
+            &Record::rev_comp,
+            &OutputOptions::default(),
+        );
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<FxError>(),
+            Some(FxError::InvalidNucleotide { .. })
+        ));
     }
 
     #[test]
-    #[should_panic]
     fn run_invalid_fastq() {
         let reader = invalid_fastq_reader();
-        let mut writer = match_output_stream(None, None, None).unwrap();
-        write_mut_output(&mut writer, reader, &format_print)
+        let mut writer = match_output_stream(None, OutputOptions::default()).unwrap();
+        let result = write_mut_output(
+            &mut writer,
+            reader,
+            &Record::rev_comp,
+            &OutputOptions::default(),
+        );
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<FxError>(),
+            Some(FxError::InvalidNucleotide { .. })
+        ));
     }
 }
@@ -1,10 +1,65 @@
 use anyhow::Result;
-use fxread::{initialize_reader, initialize_stdin_reader};
+use fxread::Record;
 use rand::{Rng, SeedableRng};
 use rand_chacha::ChaChaRng;
-use std::io::{stdin, Write};
+use std::io::Write;
 
-use super::match_output_stream;
+use super::{
+    match_fastx_reader, match_output_stream, match_paired_reader, match_paired_writer,
+    write_record, OutputOptions, PairedOptions, PairedWriter, RecordPair,
+};
+use serde_json::json;
+
+/// Writes each record of the reader to the writer with a probability of `freq`
+///
+/// Returns the number of records sampled
+pub fn sample_records<I, W, R>(
+    reader: I,
+    writer: &mut W,
+    freq: f64,
+    rng: &mut R,
+    options: &OutputOptions,
+) -> Result<usize>
+where
+    I: Iterator<Item = Record>,
+    W: Write,
+    R: Rng,
+{
+    let mut num_records = 0;
+    for record in reader {
+        if rng.gen_bool(freq) {
+            write_record(writer, &record, options)?;
+            num_records += 1;
+        }
+    }
+    Ok(num_records)
+}
+
+/// Writes each pair of the reader to the writer with a probability of `freq`
+///
+/// Returns the number of pairs sampled
+pub fn sample_pairs<I, W, R>(
+    reader: I,
+    writer: &mut PairedWriter<W>,
+    freq: f64,
+    rng: &mut R,
+    options: &OutputOptions,
+) -> Result<usize>
+where
+    I: Iterator<Item = Result<RecordPair>>,
+    W: Write,
+    R: Rng,
+{
+    let mut num_pairs = 0;
+    for pair in reader {
+        let pair = pair?;
+        if rng.gen_bool(freq) {
+            writer.write_pair(&pair, options)?;
+            num_pairs += 1;
+        }
+    }
+    Ok(num_pairs)
+}
 
 /// Runs the `sample` command.
 pub fn run(
@@ -13,29 +68,32 @@ pub fn run(
     freq: f64,
     seed: Option<u64>,
     quiet: bool,
-    compression_threads: Option<usize>,
-    compression_level: Option<usize>,
+    paired: PairedOptions,
+    options: OutputOptions,
 ) -> Result<()> {
-    let reader = if let Some(path) = input {
-        initialize_reader(&path)
-    } else {
-        initialize_stdin_reader(stdin().lock())
-    }?;
     let mut rng = match seed {
         Some(seed) => ChaChaRng::seed_from_u64(seed),
         None => ChaChaRng::from_entropy(),
     };
-    let mut writer = match_output_stream(output, compression_threads, compression_level)?;
-    let mut num_records = 0;
-    for record in reader {
-        if rng.gen_bool(freq) {
-            write!(writer, "{}", record.as_str())?;
-            num_records += 1;
-        }
-    }
+    let num_records = if paired.is_paired() {
+        let reader = match_paired_reader(input, &paired, &options.stats)?;
+        let mut writer = match_paired_writer(output, &paired, options.clone())?;
+        let num_records = sample_pairs(reader, &mut writer, freq, &mut rng, &options)?;
+        writer.finish()?;
+        num_records
+    } else {
+        let reader = match_fastx_reader(input, &options.stats)?;
+        let mut writer = match_output_stream(output, options.clone())?;
+        let num_records = sample_records(reader, &mut writer, freq, &mut rng, &options)?;
+        writer.finish()?;
+        num_records
+    };
 
     if !quiet {
         eprintln!("{} records sampled", num_records);
     }
-    Ok(())
+    options.stats.section(
+        "sample",
+        json!({"frequency": freq, "num_sampled": num_records}),
+    )
 }
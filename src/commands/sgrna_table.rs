@@ -1,21 +1,18 @@
-use anyhow::Result;
+use super::{match_fastx_reader, match_output_stream, OutputOptions, OutputStream};
+use crate::report::Stats;
+use anyhow::{bail, Result};
 use bstr::BString;
-use fxread::{initialize_reader, initialize_stdin_reader, FastxRead, Record};
+use fxread::Record;
 use spinoff::{spinners::Dots12, Color, Spinner, Streams};
-use std::{
-    collections::HashMap,
-    fs::File,
-    io::{stdin, stdout, Write},
-    str::from_utf8,
-};
+use std::{collections::HashMap, io::Write, str::from_utf8};
 
 /// Creates a mapping of gene names to `sgRNA` names
-struct Table {
+pub struct Table {
     map: HashMap<Vec<u8>, Vec<Record>>,
 }
 impl Table {
-    /// creates a table from a [`FastxRead`] reader.
-    pub fn from_reader(reader: Box<dyn FastxRead<Item = Record>>, tss_ignore: bool) -> Self {
+    /// creates a table from a [`Record`] iterator.
+    pub fn from_reader<I: Iterator<Item = Record>>(reader: I, tss_ignore: bool) -> Self {
         let map = Self::build(reader, tss_ignore);
         Self { map }
     }
@@ -31,23 +28,20 @@ impl Table {
     }
 
     /// Matches the output stream from output
-    pub fn match_stream(output: Option<String>) -> Result<Box<dyn Write>> {
-        match output {
-            Some(path) => Ok(Box::new(File::create(path)?)),
-            None => Ok(Box::new(stdout())),
-        }
+    pub fn match_stream(output: Option<String>) -> Result<OutputStream> {
+        match_output_stream(output, OutputOptions::default())
     }
 
     /// Write table to output stream
-    pub fn write_result(
+    pub fn write_result<W: Write>(
         &self,
-        writer: &mut Box<dyn Write>,
+        writer: &mut W,
         delim: u8,
         include_sequence: bool,
         order: &str,
-    ) {
-        self.map.iter().for_each(|(k, v)| {
-            v.iter().for_each(|record| {
+    ) -> Result<()> {
+        for (k, v) in self.map.iter() {
+            for record in v {
                 writeln!(
                     writer,
                     "{}",
@@ -57,12 +51,11 @@ impl Table {
                         record,
                         include_sequence,
                         order
-                    ))
-                    .unwrap()
-                )
-                .expect("Writing Error");
-            })
-        });
+                    )?)?
+                )?;
+            }
+        }
+        Ok(())
     }
 
     /// Maps an ordering character to its respective string token
@@ -71,18 +64,18 @@ impl Table {
         gene: &'a [u8],
         record: &'a Record,
         include_sequence: bool,
-    ) -> Option<&'a [u8]> {
+    ) -> Result<Option<&'a [u8]>> {
         match c {
-            'g' | 'G' => Some(gene),
-            'h' | 'H' => Some(record.id()),
+            'g' | 'G' => Ok(Some(gene)),
+            'h' | 'H' => Ok(Some(record.id())),
             's' | 'S' => {
                 if include_sequence {
-                    Some(record.seq())
+                    Ok(Some(record.seq()))
                 } else {
-                    None
+                    Ok(None)
                 }
             }
-            _ => panic!("Unexpected character in GSH token: {}", c),
+            _ => bail!("Unexpected character in GSH token: {}", c),
         }
     }
 
@@ -104,19 +97,22 @@ impl Table {
         record: &Record,
         include_sequence: bool,
         order: &str,
-    ) -> Vec<u8> {
-        order
+    ) -> Result<Vec<u8>> {
+        let tokens = order
             .chars()
             .map(|c| Self::map_token(c, gene, record, include_sequence))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(tokens
+            .into_iter()
             .enumerate()
             .fold(Vec::new(), |mut row, (idx, token)| {
                 Self::build_row(&mut row, idx, token, delim)
-            })
+            }))
     }
 
     /// main build iterator
-    fn build(
-        reader: Box<dyn FastxRead<Item = Record>>,
+    fn build<I: Iterator<Item = Record>>(
+        reader: I,
         tss_ignore: bool,
     ) -> HashMap<Vec<u8>, Vec<Record>> {
         reader.fold(HashMap::new(), |mut table, record| {
@@ -153,23 +149,19 @@ impl Table {
 fn validate_characters(order: &str) -> bool {
     order
         .chars()
-        .map(|c| matches!(c, 'G' | 'S' | 'H' | 'g' | 's' | 'h'))
-        .all(|c| c)
+        .all(|c| matches!(c, 'G' | 'S' | 'H' | 'g' | 's' | 'h'))
 }
 
 /// Validates that the order string is within the expected bounds and contains
 /// expected characters
-fn validate_order(order: &str) {
-    assert!(
-        validate_characters(order),
-        "Unrecognized characters in reorder: {}",
-        order
-    );
-    assert!(
-        order.len() <= 3,
-        "Ordering lengh must be less than 3: {}",
-        order
-    );
+pub fn validate_order(order: &str) -> Result<()> {
+    if !validate_characters(order) {
+        bail!("Unrecognized characters in reorder: {}", order);
+    }
+    if order.len() > 3 {
+        bail!("Ordering lengh must be less than 3: {}", order);
+    }
+    Ok(())
 }
 
 pub fn run(
@@ -179,6 +171,7 @@ pub fn run(
     tss_ignore: bool,
     delim: Option<char>,
     reorder: Option<String>,
+    stats: &Stats,
 ) -> Result<()> {
     let delim = match delim {
         Some(c) => c as u8,
@@ -190,13 +183,9 @@ pub fn run(
         None => String::from("ghs"),
     };
 
-    validate_order(&order);
+    validate_order(&order)?;
 
-    let reader = if let Some(path) = input {
-        initialize_reader(&path)
-    } else {
-        initialize_stdin_reader(stdin().lock())
-    }?;
+    let reader = match_fastx_reader(input, stats)?;
 
     let mut spinner = Spinner::new_with_stream(
         Dots12,
@@ -214,9 +203,15 @@ pub fn run(
         ),
     );
     let mut writer = Table::match_stream(output)?;
-    table.write_result(&mut writer, delim, include_sequence, &order);
-
-    Ok(())
+    table.write_result(&mut writer, delim, include_sequence, &order)?;
+    writer.finish()?;
+    stats.section(
+        "sgrna_table",
+        serde_json::json!({
+            "num_genes": table.num_genes(),
+            "num_records": table.num_records(),
+        }),
+    )
 }
 
 #[cfg(test)]
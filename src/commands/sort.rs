@@ -1,153 +1,500 @@
-use std::io::stdin;
+use super::{
+    filter::capture_regex,
+    io::mean_quality,
+    match_fastx_reader, match_output_stream,
+    spill::{spill_path, Items, Spill},
+    write_record, OutputOptions, OutputStream, PairedReader, PairedWriter, RecordFormat,
+    RecordPair, TempPath,
+};
+use anyhow::{bail, Context, Result};
+use flate2::{write::GzEncoder, Compression};
+use fxread::Record;
+use regex::bytes::Regex;
+use std::{
+    cmp::Ordering,
+    fs::File,
+    io::{BufWriter, Write},
+};
+
+/// Property of a record to sort by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortField {
+    /// Nucleotide sequence
+    Sequence,
+    /// Header compared lexically
+    Header,
+    /// Header with numbers compared by value (e.g. `read.2` before `read.10`)
+    Natural,
+    /// Sequence length
+    Length,
+    /// Fraction of G and C nucleotides
+    Gc,
+    /// Mean Phred quality
+    Quality,
+    /// First capture group (or the whole match) of a regex on the header
+    Capture,
+}
+
+/// Sort field with its direction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SortKey {
+    pub field: SortField,
+    pub reverse: bool,
+}
+impl SortKey {
+    /// Parses a key of the form `FIELD[:rev]`
+    pub fn parse(spec: &str) -> Result<Self> {
+        let (field, reverse) = match spec.strip_suffix(":rev") {
+            Some(field) => (field, true),
+            None => (spec, false),
+        };
+        let field = match field {
+            "sequence" | "seq" => SortField::Sequence,
+            "header" => SortField::Header,
+            "natural" => SortField::Natural,
+            "length" => SortField::Length,
+            "gc" => SortField::Gc,
+            "quality" => SortField::Quality,
+            "capture" => SortField::Capture,
+            _ => bail!(
+                "Invalid sort key: {spec} (expected one of sequence, header, natural, length, gc, quality, capture)"
+            ),
+        };
+        Ok(Self { field, reverse })
+    }
+}
+
+/// Compares records by a chain of keys, where later keys break ties of earlier ones
+#[derive(Debug, Clone)]
+pub struct Sorter {
+    keys: Vec<SortKey>,
+    capture: Option<Regex>,
+}
+impl Sorter {
+    /// Creates the sorter from key specifications, sorting by sequence if none are given
+    pub fn new(keys: &[String], capture: Option<String>) -> Result<Self> {
+        let mut keys = keys
+            .iter()
+            .map(|spec| SortKey::parse(spec))
+            .collect::<Result<Vec<_>>>()?;
+        if keys.is_empty() {
+            keys.push(SortKey {
+                field: SortField::Sequence,
+                reverse: false,
+            });
+        }
+        let uses_capture = keys.iter().any(|key| key.field == SortField::Capture);
+        let capture = match capture {
+            Some(_) if !uses_capture => bail!("A capture regex requires a `capture` sort key"),
+            Some(pattern) => Some(
+                Regex::new(&pattern)
+                    .with_context(|| format!("Invalid capture regex: {pattern}"))?,
+            ),
+            None if uses_capture => bail!("The `capture` sort key requires a capture regex"),
+            None => None,
+        };
+        Ok(Self { keys, capture })
+    }
+
+    /// Compares two records by the keys in order
+    pub fn compare(&self, a: &Record, b: &Record) -> Ordering {
+        self.keys
+            .iter()
+            .map(|key| {
+                let ordering = self.compare_field(key.field, a, b);
+                if key.reverse {
+                    ordering.reverse()
+                } else {
+                    ordering
+                }
+            })
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or(Ordering::Equal)
+    }
+
+    /// Compares two pairs by the keys of either mate
+    pub fn compare_pairs(&self, a: &RecordPair, b: &RecordPair, sort_by_r1: bool) -> Ordering {
+        if sort_by_r1 {
+            self.compare(&a.0, &b.0)
+        } else {
+            self.compare(&a.1, &b.1)
+        }
+    }
+
+    fn compare_field(&self, field: SortField, a: &Record, b: &Record) -> Ordering {
+        match field {
+            SortField::Sequence => a.seq().cmp(b.seq()),
+            SortField::Header => a.id().cmp(b.id()),
+            SortField::Natural => natural_cmp(a.id(), b.id()),
+            SortField::Length => a.seq().len().cmp(&b.seq().len()),
+            SortField::Gc => gc_content(a.seq()).total_cmp(&gc_content(b.seq())),
+            SortField::Quality => mean_quality(a).total_cmp(&mean_quality(b)),
+            SortField::Capture => {
+                let regex = self.capture.as_ref().expect("capture regex is validated");
+                capture_regex(a, regex, true).cmp(&capture_regex(b, regex, true))
+            }
+        }
+    }
+}
+
+/// Fraction of G and C nucleotides of the sequence
+fn gc_content(seq: &[u8]) -> f64 {
+    if seq.is_empty() {
+        return 0.0;
+    }
+    let num_gc = seq
+        .iter()
+        .filter(|b| matches!(b, b'G' | b'C' | b'g' | b'c'))
+        .count();
+    num_gc as f64 / seq.len() as f64
+}
 
-use super::match_output_stream;
-use anyhow::{bail, Result};
-use fxread::{initialize_reader, initialize_stdin_reader, FastxRead, Record};
+/// Compares strings lexically except for runs of digits, which are compared by value
+///
+/// Strings which only differ in leading zeros are ordered lexically.
+fn natural_cmp(a: &[u8], b: &[u8]) -> Ordering {
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i].is_ascii_digit() && b[j].is_ascii_digit() {
+            let end_a = i + a[i..].iter().take_while(|c| c.is_ascii_digit()).count();
+            let end_b = j + b[j..].iter().take_while(|c| c.is_ascii_digit()).count();
+            let trim = |digits: &[u8]| {
+                let zeros = digits.iter().take_while(|&&c| c == b'0').count();
+                digits[zeros..].to_vec()
+            };
+            let (x, y) = (trim(&a[i..end_a]), trim(&b[j..end_b]));
+            let ordering = x.len().cmp(&y.len()).then_with(|| x.cmp(&y));
+            if ordering.is_ne() {
+                return ordering;
+            }
+            (i, j) = (end_a, end_b);
+        } else {
+            if a[i] != b[j] {
+                return a[i].cmp(&b[j]);
+            }
+            i += 1;
+            j += 1;
+        }
+    }
+    (a.len() - i).cmp(&(b.len() - j)).then_with(|| a.cmp(b))
+}
 
-fn write_pair<W>(writer_r1: &mut W, writer_r2: &mut W, records: &[(Record, Record)]) -> Result<()>
+/// Number of sorted runs merged at once, which bounds the number of open files
+const MERGE_WIDTH: usize = 64;
+
+/// Sorted items spilled to a compressed temporary file
+struct SortedRun {
+    guard: TempPath,
+}
+impl SortedRun {
+    /// Writes the sorted items to a new run in the temporary directory
+    fn create<T: Spill>(items: impl Iterator<Item = Result<T>>) -> Result<Self> {
+        let (guard, file) = TempPath::create(&spill_path("sort"))?;
+        let mut writer = GzEncoder::new(BufWriter::new(file), Compression::fast());
+        for item in items {
+            item?.spill(&mut writer)?;
+        }
+        writer.finish()?.flush()?;
+        Ok(Self { guard })
+    }
+
+    /// Reads back the items of the run
+    fn items<T: Spill>(&self) -> Result<Items<T>> {
+        T::unspill(File::open(self.guard.temp_path())?)
+    }
+}
+
+/// Merges sorted streams, taking ties from the earliest stream so the merge is stable
+struct Merge<'a, T, F> {
+    streams: Vec<Items<T>>,
+    heads: Vec<Option<T>>,
+    compare: &'a F,
+}
+impl<'a, T, F> Merge<'a, T, F>
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    fn new(mut streams: Vec<Items<T>>, compare: &'a F) -> Result<Self> {
+        let heads = streams
+            .iter_mut()
+            .map(|stream| stream.next().transpose())
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            streams,
+            heads,
+            compare,
+        })
+    }
+}
+impl<T, F> Iterator for Merge<'_, T, F>
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut min: Option<(usize, &T)> = None;
+        for (idx, head) in self.heads.iter().enumerate() {
+            let Some(head) = head else { continue };
+            if min.is_none_or(|(_, x)| (self.compare)(head, x) == Ordering::Less) {
+                min = Some((idx, head));
+            }
+        }
+        let (idx, _) = min?;
+        let next = match self.streams[idx].next().transpose() {
+            Ok(next) => next,
+            Err(err) => return Some(Err(err)),
+        };
+        std::mem::replace(&mut self.heads[idx], next).map(Ok)
+    }
+}
+
+/// Sorts the items stably and passes them to `write` in order
+///
+/// Items are sorted in memory until they exceed `max_memory` bytes, after which sorted
+/// chunks are spilled to compressed runs in the temporary directory and k-way merged.
+pub fn external_sort<T, F, W>(
+    items: Items<T>,
+    max_memory: usize,
+    compare: F,
+    mut write: W,
+) -> Result<()>
+where
+    T: Spill + 'static,
+    F: Fn(&T, &T) -> Ordering,
+    W: FnMut(T) -> Result<()>,
+{
+    let mut runs = Vec::new();
+    let mut buffer = Vec::new();
+    let mut size = 0;
+    for item in items {
+        let item = item?;
+        size += item.size();
+        buffer.push(item);
+        if size > max_memory {
+            buffer.sort_by(&compare);
+            runs.push(SortedRun::create(buffer.drain(..).map(Ok))?);
+            size = 0;
+        }
+    }
+    buffer.sort_by(&compare);
+    if runs.is_empty() {
+        return buffer.into_iter().try_for_each(write);
+    }
+
+    // merge groups of runs until the remaining runs can be merged at once
+    while runs.len() >= MERGE_WIDTH {
+        let mut merged = Vec::new();
+        for group in runs.chunks(MERGE_WIDTH) {
+            let streams = group.iter().map(SortedRun::items).collect::<Result<_>>()?;
+            merged.push(SortedRun::create(Merge::new(streams, &compare)?)?);
+        }
+        runs = merged;
+    }
+    let mut streams = runs
+        .iter()
+        .map(SortedRun::items)
+        .collect::<Result<Vec<_>>>()?;
+    streams.push(Box::new(buffer.into_iter().map(Ok)));
+    Merge::new(streams, &compare)?.try_for_each(|item| write(item?))
+}
+
+/// Writes the paired records to their respective writers
+pub fn write_pair<W>(
+    writer_r1: &mut W,
+    writer_r2: &mut W,
+    records: &[(Record, Record)],
+    options: &OutputOptions,
+) -> Result<()>
 where
     W: std::io::Write,
 {
     for (r1, r2) in records {
-        write!(writer_r1, "{}", r1.as_str())?;
-        write!(writer_r2, "{}", r2.as_str())?;
+        write_record(writer_r1, r1, options)?;
+        write_record(writer_r2, r2, options)?;
     }
     Ok(())
 }
 
-fn join_reader(reader: Box<dyn FastxRead<Item = Record>>) -> Vec<Record> {
-    reader.into_iter().collect::<Vec<_>>()
+/// Collects all records of the reader into a vector
+pub fn join_reader<I: Iterator<Item = Record>>(reader: I) -> Vec<Record> {
+    reader.collect::<Vec<_>>()
 }
 
-fn join_readers(
-    reader_r1: Box<dyn FastxRead<Item = Record>>,
-    reader_r2: Box<dyn FastxRead<Item = Record>>,
-) -> Vec<(Record, Record)> {
-    reader_r1.into_iter().zip(reader_r2).collect::<Vec<_>>()
+/// Collects all paired records of the readers into a vector
+pub fn join_readers<I, J>(reader_r1: I, reader_r2: J) -> Vec<(Record, Record)>
+where
+    I: Iterator<Item = Record>,
+    J: Iterator<Item = Record>,
+{
+    reader_r1.zip(reader_r2).collect::<Vec<_>>()
 }
 
-fn sort_records(records: &mut [Record]) {
-    records.sort_by(|a, b| a.seq().cmp(b.seq()));
+/// Sorts the records in memory by the keys of the sorter
+pub fn sort_records(records: &mut [Record], sorter: &Sorter) {
+    records.sort_by(|a, b| sorter.compare(a, b));
 }
 
-fn sort_paired_records(records: &mut [(Record, Record)], sort_by_r1: bool) {
-    if sort_by_r1 {
-        records.sort_by(|a, b| a.0.seq().cmp(b.0.seq()));
-    } else {
-        records.sort_by(|a, b| a.1.seq().cmp(b.1.seq()));
-    }
+/// Sorts the paired records in memory by the keys of either R1 or R2
+pub fn sort_paired_records(records: &mut [RecordPair], sorter: &Sorter, sort_by_r1: bool) {
+    records.sort_by(|a, b| sorter.compare_pairs(a, b, sort_by_r1));
 }
 
-fn sort_paired_end(
-    r1: &str,
-    r2: &str,
-    prefix: &str,
-    gzip: bool,
-    sort_by_r1: bool,
-    compression_threads: Option<usize>,
-    compression_level: Option<usize>,
-) -> Result<()> {
-    // Determine output paths
-    let mut output_r1 = format!("{prefix}_R1.fastq");
-    let mut output_r2 = format!("{prefix}_R2.fastq");
-
-    if gzip {
-        output_r1.push_str(".gz");
-        output_r2.push_str(".gz");
+/// Where sorted records are written
+#[derive(Debug, Clone, Default)]
+pub struct SortOutput {
+    /// Prefix of the output files, which are named by mate and record format
+    pub prefix: Option<String>,
+    /// Explicit R1 and R2 output paths
+    pub paths: Option<(String, String)>,
+    /// Write paired records interleaved to stdout
+    pub interleaved: bool,
+    /// Append `.gz` to prefixed output paths
+    pub gzip: bool,
+}
+impl SortOutput {
+    /// Names the output of a mate by the prefix and the record format
+    fn prefixed(&self, prefix: &str, mate: &str, format: RecordFormat) -> String {
+        let extension = match format {
+            RecordFormat::Fasta => "fasta",
+            RecordFormat::Fastq => "fastq",
+        };
+        let mut path = format!("{prefix}_{mate}.{extension}");
+        if self.gzip {
+            path.push_str(".gz");
+        }
+        path
     }
 
-    // Initialize paired readers
-    let reader_r1 = initialize_reader(r1)?;
-    let reader_r2 = initialize_reader(r2)?;
-
-    // Zip paired readers into a single iterator and collect into a vector
-    let mut records = join_readers(reader_r1, reader_r2);
+    /// Returns the single-end output path [default: stdout]
+    fn single_path(&self, format: RecordFormat) -> Option<String> {
+        self.prefix
+            .as_ref()
+            .map(|prefix| self.prefixed(prefix, "R1", format))
+    }
 
-    // Sort by sequence
-    sort_paired_records(&mut records, sort_by_r1);
+    /// Opens the paired-end outputs [default: `sorted` prefix]
+    fn paired_writer(
+        &self,
+        format: RecordFormat,
+        options: OutputOptions,
+    ) -> Result<PairedWriter<OutputStream>> {
+        if self.interleaved {
+            return Ok(PairedWriter::interleaved(match_output_stream(
+                None, options,
+            )?));
+        }
+        let (output_r1, output_r2) = match &self.paths {
+            Some(paths) => paths.clone(),
+            None => {
+                let prefix = self.prefix.as_deref().unwrap_or("sorted");
+                (
+                    self.prefixed(prefix, "R1", format),
+                    self.prefixed(prefix, "R2", format),
+                )
+            }
+        };
+        Ok(PairedWriter::new(
+            match_output_stream(Some(output_r1), options.clone())?,
+            match_output_stream(Some(output_r2), options)?,
+        ))
+    }
+}
 
-    // Initialize writers
-    let mut writer_r1 =
-        match_output_stream(Some(output_r1), compression_threads, compression_level)?;
-    let mut writer_r2 =
-        match_output_stream(Some(output_r2), compression_threads, compression_level)?;
+/// Reads the first item to determine the record format written, which is the format of
+/// the input unless overridden (FASTQ for empty inputs)
+fn peek_format<T: 'static>(
+    mut items: Items<T>,
+    options: &OutputOptions,
+    record: impl Fn(&T) -> &Record,
+) -> Result<(RecordFormat, Items<T>)> {
+    let first = items.next().transpose()?;
+    let format = match (options.record_format, &first) {
+        (Some(format), _) => format,
+        (None, Some(x)) if record(x).qual().is_none() => RecordFormat::Fasta,
+        (None, _) => RecordFormat::Fastq,
+    };
+    Ok((format, Box::new(first.map(Ok).into_iter().chain(items))))
+}
 
-    // Write sorted records
-    write_pair(&mut writer_r1, &mut writer_r2, &records)
+fn sort_paired_end(
+    r1: Option<String>,
+    r2: &str,
+    output: &SortOutput,
+    sort_by_r1: bool,
+    sorter: &Sorter,
+    max_memory: usize,
+    options: OutputOptions,
+) -> Result<()> {
+    // Initialize paired reader
+    let reader = PairedReader::new(
+        match_fastx_reader(r1, &options.stats)?,
+        match_fastx_reader(Some(r2.to_string()), &options.stats)?,
+    );
+
+    // Initialize writers named by the input format
+    let (format, pairs) = peek_format(Box::new(reader), &options, |pair| &pair.0)?;
+    let mut writer = output.paired_writer(format, options.clone())?;
+
+    // Sort by the keys of either mate and write sorted records
+    external_sort(
+        pairs,
+        max_memory,
+        |a: &RecordPair, b: &RecordPair| sorter.compare_pairs(a, b, sort_by_r1),
+        |pair| writer.write_pair(&pair, &options),
+    )?;
+    writer.finish()
 }
 
 fn sort_single_end(
     input: Option<String>,
-    prefix: Option<String>,
-    gzip: bool,
-    compression_threads: Option<usize>,
-    compression_level: Option<usize>,
+    output: &SortOutput,
+    sorter: &Sorter,
+    max_memory: usize,
+    options: OutputOptions,
 ) -> Result<()> {
-    // Determine output path
-    let output_str = if let Some(prefix_path) = prefix {
-        let mut prefix_str = format!("{prefix_path}_R1.fastq");
-        if gzip {
-            prefix_str.push_str(".gz");
-        }
-        Some(prefix_str)
-    } else {
-        None
-    };
-
     // Initialize reader
-    let reader = if let Some(path) = input {
-        initialize_reader(&path)
-    } else {
-        initialize_stdin_reader(stdin().lock())
-    }?;
+    let reader = match_fastx_reader(input, &options.stats)?;
 
-    // Collect records into a vector
-    let mut records = join_reader(reader);
+    // Initialize writer named by the input format
+    let (format, records) = peek_format(Box::new(reader.map(Ok)), &options, |record| record)?;
+    let mut writer = match_output_stream(output.single_path(format), options.clone())?;
 
-    // Sort by sequence
-    sort_records(&mut records);
+    // Sort by the keys and write sorted records
+    external_sort(
+        records,
+        max_memory,
+        |a: &Record, b: &Record| sorter.compare(a, b),
+        |record| write_record(&mut writer, &record, &options),
+    )?;
 
-    // Initialize writer
-    let mut writer = match_output_stream(output_str, compression_threads, compression_level)?;
-
-    // Write sorted records
-    for record in records {
-        write!(writer, "{}", record.as_str())?;
-    }
-
-    Ok(())
+    writer.finish()
 }
 
+/// Runs the `sort` command.
+///
+/// Records are sorted stably by the chained `keys` (by sequence if none are given).
+/// They are sorted in memory unless `max_memory` is provided, in which case larger
+/// inputs are sorted externally.
+#[allow(clippy::too_many_arguments)]
 pub fn run(
     input: Option<String>,
     r2: Option<String>,
-    prefix: Option<String>,
-    gzip: bool,
+    output: SortOutput,
     sort_by_r1: bool,
-    compression_threads: Option<usize>,
-    compression_level: Option<usize>,
+    keys: Vec<String>,
+    capture: Option<String>,
+    max_memory: Option<usize>,
+    options: OutputOptions,
 ) -> Result<()> {
+    let sorter = Sorter::new(&keys, capture)?;
+    let max_memory = max_memory.unwrap_or(usize::MAX);
     if let Some(r2) = r2 {
-        if input.is_none() {
-            bail!("Cannot stream R1 input when sorting paired-end reads");
-        }
-        let prefix_str = if let Some(prefix) = prefix {
-            prefix
-        } else {
-            "sorted".to_string()
-        };
-
         sort_paired_end(
-            &input.unwrap(),
-            &r2,
-            &prefix_str,
-            gzip,
-            sort_by_r1,
-            compression_threads,
-            compression_level,
+            input, &r2, &output, sort_by_r1, &sorter, max_memory, options,
         )
     } else {
-        sort_single_end(input, prefix, gzip, compression_threads, compression_level)
+        sort_single_end(input, &output, &sorter, max_memory, options)
     }
 }
 
@@ -180,49 +527,294 @@ mod testing {
         Box::new(FastqReader::new(FASTQ_R2))
     }
 
+    /// Sorts the records by sequence with the given memory limit
+    fn sort_single(reader: Box<dyn FastxRead<Item = Record>>, max_memory: usize) -> Vec<Record> {
+        let sorter = Sorter::new(&[], None).unwrap();
+        let mut records = Vec::new();
+        external_sort(
+            Box::new(reader.map(Ok)),
+            max_memory,
+            |a: &Record, b: &Record| sorter.compare(a, b),
+            |record| {
+                records.push(record);
+                Ok(())
+            },
+        )
+        .unwrap();
+        records
+    }
+
+    /// Sorts the pairs by the sequence of either mate with the given memory limit
+    fn sort_paired(
+        reader_r1: Box<dyn FastxRead<Item = Record>>,
+        reader_r2: Box<dyn FastxRead<Item = Record>>,
+        sort_by_r1: bool,
+        max_memory: usize,
+    ) -> Vec<RecordPair> {
+        let sorter = Sorter::new(&[], None).unwrap();
+        let mut pairs = Vec::new();
+        external_sort(
+            Box::new(PairedReader::new(reader_r1, reader_r2)),
+            max_memory,
+            |a: &RecordPair, b: &RecordPair| sorter.compare_pairs(a, b, sort_by_r1),
+            |pair| {
+                pairs.push(pair);
+                Ok(())
+            },
+        )
+        .unwrap();
+        pairs
+    }
+
     #[test]
     fn sort_single_fastq() {
-        let mut records = join_reader(r1_fastq_reader());
-        sort_records(&mut records);
+        let records = sort_single(r1_fastq_reader(), usize::MAX);
         assert_eq!(records[0].id(), b"r1");
     }
 
     #[test]
     fn sort_paired_fastq_by_r2() {
-        let mut records = join_readers(r1_fastq_reader(), r2_fastq_reader());
-        sort_paired_records(&mut records, false);
+        let records = sort_paired(r1_fastq_reader(), r2_fastq_reader(), false, usize::MAX);
         assert_eq!(records[0].0.id(), b"r2");
         assert_eq!(records[0].1.id(), b"r2");
     }
 
     #[test]
     fn sort_paired_fastq_by_r1() {
-        let mut records = join_readers(r1_fastq_reader(), r2_fastq_reader());
-        sort_paired_records(&mut records, true);
+        let records = sort_paired(r1_fastq_reader(), r2_fastq_reader(), true, usize::MAX);
         assert_eq!(records[0].0.id(), b"r1");
         assert_eq!(records[0].1.id(), b"r1");
     }
 
     #[test]
     fn sort_single_fasta() {
-        let mut records = join_reader(r1_fasta_reader());
-        sort_records(&mut records);
+        let records = sort_single(r1_fasta_reader(), usize::MAX);
         assert_eq!(records[0].id(), b"r1");
     }
 
     #[test]
     fn sort_paired_fasta_by_r2() {
-        let mut records = join_readers(r1_fasta_reader(), r2_fasta_reader());
-        sort_paired_records(&mut records, false);
+        let records = sort_paired(r1_fasta_reader(), r2_fasta_reader(), false, usize::MAX);
         assert_eq!(records[0].0.id(), b"r2");
         assert_eq!(records[0].1.id(), b"r2");
     }
 
     #[test]
     fn sort_paired_fasta_by_r1() {
-        let mut records = join_readers(r1_fasta_reader(), r2_fasta_reader());
-        sort_paired_records(&mut records, true);
+        let records = sort_paired(r1_fasta_reader(), r2_fasta_reader(), true, usize::MAX);
         assert_eq!(records[0].0.id(), b"r1");
         assert_eq!(records[0].1.id(), b"r1");
     }
+
+    #[test]
+    fn sort_single_spilled() {
+        for reader in [r1_fastq_reader(), r1_fasta_reader()] {
+            let records = sort_single(reader, 1);
+            assert_eq!(records[0].id(), b"r1");
+            assert_eq!(records[1].id(), b"r2");
+        }
+    }
+
+    #[test]
+    fn sort_paired_spilled() {
+        for sort_by_r1 in [false, true] {
+            let expected: &[u8] = if sort_by_r1 { b"r1" } else { b"r2" };
+            let fastq = sort_paired(r1_fastq_reader(), r2_fastq_reader(), sort_by_r1, 1);
+            let fasta = sort_paired(r1_fasta_reader(), r2_fasta_reader(), sort_by_r1, 1);
+            for records in [fastq, fasta] {
+                assert_eq!(records[0].0.id(), expected);
+                assert_eq!(records[0].1.id(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn sort_joined_records() {
+        let sorter = Sorter::new(&[], None).unwrap();
+        let mut records = join_reader(r2_fastq_reader());
+        sort_records(&mut records, &sorter);
+        assert_eq!(records[0].id(), b"r2");
+
+        let mut records = join_readers(r1_fastq_reader(), r2_fastq_reader());
+        sort_paired_records(&mut records, &sorter, false);
+        let (mut out_r1, mut out_r2) = (Vec::new(), Vec::new());
+        write_pair(
+            &mut out_r1,
+            &mut out_r2,
+            &records,
+            &OutputOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(out_r1, b"@r2\nTGCA\n+\nIIII\n@r1\nACGT\n+\nIIII\n");
+        assert_eq!(out_r2, b"@r2\nACGT\n+\nIIII\n@r1\nTGCA\n+\nIIII\n");
+    }
+
+    #[test]
+    fn external_sort_matches_in_memory() {
+        let sequence = (0..500)
+            .map(|i| {
+                format!(
+                    ">seq.{i}\n{}\n",
+                    ["ACGT", "AAAA", "TTGA", "CCCC", "AAAA"][i % 5]
+                )
+            })
+            .collect::<String>();
+        let sorted = |max_memory: usize| {
+            let reader = FastaReader::new(std::io::Cursor::new(sequence.clone()));
+            let mut ids = Vec::new();
+            external_sort(
+                Box::new(reader.map(Ok)),
+                max_memory,
+                |a: &Record, b: &Record| a.seq().cmp(b.seq()),
+                |record| {
+                    ids.push(record.id_str().to_string());
+                    Ok(())
+                },
+            )
+            .unwrap();
+            ids
+        };
+        let in_memory = sorted(usize::MAX);
+        assert_eq!(in_memory.len(), 500);
+        // ties keep their input order
+        assert_eq!(&in_memory[..3], ["seq.1", "seq.4", "seq.6"]);
+        // single runs, several runs, and merges over multiple levels
+        for max_memory in [1000, 10_000, 1] {
+            assert_eq!(sorted(max_memory), in_memory);
+        }
+    }
+
+    #[test]
+    fn external_sort_keeps_pairs() {
+        let reader = PairedReader::new(r1_fastq_reader(), r2_fastq_reader());
+        let mut pairs = Vec::new();
+        external_sort(
+            Box::new(reader),
+            1,
+            |a: &RecordPair, b: &RecordPair| a.1.seq().cmp(b.1.seq()),
+            |(r1, r2)| {
+                pairs.push((r1.id_str().to_string(), r2.seq().to_vec()));
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            pairs,
+            vec![
+                ("r2".into(), b"ACGT".to_vec()),
+                ("r1".into(), b"TGCA".to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn sort_key_specs() {
+        let key = SortKey::parse("length:rev").unwrap();
+        assert_eq!(key.field, SortField::Length);
+        assert!(key.reverse);
+        assert!(!SortKey::parse("gc").unwrap().reverse);
+        assert!(SortKey::parse("width").is_err());
+        assert!(Sorter::new(&["capture".into()], None).is_err());
+        assert!(Sorter::new(&["header".into()], Some("_(.*)".into())).is_err());
+    }
+
+    #[test]
+    fn natural_order() {
+        let mut ids = vec!["read.10", "read.2", "read.1", "read.02", "read", "read.1a"];
+        ids.sort_by(|a, b| natural_cmp(a.as_bytes(), b.as_bytes()));
+        assert_eq!(
+            ids,
+            vec!["read", "read.1", "read.1a", "read.02", "read.2", "read.10"]
+        );
+    }
+
+    #[test]
+    fn sort_by_chained_keys() {
+        let sequence: &[u8] = b"@r.10_B\nACGT\n+\nIIII\n@r.2_A\nAAA\n+\n###\n@r.1_B\nGG\n+\nII\n@r.3_A\nCCCC\n+\n####\n";
+        let sorted = |keys: &[&str], capture: Option<&str>| {
+            let keys = keys.iter().map(|k| k.to_string()).collect::<Vec<_>>();
+            let sorter = Sorter::new(&keys, capture.map(String::from)).unwrap();
+            let mut records = FastqReader::new(sequence).collect::<Vec<_>>();
+            sort_records(&mut records, &sorter);
+            records
+                .iter()
+                .map(|r| r.id_str().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            sorted(&["header"], None),
+            ["r.10_B", "r.1_B", "r.2_A", "r.3_A"]
+        );
+        assert_eq!(
+            sorted(&["natural"], None),
+            ["r.1_B", "r.2_A", "r.3_A", "r.10_B"]
+        );
+        // ties of the first key keep the input order
+        assert_eq!(
+            sorted(&["length:rev"], None),
+            ["r.10_B", "r.3_A", "r.2_A", "r.1_B"]
+        );
+        assert_eq!(
+            sorted(&["capture", "length"], Some("_([AB])$")),
+            ["r.2_A", "r.3_A", "r.1_B", "r.10_B"]
+        );
+        assert_eq!(
+            sorted(&["gc:rev", "quality:rev"], None),
+            ["r.1_B", "r.3_A", "r.10_B", "r.2_A"]
+        );
+    }
+
+    #[test]
+    fn output_naming() {
+        let output = SortOutput {
+            prefix: Some("out".into()),
+            gzip: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            output.single_path(RecordFormat::Fasta).as_deref(),
+            Some("out_R1.fasta.gz")
+        );
+        let output = SortOutput {
+            gzip: false,
+            ..output
+        };
+        assert_eq!(
+            output.prefixed("out", "R2", RecordFormat::Fastq),
+            "out_R2.fastq"
+        );
+        assert_eq!(SortOutput::default().single_path(RecordFormat::Fastq), None);
+    }
+
+    #[test]
+    fn peek_input_format() {
+        let options = OutputOptions::default();
+        let (format, records) =
+            peek_format(Box::new(r1_fasta_reader().map(Ok)), &options, |r| r).unwrap();
+        assert_eq!(format, RecordFormat::Fasta);
+        assert_eq!(records.count(), 2);
+
+        let pairs = PairedReader::new(r1_fastq_reader(), r2_fastq_reader());
+        let (format, _) = peek_format(Box::new(pairs), &options, |pair| &pair.0).unwrap();
+        assert_eq!(format, RecordFormat::Fastq);
+
+        let options = OutputOptions {
+            record_format: Some(RecordFormat::Fastq),
+            ..Default::default()
+        };
+        let (format, _) =
+            peek_format(Box::new(r1_fasta_reader().map(Ok)), &options, |r| r).unwrap();
+        assert_eq!(format, RecordFormat::Fastq);
+    }
+
+    #[test]
+    fn sort_paired_records_by_keys() {
+        let sorter = Sorter::new(&["header:rev".into()], None).unwrap();
+        let mut pairs = PairedReader::new(r1_fasta_reader(), r2_fasta_reader())
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        sort_paired_records(&mut pairs, &sorter, true);
+        assert_eq!(pairs[0].0.id(), b"r2");
+        assert_eq!(pairs[0].1.seq(), b"ACGT");
+    }
 }
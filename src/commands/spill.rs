@@ -0,0 +1,69 @@
+use super::{io::fastx_reader, PairedReader, RecordPair};
+use anyhow::Result;
+use fxread::Record;
+use std::{
+    io::{Read, Write},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Fallible iterator over records or pairs
+pub type Items<T> = Box<dyn Iterator<Item = Result<T>>>;
+
+/// Approximate bookkeeping bytes of an item held in memory
+const ITEM_OVERHEAD: usize = 64;
+
+/// Number of spill files created by the process so far
+static NUM_SPILLS: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns a path in the temporary directory which is unique within the process
+///
+/// Spill files are created through [`super::TempPath`], which adds the process id.
+pub fn spill_path(name: &str) -> String {
+    let index = NUM_SPILLS.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir()
+        .join(format!("fxtools-{name}-{index}"))
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Records or pairs which can be spilled to temporary files and read back
+pub trait Spill: Sized {
+    /// Writes the item to a spill file
+    fn spill<W: Write>(&self, writer: &mut W) -> Result<()>;
+
+    /// Reads the items of a (possibly compressed) spill file
+    fn unspill<R: Read + 'static>(reader: R) -> Result<Items<Self>>;
+
+    /// Approximate number of bytes the item occupies in memory
+    fn size(&self) -> usize;
+}
+impl Spill for Record {
+    fn spill<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(self.data())?;
+        Ok(())
+    }
+
+    fn unspill<R: Read + 'static>(reader: R) -> Result<Items<Self>> {
+        Ok(Box::new(fastx_reader(reader)?.map(Ok)))
+    }
+
+    fn size(&self) -> usize {
+        self.data().len() + self.seq().len() + ITEM_OVERHEAD
+    }
+}
+impl Spill for RecordPair {
+    /// Writes the mates interleaved
+    fn spill<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(self.0.data())?;
+        writer.write_all(self.1.data())?;
+        Ok(())
+    }
+
+    fn unspill<R: Read + 'static>(reader: R) -> Result<Items<Self>> {
+        Ok(Box::new(PairedReader::interleaved(fastx_reader(reader)?)))
+    }
+
+    fn size(&self) -> usize {
+        self.0.size() + self.1.size()
+    }
+}
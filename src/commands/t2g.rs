@@ -1,31 +1,32 @@
-use super::match_output_stream;
+use super::{match_fastx_reader, match_output_stream, OutputOptions};
+use crate::error::FxError;
 use anyhow::Result;
-use fxread::{initialize_reader, initialize_stdin_reader, Record};
-use std::io::stdin;
+use fxread::Record;
+use std::io::Write;
 
-fn get_line(record: &Record, symbol: bool, dot_version: bool) -> String {
+/// Builds the transcript to gene line for a record
+pub fn get_line(record: &Record, symbol: bool, dot_version: bool) -> Result<String> {
     // select the id
     let id = record.id_str();
+    let missing = |field: &str| FxError::MalformedHeader(format!("No {} in header: {}", field, id));
 
     // split the id into attributes
     let mut attributes = id.split_whitespace();
 
     // get the transcript id
-    let transcript_id = attributes.next().expect("No ensembl id");
+    let transcript_id = attributes.next().ok_or_else(|| missing("ensembl id"))?;
 
     // get the gene id
     let gene_id = if dot_version {
         attributes
             .next()
-            .expect("No gene id")
+            .ok_or_else(|| missing("gene id"))?
             .replace("gene_id:", "")
     } else {
         attributes
             .next()
-            .expect("No gene id")
-            .split('.')
-            .next()
-            .expect("No gene id")
+            .and_then(|gene_id| gene_id.split('.').next())
+            .ok_or_else(|| missing("gene id"))?
             .replace("gene_id:", "")
     };
 
@@ -33,42 +34,45 @@ fn get_line(record: &Record, symbol: bool, dot_version: bool) -> String {
     if symbol {
         let gene_symbol = attributes
             .next()
-            .expect("No gene symbol")
+            .ok_or_else(|| missing("gene symbol"))?
             .replace("gene_name:", "");
 
         // if the gene symbol is empty, just return the transcript and gene id
         if gene_symbol.is_empty() {
-            format!("{}\t{}\n", transcript_id, gene_id)
+            Ok(format!("{}\t{}\n", transcript_id, gene_id))
         } else {
-            format!("{}\t{}\n", transcript_id, gene_symbol)
+            Ok(format!("{}\t{}\n", transcript_id, gene_symbol))
         }
 
     // otherwise just return the transcript and gene id
     } else {
-        format!("{}\t{}\n", transcript_id, gene_id)
+        Ok(format!("{}\t{}\n", transcript_id, gene_id))
     }
 }
 
+/// Writes the transcript to gene mapping of all records to the writer
+pub fn write_t2g<I, W>(reader: I, writer: &mut W, symbol: bool, dot_version: bool) -> Result<()>
+where
+    I: Iterator<Item = Record>,
+    W: Write,
+{
+    for record in reader {
+        write!(writer, "{}", get_line(&record, symbol, dot_version)?)?;
+    }
+    Ok(())
+}
+
 pub fn run(
     input: Option<String>,
     output: Option<String>,
     symbol: bool,
     dot_version: bool,
-    compression_threads: Option<usize>,
-    compression_level: Option<usize>,
+    options: OutputOptions,
 ) -> Result<()> {
-    let reader = if let Some(path) = input {
-        initialize_reader(&path)
-    } else {
-        initialize_stdin_reader(stdin().lock())
-    }?;
-    let mut writer = match_output_stream(output, compression_threads, compression_level)?;
-    reader
-        .map(|x| get_line(&x, symbol, dot_version))
-        .for_each(|x| {
-            write!(writer, "{x}").expect("Error Writing to File");
-        });
-    Ok(())
+    let reader = match_fastx_reader(input, &options.stats)?;
+    let mut writer = match_output_stream(output, options)?;
+    write_t2g(reader, &mut writer, symbol, dot_version)?;
+    writer.finish()
 }
 
 #[cfg(test)]
@@ -90,7 +94,7 @@ mod testing {
     fn run_fasta_nodot_gene_id() {
         let mut reader = fasta_reader();
         let record = reader.next().unwrap();
-        let line = get_line(&record, false, false);
+        let line = get_line(&record, false, false).unwrap();
         assert_eq!(line, "ENST00000003583.12\tENSG00000001460\n");
     }
 
@@ -98,7 +102,7 @@ mod testing {
     fn run_fasta_nodot_gene_symbol() {
         let mut reader = fasta_reader();
         let record = reader.next().unwrap();
-        let line = get_line(&record, true, false);
+        let line = get_line(&record, true, false).unwrap();
         assert_eq!(line, "ENST00000003583.12\tSTPG1\n");
     }
 
@@ -106,7 +110,7 @@ mod testing {
     fn run_fasta_dot_gene_id() {
         let mut reader = fasta_reader();
         let record = reader.next().unwrap();
-        let line = get_line(&record, false, true);
+        let line = get_line(&record, false, true).unwrap();
         assert_eq!(line, "ENST00000003583.12\tENSG00000001460.18\n");
     }
 
@@ -114,7 +118,7 @@ mod testing {
     fn run_fasta_nodot_missing_gene_name() {
         let mut reader = fasta_missing_symbol();
         let record = reader.next().unwrap();
-        let line = get_line(&record, true, false);
+        let line = get_line(&record, true, false).unwrap();
         assert_eq!(line, "ENST00000003583.12\tENSG00000001460\n");
     }
 
@@ -122,7 +126,18 @@ mod testing {
     fn run_fasta_dot_missing_gene_name() {
         let mut reader = fasta_missing_symbol();
         let record = reader.next().unwrap();
-        let line = get_line(&record, true, true);
+        let line = get_line(&record, true, true).unwrap();
         assert_eq!(line, "ENST00000003583.12\tENSG00000001460.18\n");
     }
+
+    #[test]
+    fn run_fasta_missing_gene_id() {
+        let sequence: &'static [u8] = b">ENST00000003583.12\nACTACTACT";
+        let record = FastaReader::new(sequence).next().unwrap();
+        let result = get_line(&record, false, false);
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<FxError>(),
+            Some(FxError::MalformedHeader(_))
+        ));
+    }
 }
@@ -1,26 +1,68 @@
-use super::match_output_stream;
+use super::{
+    match_fastx_reader, match_output_stream, match_paired_reader, match_paired_writer,
+    write_record, OutputOptions, PairedOptions, PairedWriter, RecordPair,
+};
 use anyhow::Result;
-use fxread::{initialize_reader, initialize_stdin_reader};
-use std::io::stdin;
+use fxread::Record;
+use std::io::Write;
 
+/// Writes `num_records` records of the reader to the writer after skipping the first `skip`
+pub fn take_records<I, W>(
+    reader: I,
+    writer: &mut W,
+    num_records: usize,
+    skip: usize,
+    options: &OutputOptions,
+) -> Result<()>
+where
+    I: Iterator<Item = Record>,
+    W: Write,
+{
+    for record in reader.skip(skip).take(num_records) {
+        write_record(writer, &record, options)?;
+    }
+    Ok(())
+}
+
+/// Writes `num_pairs` pairs of the reader to the writer after skipping the first `skip`
+pub fn take_pairs<I, W>(
+    reader: I,
+    writer: &mut PairedWriter<W>,
+    num_pairs: usize,
+    skip: usize,
+    options: &OutputOptions,
+) -> Result<()>
+where
+    I: Iterator<Item = Result<RecordPair>>,
+    W: Write,
+{
+    // Skipped pairs are still checked so unbalanced inputs are reported
+    for (idx, pair) in reader.enumerate().take(skip + num_pairs) {
+        let pair = pair?;
+        if idx >= skip {
+            writer.write_pair(&pair, options)?;
+        }
+    }
+    Ok(())
+}
+
+/// Runs the `take` command.
 pub fn run(
     input: Option<String>,
     output: Option<String>,
     num_records: usize,
     skip: usize,
-    compression_threads: Option<usize>,
-    compression_level: Option<usize>,
+    paired: PairedOptions,
+    options: OutputOptions,
 ) -> Result<()> {
-    let reader = if let Some(path) = input {
-        initialize_reader(&path)
-    } else {
-        initialize_stdin_reader(stdin().lock())
-    }?;
-    let mut writer = match_output_stream(output, compression_threads, compression_level)?;
-
-    let record_iter = reader.skip(skip).take(num_records);
-    for record in record_iter {
-        write!(writer, "{}", record.as_str(),)?;
+    if paired.is_paired() {
+        let reader = match_paired_reader(input, &paired, &options.stats)?;
+        let mut writer = match_paired_writer(output, &paired, options.clone())?;
+        take_pairs(reader, &mut writer, num_records, skip, &options)?;
+        return writer.finish();
     }
-    Ok(())
+    let reader = match_fastx_reader(input, &options.stats)?;
+    let mut writer = match_output_stream(output, options.clone())?;
+    take_records(reader, &mut writer, num_records, skip, &options)?;
+    writer.finish()
 }
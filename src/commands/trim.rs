@@ -1,133 +1,614 @@
-use std::{
-    borrow::Borrow,
-    io::{stdin, Write},
-    str::from_utf8,
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Mutex,
 };
 
-use anyhow::Result;
-use fxread::{initialize_reader, initialize_stdin_reader, Record};
-use memchr::memmem;
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use fxread::Record;
+use serde::Serialize;
 use spinoff::{spinners::Dots12, Color, Spinner, Streams};
 
-use super::match_output_stream;
+use super::{
+    adapter::{
+        read_adapters, Adapter, AdapterHit, AdapterKind, AdapterSeq, Anchor, KeepSide, MatchOptions,
+    },
+    io::{fastx_reader, match_input_stream},
+    match_fastx_reader, match_output_stream, match_paired_reader, match_paired_writer,
+    process_parallel, tag_record, validate_record, write_record, OutputOptions, OutputStream,
+    PairFilter, PairedOptions, PairedWriter, RecordPair,
+};
+use crate::report::Stats;
+use serde_json::json;
+
+/// What happens to records in which no adapter is found
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Untrimmed {
+    /// Drop untrimmed records
+    #[default]
+    Discard,
+    /// Write untrimmed records unchanged to the output
+    Keep,
+}
+
+/// A record or pair after trimming
+#[derive(Debug)]
+pub enum Trimmed<T> {
+    /// An adapter was found and trimmed
+    Trimmed(T),
+    /// No adapter was found and the record is unchanged
+    Untrimmed(T),
+}
 
-struct Trimmer {
-    adapter: String,
-    trim_adapter: bool,
-    num_records: usize,
-    num_trimmed: usize,
+/// Matches of a single adapter across all records
+#[derive(Debug, Clone, Serialize)]
+pub struct AdapterStats {
+    /// Name of the adapter
+    pub name: String,
+    /// Number of records trimmed by the adapter
+    pub num_matches: usize,
+    /// Number of matches by start position of the adapter in the sequence
+    pub positions: Vec<usize>,
+}
+impl AdapterStats {
+    /// Formats the non-empty bins of the position histogram as `position:count` pairs
+    pub fn histogram(&self) -> String {
+        let bins = self
+            .positions
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(position, count)| format!("{position}:{count}"))
+            .collect::<Vec<_>>();
+        if bins.is_empty() {
+            "-".to_string()
+        } else {
+            bins.join(",")
+        }
+    }
+}
+
+/// Selects records containing an adapter and trims the sequence preceding it
+pub struct Trimmer {
+    adapters: Vec<Adapter>,
+    side: KeepSide,
+    matching: MatchOptions,
+    tag_errors: bool,
+    num_records: AtomicUsize,
+    num_trimmed: AtomicUsize,
+    /// Number of trimmed records (or mates) by the number of errors in their adapter match
+    num_errors: Vec<AtomicUsize>,
+    /// Histogram of match positions of each adapter
+    positions: Vec<Mutex<Vec<usize>>>,
 }
 impl Trimmer {
+    /// Creates a new trimmer for exact matches of the adapter, optionally trimming the
+    /// adapter itself
     pub fn new(adapter: String, trim_adapter: bool) -> Self {
+        let adapter = Adapter {
+            name: adapter.clone(),
+            kind: AdapterKind::Single(AdapterSeq {
+                seq: adapter.into_bytes(),
+                anchor: Anchor::None,
+            }),
+        };
+        let side = KeepSide::new(trim_adapter, false);
+        Self::with_adapters(vec![adapter], side, MatchOptions::default(), false)
+    }
+
+    /// Creates a new trimmer for approximate matches of any of the adapters
+    ///
+    /// Each record is trimmed by the adapter with the best match, keeping the `side` of
+    /// single adapters. If `tag_errors` is set
+    /// the number of errors in the match is appended to the header of each trimmed
+    /// record as `adapter_errors=<n>`.
+    pub fn with_adapters(
+        adapters: Vec<Adapter>,
+        side: KeepSide,
+        matching: MatchOptions,
+        tag_errors: bool,
+    ) -> Self {
+        let max_len = adapters.iter().map(Adapter::len).max().unwrap_or(0);
+        let num_errors = (0..=max_len).map(|_| AtomicUsize::new(0)).collect();
+        let positions = adapters.iter().map(|_| Mutex::new(Vec::new())).collect();
         Self {
-            adapter,
-            trim_adapter,
-            num_records: 0,
-            num_trimmed: 0,
+            adapters,
+            side,
+            matching,
+            tag_errors,
+            num_records: AtomicUsize::new(0),
+            num_trimmed: AtomicUsize::new(0),
+            num_errors,
+            positions,
         }
     }
 
-    pub fn trim(&mut self, record: &Record) -> Option<String> {
-        self.num_records += 1;
-        if let Some(idx) = memmem::find(record.seq(), self.adapter.as_bytes()) {
-            self.num_trimmed += 1;
-            Some(self.prepare_record(record, idx))
-        } else {
-            None
+    /// Returns the number of records processed, counting each pair once
+    pub fn num_records(&self) -> usize {
+        self.num_records.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of records trimmed, counting each pair once
+    pub fn num_trimmed(&self) -> usize {
+        self.num_trimmed.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of trimmed records indexed by the number of errors in their
+    /// adapter match, counting each trimmed mate of a pair
+    pub fn error_counts(&self) -> Vec<usize> {
+        let mut counts = self
+            .num_errors
+            .iter()
+            .map(|x| x.load(Ordering::Relaxed))
+            .collect::<Vec<_>>();
+        while counts.len() > 1 && counts.last() == Some(&0) {
+            counts.pop();
         }
+        counts
     }
 
-    fn prepare_record(&self, record: &Record, index: usize) -> String {
-        if record.qual().is_some() {
-            format!(
-                "@{}\n{}\n{}\n{}\n",
-                from_utf8(record.id()).expect("invalid utf8"),
-                from_utf8(self.trim_sequence(record, index)).expect("invalid utf8"),
-                from_utf8(record.plus().unwrap()).expect("invalid utf8"),
-                from_utf8(self.trim_qual(record, index)).expect("invalid utf8"),
-            )
-        } else {
-            format!(
-                ">{}\n{}\n",
-                from_utf8(record.id()).expect("invalid utf8"),
-                from_utf8(self.trim_sequence(record, index)).expect("invalid utf8"),
-            )
+    /// Returns the number of matches and match positions of each adapter
+    pub fn adapter_stats(&self) -> Vec<AdapterStats> {
+        self.adapters
+            .iter()
+            .zip(&self.positions)
+            .map(|(adapter, positions)| {
+                let positions = positions.lock().unwrap().clone();
+                AdapterStats {
+                    name: adapter.name.clone(),
+                    num_matches: positions.iter().sum(),
+                    positions,
+                }
+            })
+            .collect()
+    }
+
+    /// Trims the record if the adapter is found and returns it
+    pub fn trim(&self, record: Record) -> Result<Option<Record>> {
+        match self.trim_record(record)? {
+            Trimmed::Trimmed(record) => Ok(Some(record)),
+            Trimmed::Untrimmed(_) => Ok(None),
         }
     }
 
-    fn trim_sequence<'a>(&self, record: &'a Record, index: usize) -> &'a [u8] {
-        if self.trim_adapter {
-            &record.seq()[index + self.adapter.len()..]
-        } else {
-            &record.seq()[index..]
+    /// Trims the record if the adapter is found
+    pub fn trim_record(&self, mut record: Record) -> Result<Trimmed<Record>> {
+        self.num_records.fetch_add(1, Ordering::Relaxed);
+        match self.find(&record) {
+            Some(hit) => {
+                self.num_trimmed.fetch_add(1, Ordering::Relaxed);
+                self.apply(&mut record, hit)?;
+                Ok(Trimmed::Trimmed(record))
+            }
+            None => Ok(Trimmed::Untrimmed(record)),
         }
     }
 
-    fn trim_qual<'a>(&self, record: &'a Record, index: usize) -> &'a [u8] {
-        if self.trim_adapter {
-            &record
-                .qual()
-                .expect("Missing Quality - called from trim_qual")[index + self.adapter.len()..]
-        } else {
-            &record
-                .qual()
-                .expect("Missing Quality - called from trim_qual")[index..]
+    /// Trims both mates of a pair if the adapter was found in the mates required by the
+    /// pair filter
+    ///
+    /// Mates without the adapter are kept untrimmed, and pairs failing the filter are
+    /// returned unchanged.
+    pub fn trim_pair(
+        &self,
+        (mut r1, mut r2): RecordPair,
+        pair_filter: PairFilter,
+    ) -> Result<Trimmed<RecordPair>> {
+        self.num_records.fetch_add(1, Ordering::Relaxed);
+        let hit_r1 = self.find(&r1);
+        let hit_r2 = self.find(&r2);
+        if !pair_filter.keep(hit_r1.is_some(), hit_r2.is_some()) {
+            return Ok(Trimmed::Untrimmed((r1, r2)));
+        }
+        self.num_trimmed.fetch_add(1, Ordering::Relaxed);
+        if let Some(hit) = hit_r1 {
+            self.apply(&mut r1, hit)?;
         }
+        if let Some(hit) = hit_r2 {
+            self.apply(&mut r2, hit)?;
+        }
+        Ok(Trimmed::Trimmed((r1, r2)))
+    }
+
+    /// Returns the index of the adapter with the best match and its match
+    ///
+    /// Matches with more aligned adapter nucleotides net of errors are preferred, then
+    /// matches with fewer errors, then adapters given first.
+    fn best_hit(&self, seq: &[u8]) -> Option<(usize, AdapterHit)> {
+        self.adapters
+            .iter()
+            .enumerate()
+            .filter_map(|(index, adapter)| {
+                adapter
+                    .find(seq, &self.matching, self.side)
+                    .map(|hit| (index, hit))
+            })
+            .reduce(|best, hit| {
+                if (hit.1.score, best.1.errors) > (best.1.score, hit.1.errors) {
+                    hit
+                } else {
+                    best
+                }
+            })
+    }
+
+    /// Returns the best adapter match of the record
+    fn find(&self, record: &Record) -> Option<(usize, AdapterHit)> {
+        self.best_hit(record.seq())
+    }
+
+    /// Trims the record to its adapter match in place and records the match
+    fn apply(&self, record: &mut Record, (index, hit): (usize, AdapterHit)) -> Result<()> {
+        self.num_errors[hit.errors].fetch_add(1, Ordering::Relaxed);
+        {
+            let mut positions = self.positions[index].lock().unwrap();
+            if positions.len() <= hit.position {
+                positions.resize(hit.position + 1, 0);
+            }
+            positions[hit.position] += 1;
+        }
+        record.trim_right(record.seq().len() - hit.keep.end)?;
+        record.trim_left(hit.keep.start)?;
+        if self.tag_errors {
+            *record = tag_record(record, &format!("adapter_errors={}", hit.errors))?;
+        }
+        Ok(())
     }
 }
 
-pub fn write_conditional_output_string<W, I, R>(
-    writer: &mut W,
-    reader: I,
-    f: &mut dyn FnMut(&Record) -> Option<String>,
-) where
-    W: Write,
-    I: Iterator<Item = R>,
-    R: Borrow<Record>,
-{
-    reader.for_each(|x| {
-        assert!(
-            x.borrow().valid(),
-            "Invalid Nucleotides in record: {}",
-            from_utf8(x.borrow().id()).expect("invalid utf8")
-        );
-        if let Some(s) = f(x.borrow()) {
-            write!(writer, "{}", s).expect("Error Writing to File");
+/// Parses the adapters given on the command line followed by those of the FASTA file
+///
+/// Adapters given on the command line are named by their sequence. The adapter file is
+/// registered as an input in `stats`.
+pub fn load_adapters(
+    specs: &[String],
+    adapter_file: Option<String>,
+    stats: &Stats,
+) -> Result<Vec<Adapter>> {
+    let mut adapters = specs
+        .iter()
+        .map(|spec| Adapter::parse(spec, spec))
+        .collect::<Result<Vec<_>>>()?;
+    if let Some(path) = adapter_file {
+        let reader = fastx_reader(match_input_stream(Some(path), stats)?)?;
+        adapters.extend(read_adapters(reader)?);
+    }
+    if adapters.is_empty() {
+        bail!("No adapters provided");
+    }
+    Ok(adapters)
+}
+
+/// Opens the output for untrimmed pairs, interleaving mates if no R2 output is provided
+fn match_untrimmed_pair_writer(
+    output: Option<String>,
+    output_r2: Option<String>,
+    options: OutputOptions,
+) -> Result<Option<PairedWriter<OutputStream>>> {
+    let Some(output) = output else {
+        return Ok(None);
+    };
+    let writer_r1 = match_output_stream(Some(output), options.clone())?;
+    Ok(Some(match output_r2 {
+        Some(output_r2) => {
+            PairedWriter::new(writer_r1, match_output_stream(Some(output_r2), options)?)
         }
-    });
+        None => PairedWriter::interleaved(writer_r1),
+    }))
 }
 
+/// Runs the `trim` command.
+#[allow(clippy::too_many_arguments)]
 pub fn run(
     input: Option<String>,
-    adapter: &str,
+    adapters: Vec<Adapter>,
     output: Option<String>,
-    trim_adapter: bool,
-    compression_threads: Option<usize>,
-    compression_level: Option<usize>,
+    side: KeepSide,
+    matching: MatchOptions,
+    tag_errors: bool,
+    untrimmed: Untrimmed,
+    untrimmed_output: Option<String>,
+    untrimmed_output_r2: Option<String>,
+    pair_filter: PairFilter,
+    paired: PairedOptions,
+    options: OutputOptions,
+    threads: usize,
 ) -> Result<()> {
-    let reader = if let Some(path) = input {
-        initialize_reader(&path)
-    } else {
-        initialize_stdin_reader(stdin().lock())
-    }?;
-    let mut trimmer = Trimmer::new(adapter.to_string(), trim_adapter);
-    let mut writer = match_output_stream(output, compression_threads, compression_level)?;
+    let trimmer = Trimmer::with_adapters(adapters, side, matching, tag_errors);
+    let names = trimmer
+        .adapters
+        .iter()
+        .map(|adapter| adapter.name.as_str())
+        .collect::<Vec<_>>();
 
     let mut spinner = Spinner::new_with_stream(
         Dots12,
-        format!("Trimming records with adapter: {}", adapter),
+        format!("Trimming records with adapters: {}", names.join(", ")),
         Color::Green,
         Streams::Stderr,
     );
 
-    write_conditional_output_string(&mut writer, reader, &mut |x| trimmer.trim(x));
+    if paired.is_paired() {
+        let reader = match_paired_reader(input, &paired, &options.stats)?;
+        let mut writer = match_paired_writer(output, &paired, options.clone())?;
+        let mut untrimmed_writer =
+            match_untrimmed_pair_writer(untrimmed_output, untrimmed_output_r2, options.clone())?;
+        process_parallel(
+            reader,
+            threads,
+            |pair| {
+                let pair = pair?;
+                validate_record(&pair.0)?;
+                validate_record(&pair.1)?;
+                trimmer.trim_pair(pair, pair_filter).map(Some)
+            },
+            |trimmed| match (trimmed, untrimmed, untrimmed_writer.as_mut()) {
+                (Trimmed::Trimmed(pair), _, _) => writer.write_pair(&pair, &options),
+                (Trimmed::Untrimmed(pair), _, Some(untrimmed_writer)) => {
+                    untrimmed_writer.write_pair(&pair, &options)
+                }
+                (Trimmed::Untrimmed(pair), Untrimmed::Keep, None) => {
+                    writer.write_pair(&pair, &options)
+                }
+                (Trimmed::Untrimmed(_), Untrimmed::Discard, None) => Ok(()),
+            },
+        )?;
+        writer.finish()?;
+        if let Some(untrimmed_writer) = untrimmed_writer {
+            untrimmed_writer.finish()?;
+        }
+    } else {
+        let reader = match_fastx_reader(input, &options.stats)?;
+        let mut writer = match_output_stream(output, options.clone())?;
+        let mut untrimmed_writer = untrimmed_output
+            .map(|path| match_output_stream(Some(path), options.clone()))
+            .transpose()?;
+        process_parallel(
+            reader,
+            threads,
+            |record| {
+                validate_record(&record)?;
+                trimmer.trim_record(record).map(Some)
+            },
+            |trimmed| match (trimmed, untrimmed, untrimmed_writer.as_mut()) {
+                (Trimmed::Trimmed(record), _, _) => write_record(&mut writer, &record, &options),
+                (Trimmed::Untrimmed(record), _, Some(untrimmed_writer)) => {
+                    write_record(untrimmed_writer, &record, &options)
+                }
+                (Trimmed::Untrimmed(record), Untrimmed::Keep, None) => {
+                    write_record(&mut writer, &record, &options)
+                }
+                (Trimmed::Untrimmed(_), Untrimmed::Discard, None) => Ok(()),
+            },
+        )?;
+        writer.finish()?;
+        if let Some(untrimmed_writer) = untrimmed_writer {
+            untrimmed_writer.finish()?;
+        }
+    }
 
+    let unit = if paired.is_paired() {
+        "pairs"
+    } else {
+        "records"
+    };
     spinner.stop_with_message(&format!(
-        "Trimmed {} out of {} records ( {:.2}% )",
-        trimmer.num_trimmed,
-        trimmer.num_records,
-        100.0 * trimmer.num_trimmed as f64 / trimmer.num_records as f64
+        "Trimmed {} out of {} {unit} ( {:.2}% )",
+        trimmer.num_trimmed(),
+        trimmer.num_records(),
+        100.0 * trimmer.num_trimmed() as f64 / trimmer.num_records() as f64
     ));
 
-    Ok(())
+    let adapter_stats = trimmer.adapter_stats();
+    for stats in &adapter_stats {
+        let mode = stats
+            .positions
+            .iter()
+            .enumerate()
+            .max_by_key(|(position, count)| (**count, std::cmp::Reverse(*position)))
+            .filter(|(_, count)| **count > 0)
+            .map_or("-".to_string(), |(position, _)| position.to_string());
+        eprintln!(
+            "{}\t{} matches\tmost frequent position: {}\tpositions: {}",
+            stats.name,
+            stats.num_matches,
+            mode,
+            stats.histogram()
+        );
+    }
+
+    options.stats.section(
+        "trim",
+        json!({
+            "adapters": adapter_stats,
+            "num_records": trimmer.num_records(),
+            "num_trimmed": trimmer.num_trimmed(),
+            "error_counts": trimmer.error_counts(),
+        }),
+    )
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::commands::process_records;
+    use fxread::{FastaReader, FastqReader};
+
+    fn trim_all(reader: impl Iterator<Item = Record>, trimmer: &Trimmer) -> String {
+        let mut output = Vec::new();
+        let options = OutputOptions::default();
+        process_records(reader, &mut output, 2, &options, |record| {
+            trimmer.trim(record)
+        })
+        .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn trim_fasta() {
+        let fasta: &'static [u8] = b">seq.0\nAAGGTTCC\n>seq.1\nACACACAC\n";
+        let trimmer = Trimmer::new("GGT".to_string(), false);
+        let output = trim_all(FastaReader::new(fasta), &trimmer);
+        assert_eq!(output, ">seq.0\nGGTTCC\n");
+        assert_eq!(trimmer.num_records(), 2);
+        assert_eq!(trimmer.num_trimmed(), 1);
+    }
+
+    #[test]
+    fn trim_fastq_adapter() {
+        let fastq: &'static [u8] = b"@seq.0\nAAGGTTCC\n+\n12345678\n";
+        let trimmer = Trimmer::new("GGT".to_string(), true);
+        let output = trim_all(FastqReader::new(fastq), &trimmer);
+        assert_eq!(output, "@seq.0\nTCC\n+\n678\n");
+    }
+
+    #[test]
+    fn trim_approximate() {
+        let fastq: &'static [u8] =
+            b"@seq.0\nAAACGTTCGTACTT\n+\n12345678901234\n@seq.1\nTTTTTTTTACGT\n+\n123456789012\n";
+        let matching = MatchOptions {
+            error_rate: 0.1,
+            indels: false,
+            min_overlap: 4,
+        };
+        let adapter = Adapter::parse("adapter", "ACGTACGTAC").unwrap();
+        let trimmer = Trimmer::with_adapters(
+            vec![adapter.clone()],
+            KeepSide::AfterAdapter,
+            matching,
+            true,
+        );
+        let output = trim_all(FastqReader::new(fastq), &trimmer);
+        // the partial adapter of seq.1 has nothing following it to keep
+        assert_eq!(output, "@seq.0 adapter_errors=1\nTT\n+\n34\n");
+        assert_eq!(trimmer.num_trimmed(), 1);
+        assert_eq!(trimmer.error_counts(), vec![0, 1]);
+
+        // as a 3' adapter the sequence preceding full and partial matches is kept
+        let trimmer =
+            Trimmer::with_adapters(vec![adapter], KeepSide::BeforeAdapter, matching, true);
+        let output = trim_all(FastqReader::new(fastq), &trimmer);
+        assert_eq!(
+            output,
+            "@seq.0 adapter_errors=1\nAA\n+\n12\n@seq.1 adapter_errors=0\nTTTTTTTT\n+\n12345678\n"
+        );
+        assert_eq!(trimmer.num_trimmed(), 2);
+        assert_eq!(trimmer.error_counts(), vec![1, 1]);
+    }
+
+    #[test]
+    fn trim_pairs() {
+        let fasta_r1: &'static [u8] = b">seq.0\nAAGGTTCC\n";
+        let fasta_r2: &'static [u8] = b">seq.0\nACACACAC\n";
+        let pair = || {
+            let r1 = FastaReader::new(fasta_r1).next().unwrap();
+            let r2 = FastaReader::new(fasta_r2).next().unwrap();
+            (r1, r2)
+        };
+        let trimmer = Trimmer::new("GGT".to_string(), false);
+        let Trimmed::Trimmed((r1, r2)) = trimmer.trim_pair(pair(), PairFilter::Either).unwrap()
+        else {
+            panic!("pair should be trimmed");
+        };
+        assert_eq!(r1.seq(), b"GGTTCC");
+        assert_eq!(r2.seq(), b"ACACACAC");
+
+        // pairs failing the filter are returned unchanged
+        let Trimmed::Untrimmed((r1, _)) = trimmer.trim_pair(pair(), PairFilter::Both).unwrap()
+        else {
+            panic!("pair should be untrimmed");
+        };
+        assert_eq!(r1.seq(), b"AAGGTTCC");
+        assert_eq!(trimmer.num_records(), 2);
+        assert_eq!(trimmer.num_trimmed(), 1);
+
+        // pairs with the adapter in both mates are counted once
+        let both = || {
+            let r1 = FastaReader::new(fasta_r1).next().unwrap();
+            let r2 = FastaReader::new(b">seq.0\nCCGGTAAA\n" as &[u8])
+                .next()
+                .unwrap();
+            (r1, r2)
+        };
+        let Trimmed::Trimmed((r1, r2)) = trimmer.trim_pair(both(), PairFilter::Both).unwrap()
+        else {
+            panic!("pair should be trimmed");
+        };
+        assert_eq!(r1.seq(), b"GGTTCC");
+        assert_eq!(r2.seq(), b"GGTAAA");
+        assert_eq!(trimmer.num_records(), 3);
+        assert_eq!(trimmer.num_trimmed(), 2);
+    }
+
+    #[test]
+    fn untrimmed_records() {
+        let fasta: &'static [u8] = b">seq.0\nAAGGTTCC\n>seq.1\nACACACAC\n";
+        let trimmer = Trimmer::new("GGT".to_string(), false);
+        let outcomes = FastaReader::new(fasta)
+            .map(|record| trimmer.trim_record(record).unwrap())
+            .collect::<Vec<_>>();
+        assert!(matches!(&outcomes[0], Trimmed::Trimmed(r) if r.seq() == b"GGTTCC"));
+        assert!(matches!(&outcomes[1], Trimmed::Untrimmed(r) if r.seq() == b"ACACACAC"));
+    }
+
+    #[test]
+    fn adapter_positions() {
+        let fasta: &'static [u8] = b">seq.0\nAAGGTTCC\n>seq.1\nTTTAGGTT\n>seq.2\nAGGTCCCC\n";
+        let adapters = vec![
+            Adapter::parse("ggt", "GGT").unwrap(),
+            Adapter::parse("ccc", "CCCC$").unwrap(),
+        ];
+        let trimmer = Trimmer::with_adapters(
+            adapters,
+            KeepSide::WithAdapter,
+            MatchOptions::default(),
+            false,
+        );
+        trim_all(FastaReader::new(fasta), &trimmer);
+        let stats = trimmer.adapter_stats();
+        assert_eq!(stats[0].name, "ggt");
+        assert_eq!(stats[0].num_matches, 2);
+        assert_eq!(stats[0].positions, vec![0, 0, 1, 0, 1]);
+        assert_eq!(stats[1].num_matches, 1);
+        assert_eq!(stats[1].positions, vec![0, 0, 0, 0, 1]);
+        assert_eq!(stats[0].histogram(), "2:1,4:1");
+        let unmatched = AdapterStats {
+            name: "none".to_string(),
+            num_matches: 0,
+            positions: Vec::new(),
+        };
+        assert_eq!(unmatched.histogram(), "-");
+    }
+
+    #[test]
+    fn trim_best_adapter() {
+        let fasta: &'static [u8] = b">seq.0\nTTACGTACGTTT\n>seq.1\nTTGGGGTTTT\n";
+        let adapters = vec![
+            Adapter::parse("short", "ACG").unwrap(),
+            Adapter::parse("long", "ACGTACGT").unwrap(),
+            Adapter::parse("other", "GGGG").unwrap(),
+        ];
+        let trimmer = Trimmer::with_adapters(
+            adapters,
+            KeepSide::AfterAdapter,
+            MatchOptions::default(),
+            false,
+        );
+        let output = trim_all(FastaReader::new(fasta), &trimmer);
+        assert_eq!(output, ">seq.0\nTT\n>seq.1\nTTTT\n");
+    }
+
+    #[test]
+    fn trim_linked() {
+        let fastq: &'static [u8] = b"@seq.0\nAAAAGTGTCCCCTT\n+\n12345678901234\n";
+        let adapters = vec![Adapter::parse("linked", "^AAAA...CCCC").unwrap()];
+        let trimmer = Trimmer::with_adapters(
+            adapters,
+            KeepSide::WithAdapter,
+            MatchOptions::default(),
+            false,
+        );
+        let output = trim_all(FastqReader::new(fastq), &trimmer);
+        assert_eq!(output, "@seq.0\nGTGT\n+\n5678\n");
+    }
 }
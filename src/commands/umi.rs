@@ -0,0 +1,529 @@
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use fxread::Record;
+use regex::bytes::Regex;
+use serde_json::json;
+use spinoff::{spinners::Dots12, Color, Spinner, Streams};
+use std::{collections::HashMap, io::Write};
+
+use super::{
+    adapter::hamming,
+    clip::parse_range,
+    filter::capture_regex,
+    match_output_stream, match_paired_writer,
+    unique::{DedupItem, Output},
+    OutputOptions, OutputStream, PairedOptions, PairedWriter, RecordPair,
+};
+
+/// How UMIs within the Hamming distance of each other are merged into clusters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum UmiMethod {
+    /// Merge a UMI into a neighbour with at least twice (minus one) its count, following
+    /// chains of such neighbours
+    #[default]
+    Directional,
+    /// Within each connected network, merge UMIs into the fewest most abundant UMIs whose
+    /// neighbours cover the network
+    Adjacency,
+}
+
+/// Where the UMI of each record is found
+#[derive(Debug, Clone)]
+pub enum UmiSource {
+    /// The first capture group (or the whole match) of a regex on the header
+    Header(Regex),
+    /// The sequence between two positions, truncated to the sequence length and removed
+    /// from the insert
+    Range {
+        start: Option<usize>,
+        end: Option<usize>,
+    },
+}
+impl UmiSource {
+    /// Creates the source from either a header regex or a `[start]..[end]` range
+    pub fn new(header: Option<String>, range: Option<String>) -> Result<Option<Self>> {
+        match (header, range) {
+            (Some(pattern), None) => Ok(Some(Self::Header(Regex::new(&pattern)?))),
+            (None, Some(range)) => {
+                let (start, end) = parse_range(range.clone())
+                    .with_context(|| format!("Invalid UMI range: {range}"))?;
+                Ok(Some(Self::Range { start, end }))
+            }
+            (None, None) => Ok(None),
+            (Some(_), Some(_)) => bail!("UMIs can only be taken from the header or sequence"),
+        }
+    }
+
+    /// Returns the UMI of the record or `None` if the header does not match
+    pub fn umi<'a>(&self, record: &'a Record) -> Option<&'a [u8]> {
+        match self {
+            Self::Header(regex) => capture_regex(record, regex, true),
+            Self::Range { .. } => {
+                let (start, end) = self.bounds(record.seq().len());
+                Some(&record.seq()[start..end])
+            }
+        }
+    }
+
+    /// Returns the sequence of the record with the UMI range removed
+    fn insert(&self, record: &Record) -> Vec<u8> {
+        let seq = record.seq();
+        let (start, end) = self.bounds(seq.len());
+        [&seq[..start], &seq[end..]].concat()
+    }
+
+    /// Returns the positions of the UMI range in a sequence of length `len` (empty for
+    /// header UMIs)
+    fn bounds(&self, len: usize) -> (usize, usize) {
+        match self {
+            Self::Header(_) => (0, 0),
+            Self::Range { start, end } => {
+                let end = end.map_or(len, |end| end.min(len));
+                (start.unwrap_or(0).min(end), end)
+            }
+        }
+    }
+}
+
+/// Options of the UMI-aware deduplication
+#[derive(Debug, Clone)]
+pub struct UmiOptions {
+    /// Where the UMI of each record is found
+    pub source: UmiSource,
+    /// Maximum Hamming distance between merged UMIs
+    pub distance: usize,
+    /// How UMIs are merged
+    pub method: UmiMethod,
+    /// Filepath to write the cluster of each record to
+    pub clusters: Option<String>,
+}
+
+/// Records or pairs which carry a UMI
+pub trait UmiItem: DedupItem {
+    /// Returns the record holding the UMI
+    fn umi_record(&self) -> &Record;
+
+    /// Returns the sequence identifying duplicates, excluding the UMI if it is part of the
+    /// sequence
+    fn insert_key(&self, source: &UmiSource) -> Vec<u8>;
+}
+impl UmiItem for Record {
+    fn umi_record(&self) -> &Record {
+        self
+    }
+
+    fn insert_key(&self, source: &UmiSource) -> Vec<u8> {
+        source.insert(self)
+    }
+}
+impl UmiItem for RecordPair {
+    /// The UMI is taken from the first mate
+    fn umi_record(&self) -> &Record {
+        &self.0
+    }
+
+    fn insert_key(&self, source: &UmiSource) -> Vec<u8> {
+        let mut key = source.insert(&self.0);
+        key.push(b'\n');
+        key.extend_from_slice(self.1.seq());
+        key
+    }
+}
+
+/// Assigns each UMI to a cluster
+///
+/// UMIs must be sorted by decreasing count. Clusters are numbered in order of their most
+/// abundant UMI, which is the first UMI of each cluster.
+pub fn cluster_umis(umis: &[(Vec<u8>, usize)], distance: usize, method: UmiMethod) -> Vec<usize> {
+    let adjacent = |i: usize, j: usize| {
+        umis[i].0.len() == umis[j].0.len() && hamming(&umis[i].0, &umis[j].0, distance) <= distance
+    };
+    let neighbours = (0..umis.len())
+        .map(|i| {
+            (0..umis.len())
+                .filter(|&j| j != i && adjacent(i, j))
+                .collect()
+        })
+        .collect::<Vec<Vec<usize>>>();
+    match method {
+        UmiMethod::Directional => cluster_directional(umis, &neighbours),
+        UmiMethod::Adjacency => cluster_adjacency(&neighbours),
+    }
+}
+
+/// Clusters UMIs by following edges from each UMI to its neighbours with
+/// `count <= (count_parent + 1) / 2`
+fn cluster_directional(umis: &[(Vec<u8>, usize)], neighbours: &[Vec<usize>]) -> Vec<usize> {
+    let mut clusters = vec![usize::MAX; umis.len()];
+    let mut num_clusters = 0;
+    for root in 0..umis.len() {
+        if clusters[root] != usize::MAX {
+            continue;
+        }
+        clusters[root] = num_clusters;
+        let mut stack = vec![root];
+        while let Some(node) = stack.pop() {
+            for &next in &neighbours[node] {
+                if clusters[next] == usize::MAX && umis[node].1 + 1 >= 2 * umis[next].1 {
+                    clusters[next] = num_clusters;
+                    stack.push(next);
+                }
+            }
+        }
+        num_clusters += 1;
+    }
+    clusters
+}
+
+/// Clusters UMIs by selecting the fewest most abundant UMIs of each connected network
+/// whose neighbours cover the network and assigning the remaining UMIs to the first
+/// selected neighbour
+fn cluster_adjacency(neighbours: &[Vec<usize>]) -> Vec<usize> {
+    let mut component = vec![usize::MAX; neighbours.len()];
+    let mut clusters = vec![usize::MAX; neighbours.len()];
+    let mut num_clusters = 0;
+    for root in 0..neighbours.len() {
+        if component[root] != usize::MAX {
+            continue;
+        }
+
+        // connected network of the root (sorted by decreasing count)
+        component[root] = root;
+        let mut nodes = vec![root];
+        let mut stack = vec![root];
+        while let Some(node) = stack.pop() {
+            for &next in &neighbours[node] {
+                if component[next] == usize::MAX {
+                    component[next] = root;
+                    nodes.push(next);
+                    stack.push(next);
+                }
+            }
+        }
+        nodes.sort_unstable();
+
+        // select leading UMIs until their neighbourhoods cover the network
+        let mut covered = 0;
+        let mut leads = Vec::new();
+        for &node in &nodes {
+            if covered == nodes.len() {
+                break;
+            }
+            leads.push(node);
+            covered = nodes
+                .iter()
+                .filter(|&&x| leads.iter().any(|&l| l == x || neighbours[l].contains(&x)))
+                .count();
+        }
+        for &lead in &leads {
+            clusters[lead] = num_clusters;
+            num_clusters += 1;
+        }
+        for &node in &nodes {
+            if clusters[node] == usize::MAX {
+                let lead = leads
+                    .iter()
+                    .find(|&&l| neighbours[l].contains(&node))
+                    .expect("network is covered by the leads");
+                clusters[node] = clusters[*lead];
+            }
+        }
+    }
+    clusters
+}
+
+/// Records sharing an insert grouped by their UMI in order of first occurrence
+struct Insert<T> {
+    index: HashMap<Vec<u8>, usize>,
+    umis: Vec<(Vec<u8>, Vec<T>)>,
+}
+
+/// Deduplicates the items by their UMI and insert and passes the representative of each
+/// cluster to `write` along with every member of the cluster and its UMI
+///
+/// The representative is the first record of the most abundant UMI of a cluster.
+pub fn dedup_umis<T, I, F>(items: I, umi: &UmiOptions, mut write: F) -> Result<()>
+where
+    T: UmiItem,
+    I: Iterator<Item = Result<T>>,
+    F: FnMut(&T, &[(&[u8], &T)]) -> Result<()>,
+{
+    let mut index = HashMap::new();
+    let mut inserts: Vec<Insert<T>> = Vec::new();
+    for x in items {
+        let x = x?;
+        let Some(key) = umi.source.umi(x.umi_record()) else {
+            bail!("No UMI found in record: {}", x.umi_record().id_str());
+        };
+        let key = key.to_vec();
+        let insert = *index.entry(x.insert_key(&umi.source)).or_insert_with(|| {
+            inserts.push(Insert {
+                index: HashMap::new(),
+                umis: Vec::new(),
+            });
+            inserts.len() - 1
+        });
+        let insert = &mut inserts[insert];
+        match insert.index.get(&key) {
+            Some(&idx) => insert.umis[idx].1.push(x),
+            None => {
+                insert.index.insert(key.clone(), insert.umis.len());
+                insert.umis.push((key, vec![x]));
+            }
+        }
+    }
+
+    for mut insert in inserts {
+        // stable sort keeps UMIs of equal count in order of first occurrence
+        insert
+            .umis
+            .sort_by_key(|(_, xs)| std::cmp::Reverse(xs.len()));
+        let counts = insert
+            .umis
+            .iter()
+            .map(|(key, xs)| (key.clone(), xs.len()))
+            .collect::<Vec<_>>();
+        let clusters = cluster_umis(&counts, umi.distance, umi.method);
+        let num_clusters = clusters.iter().max().map_or(0, |x| x + 1);
+        for cluster in 0..num_clusters {
+            let members = insert
+                .umis
+                .iter()
+                .zip(&clusters)
+                .filter(|(_, c)| **c == cluster)
+                .flat_map(|((key, xs), _)| xs.iter().map(move |x| (key.as_slice(), x)))
+                .collect::<Vec<_>>();
+            write(members[0].1, &members)?;
+        }
+    }
+    Ok(())
+}
+
+/// Runs the UMI-aware deduplication of the `unique` command
+fn run_umi<T, O>(
+    input: Option<String>,
+    paired: &PairedOptions,
+    mut output: O,
+    allow_invalid: bool,
+    options: OutputOptions,
+    umi: &UmiOptions,
+) -> Result<()>
+where
+    T: UmiItem,
+    O: Output<T>,
+{
+    let mut spinner =
+        Spinner::new_with_stream(Dots12, "Clustering UMIs", Color::Green, Streams::Stderr);
+    let mut table: Option<OutputStream> = match &umi.clusters {
+        Some(path) => {
+            let mut writer = match_output_stream(Some(path.clone()), OutputOptions::default())?;
+            writeln!(writer, "id\tumi\tcluster\trepresentative")?;
+            Some(writer)
+        }
+        None => None,
+    };
+    let mut num_records = 0;
+    let mut num_clusters = 0;
+    dedup_umis(
+        T::open(input, paired, &options.stats, true)?,
+        umi,
+        |representative, members| {
+            if !allow_invalid {
+                representative.validate()?;
+            }
+            output.write_item(representative, &options)?;
+            if let Some(table) = table.as_mut() {
+                for (key, x) in members {
+                    table.write_all(x.umi_record().id())?;
+                    table.write_all(b"\t")?;
+                    table.write_all(key)?;
+                    write!(table, "\t{num_clusters}\t")?;
+                    table.write_all(representative.umi_record().id())?;
+                    table.write_all(b"\n")?;
+                }
+            }
+            num_records += members.len();
+            num_clusters += 1;
+            Ok(())
+        },
+    )?;
+    output.close()?;
+    if let Some(table) = table {
+        table.finish()?;
+    }
+
+    spinner.stop_and_persist(
+        "✔",
+        &format!("Deduplicated {num_records} records into {num_clusters} UMI clusters"),
+    );
+    options.stats.section(
+        "unique",
+        json!({
+            "num_records": num_records,
+            "num_clusters": num_clusters,
+        }),
+    )
+}
+
+/// Runs the `unique` command deduplicating on UMIs and inserts
+///
+/// Paired records carry the UMI in the first mate and are duplicates if both inserts match.
+/// Every record is held in memory and the UMIs of each insert are compared pairwise, so
+/// the memory usage is not bounded.
+pub fn run(
+    input: Option<String>,
+    output: Option<String>,
+    paired: PairedOptions,
+    options: OutputOptions,
+    allow_invalid: bool,
+    umi: UmiOptions,
+) -> Result<()> {
+    if paired.is_paired() {
+        let writer: PairedWriter<OutputStream> =
+            match_paired_writer(output, &paired, options.clone())?;
+        run_umi::<RecordPair, _>(input, &paired, writer, allow_invalid, options, &umi)
+    } else {
+        let writer = match_output_stream(output, options.clone())?;
+        run_umi::<Record, _>(input, &paired, writer, allow_invalid, options, &umi)
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use fxread::FastaReader;
+    use regex::bytes::Regex;
+
+    fn umis(counts: &[(&str, usize)]) -> Vec<(Vec<u8>, usize)> {
+        counts
+            .iter()
+            .map(|(umi, count)| (umi.as_bytes().to_vec(), *count))
+            .collect()
+    }
+
+    #[test]
+    fn directional_clusters() {
+        // AAAA absorbs AAAT which absorbs AATT, but AAAC is too abundant (2 * 6 - 1 > 10)
+        let counts = umis(&[
+            ("AAAA", 10),
+            ("AAAC", 6),
+            ("AAAT", 4),
+            ("AATT", 1),
+            ("GGGG", 1),
+        ]);
+        let clusters = cluster_umis(&counts, 1, UmiMethod::Directional);
+        assert_eq!(clusters, vec![0, 1, 0, 0, 2]);
+    }
+
+    #[test]
+    fn adjacency_clusters() {
+        // AAAA covers AAAC and AAAT, and AATT is only covered once AAAT leads as well
+        let counts = umis(&[
+            ("AAAA", 10),
+            ("AAAC", 6),
+            ("AAAT", 4),
+            ("AATT", 1),
+            ("GGGG", 1),
+        ]);
+        let clusters = cluster_umis(&counts, 1, UmiMethod::Adjacency);
+        assert_eq!(clusters, vec![0, 1, 2, 2, 3]);
+
+        // different lengths are never adjacent
+        let counts = umis(&[("AAAA", 2), ("AAA", 1)]);
+        assert_eq!(cluster_umis(&counts, 1, UmiMethod::Adjacency), vec![0, 1]);
+    }
+
+    #[test]
+    fn umi_sources() {
+        let record = FastaReader::new(&b">r0_GGCC\nAAAACGTT\n"[..])
+            .next()
+            .unwrap();
+        let header = UmiSource::new(Some("_([ACGT]+)$".to_string()), None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(header.umi(&record), Some(&b"GGCC"[..]));
+        assert_eq!(header.insert(&record), b"AAAACGTT");
+
+        let range = UmiSource::new(None, Some("2..4".to_string()))
+            .unwrap()
+            .unwrap();
+        assert_eq!(range.umi(&record), Some(&b"AA"[..]));
+        assert_eq!(range.insert(&record), b"AACGTT");
+
+        // ranges past the end of the sequence are truncated
+        let range = UmiSource::new(None, Some("6..20".to_string()))
+            .unwrap()
+            .unwrap();
+        assert_eq!(range.umi(&record), Some(&b"TT"[..]));
+        assert_eq!(range.insert(&record), b"AAAACG");
+
+        assert!(UmiSource::new(None, Some("1-2".to_string())).is_err());
+        assert!(UmiSource::new(None, None).unwrap().is_none());
+    }
+
+    #[test]
+    fn dedup_header_umis() {
+        let fasta: &'static [u8] =
+            b">r0_AAAA\nACGT\n>r1_AAAA\nACGT\n>r2_AAAT\nACGT\n>r3_GGGG\nACGT\n>r4_AAAA\nTTTT\n";
+        let umi = UmiOptions {
+            source: UmiSource::Header(Regex::new("_([ACGT]+)$").unwrap()),
+            distance: 1,
+            method: UmiMethod::Directional,
+            clusters: None,
+        };
+        let mut clusters = Vec::new();
+        dedup_umis(
+            FastaReader::new(fasta).map(Ok),
+            &umi,
+            |x: &Record, members| {
+                let ids = members
+                    .iter()
+                    .map(|(_, m)| m.id_str().to_string())
+                    .collect::<Vec<_>>();
+                clusters.push((x.id_str().to_string(), ids));
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            clusters,
+            vec![
+                (
+                    "r0_AAAA".into(),
+                    vec!["r0_AAAA".into(), "r1_AAAA".into(), "r2_AAAT".into()]
+                ),
+                ("r3_GGGG".into(), vec!["r3_GGGG".into()]),
+                ("r4_AAAA".into(), vec!["r4_AAAA".into()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn dedup_sequence_umis() {
+        // the UMI is removed from the insert
+        let fasta: &'static [u8] = b">r0\nAAAAACGT\n>r1\nCCCCACGT\n>r2\nAAATACGT\n";
+        let umi = UmiOptions {
+            source: UmiSource::Range {
+                start: None,
+                end: Some(4),
+            },
+            distance: 1,
+            method: UmiMethod::Directional,
+            clusters: None,
+        };
+        let mut representatives = Vec::new();
+        dedup_umis(FastaReader::new(fasta).map(Ok), &umi, |x: &Record, _| {
+            representatives.push(x.id_str().to_string());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(representatives, vec!["r0", "r1"]);
+        assert_eq!(
+            FastaReader::new(fasta)
+                .next()
+                .unwrap()
+                .insert_key(&umi.source),
+            b"ACGT"
+        );
+    }
+}
@@ -1,31 +1,309 @@
-use anyhow::Result;
-use fxread::{initialize_reader, initialize_stdin_reader, FastxRead, Record};
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use fxread::Record;
 use spinoff::{spinners::Dots12, Color, Spinner, Streams};
-use std::{collections::HashMap, io::stdin};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fs::File,
+    hash::{DefaultHasher, Hasher},
+    io::{BufWriter, Write},
+};
 
-use super::{match_output_stream, write_output, write_output_with_invalid};
+use super::{
+    clip::parse_range,
+    io::{fastx_reader, match_input_stream, mean_quality},
+    match_fastx_reader, match_output_stream, match_paired_reader, match_paired_writer,
+    spill::{spill_path, Items, Spill},
+    tag_record, validate_record, write_record, OutputOptions, OutputStream, PairedOptions,
+    PairedReader, PairedWriter, RecordPair, TempPath,
+};
+use crate::report::Stats;
+use serde_json::json;
 
-type UniqMap = HashMap<Vec<u8>, Record>;
-type NullMap = HashMap<Vec<u8>, Vec<Record>>;
+type UniqMap<T> = HashMap<Vec<u8>, T>;
+type NullMap<T> = HashMap<Vec<u8>, Vec<T>>;
 
-struct Unique {
-    map: UniqMap,
-    null: NullMap,
+/// What identifies duplicate records
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum DedupKey {
+    /// The full sequence
+    #[default]
+    Sequence,
+    /// The record id
+    Id,
+    /// The sequence between two positions, truncated to the sequence length
+    Range {
+        start: Option<usize>,
+        end: Option<usize>,
+    },
 }
-impl Unique {
+impl DedupKey {
+    /// Creates the key from the record id or a `[start]..[end]` sequence range, and
+    /// defaults to the full sequence
+    pub fn new(by_id: bool, by_range: Option<String>) -> Result<Self> {
+        match (by_id, by_range) {
+            (false, None) => Ok(Self::Sequence),
+            (true, None) => Ok(Self::Id),
+            (false, Some(range)) => {
+                let (start, end) = parse_range(range.clone())
+                    .with_context(|| format!("Invalid sequence range: {range}"))?;
+                Ok(Self::Range { start, end })
+            }
+            (true, Some(_)) => bail!("Records can only be deduplicated by id or range"),
+        }
+    }
+
+    /// Returns the key of the record
+    fn record_key<'a>(&self, record: &'a Record) -> &'a [u8] {
+        match self {
+            Self::Sequence => record.seq(),
+            Self::Id => record.id(),
+            Self::Range { start, end } => {
+                let seq = record.seq();
+                let end = end.map_or(seq.len(), |end| end.min(seq.len()));
+                let start = start.unwrap_or(0).min(end);
+                &seq[start..end]
+            }
+        }
+    }
+}
+
+/// Items which are deduplicated by their sequence
+pub trait SequenceKey {
+    /// Returns the sequence identifying duplicates
+    fn sequence_key(&self) -> Vec<u8>;
+
+    /// Returns the key identifying duplicates
+    fn dedup_key(&self, key: &DedupKey) -> Vec<u8>;
+}
+impl SequenceKey for Record {
+    fn sequence_key(&self) -> Vec<u8> {
+        self.seq().to_owned()
+    }
+
+    fn dedup_key(&self, key: &DedupKey) -> Vec<u8> {
+        key.record_key(self).to_owned()
+    }
+}
+impl SequenceKey for RecordPair {
+    /// Joins the mate sequences with a newline which cannot occur within a sequence
+    fn sequence_key(&self) -> Vec<u8> {
+        self.dedup_key(&DedupKey::Sequence)
+    }
+
+    /// Joins the keys of both mates with a newline, except for ids which are taken from
+    /// the first mate
+    fn dedup_key(&self, key: &DedupKey) -> Vec<u8> {
+        if *key == DedupKey::Id {
+            return self.0.id().to_owned();
+        }
+        let (r1, r2) = (key.record_key(&self.0), key.record_key(&self.1));
+        let mut joined = Vec::with_capacity(r1.len() + r2.len() + 1);
+        joined.extend_from_slice(r1);
+        joined.push(b'\n');
+        joined.extend_from_slice(r2);
+        joined
+    }
+}
+
+/// Items which can be written as collapsed records
+pub trait Collapsible: Sized {
+    /// Returns the identifier of the item
+    fn collapse_id(&self) -> &[u8];
+
+    /// Returns the item with the tag appended to its header
+    fn tagged(&self, tag: &str) -> Result<Self>;
+}
+impl Collapsible for Record {
+    fn collapse_id(&self) -> &[u8] {
+        self.id()
+    }
+
+    fn tagged(&self, tag: &str) -> Result<Self> {
+        tag_record(self, tag)
+    }
+}
+impl Collapsible for RecordPair {
+    fn collapse_id(&self) -> &[u8] {
+        self.0.id()
+    }
+
+    /// Tags both mates
+    fn tagged(&self, tag: &str) -> Result<Self> {
+        Ok((tag_record(&self.0, tag)?, tag_record(&self.1, tag)?))
+    }
+}
+
+/// Distinct sequences with the first record (or pair) carrying them and their copy number
+pub struct Collapsed<T = Record> {
+    groups: Vec<(T, usize)>,
+}
+impl<T: SequenceKey> Collapsed<T> {
+    /// Collapses the records in order of first occurrence
+    pub fn from_reader<I: Iterator<Item = T>>(reader: I) -> Self {
+        Self::from_reader_by(reader, &DedupKey::Sequence)
+    }
+
+    /// Collapses the records with equal keys in order of first occurrence
+    pub fn from_reader_by<I: Iterator<Item = T>>(reader: I, key: &DedupKey) -> Self {
+        let mut index: UniqMap<usize> = UniqMap::new();
+        let mut groups: Vec<(T, usize)> = Vec::new();
+        for x in reader {
+            match index.entry(x.dedup_key(key)) {
+                Entry::Occupied(entry) => groups[*entry.get()].1 += 1,
+                Entry::Vacant(entry) => {
+                    entry.insert(groups.len());
+                    groups.push((x, 1));
+                }
+            }
+        }
+        Self { groups }
+    }
+
+    /// Sorts the sequences by decreasing copy number, keeping ties in order of first
+    /// occurrence
+    pub fn sort_by_abundance(&mut self) {
+        self.groups
+            .sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+    }
+
+    /// Returns the first record of each distinct sequence with its copy number
+    pub fn groups(&self) -> impl Iterator<Item = (&T, usize)> {
+        self.groups.iter().map(|(x, count)| (x, *count))
+    }
+
+    /// Return number of distinct sequences
+    pub fn num_sequences(&self) -> usize {
+        self.groups.len()
+    }
+
+    /// Return number of collapsed records
+    pub fn num_records(&self) -> usize {
+        self.groups.iter().map(|(_, count)| count).sum()
+    }
+}
+impl<T: SequenceKey + Collapsible> Collapsed<T> {
+    /// Writes a table of the sequence, copy number, and first identifier of each distinct
+    /// sequence
+    ///
+    /// Mate sequences of pairs are joined by a `+`.
+    pub fn write_counts<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writeln!(writer, "{COUNTS_HEADER}")?;
+        for (x, count) in self.groups() {
+            write_count_row(writer, x, count)?;
+        }
+        Ok(())
+    }
+}
+
+/// Header of the copy number table
+const COUNTS_HEADER: &str = "sequence\tcount\tid";
+
+/// Writes the sequence, copy number, and identifier of an item to the copy number table
+fn write_count_row<W, T>(writer: &mut W, x: &T, count: usize) -> Result<()>
+where
+    W: Write + ?Sized,
+    T: SequenceKey + Collapsible,
+{
+    let mut key = x.sequence_key();
+    key.iter_mut()
+        .filter(|b| **b == b'\n')
+        .for_each(|b| *b = b'+');
+    writer.write_all(&key)?;
+    write!(writer, "\t{count}\t")?;
+    writer.write_all(x.collapse_id())?;
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+/// Which record of a group of duplicates is written to the output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Keep {
+    /// The first record
+    First,
+    /// The last record
+    Last,
+    /// The record with the highest mean base quality (the first one on ties)
+    BestQuality,
+}
+impl Keep {
+    /// Returns the index of the kept item of a group of duplicates
+    pub fn select<T: DedupItem>(&self, group: &[T]) -> usize {
+        match self {
+            Self::First => 0,
+            Self::Last => group.len() - 1,
+            Self::BestQuality => {
+                group
+                    .iter()
+                    .enumerate()
+                    .fold((0, f64::MIN), |best, (idx, x)| {
+                        let quality = x.mean_quality();
+                        if quality > best.1 {
+                            (idx, quality)
+                        } else {
+                            best
+                        }
+                    })
+                    .0
+            }
+        }
+    }
+}
+
+/// How duplicates are determined and written
+#[derive(Debug, Clone, Default)]
+pub struct UniqueOptions {
+    /// What identifies duplicate records
+    pub key: DedupKey,
+    /// Write one record of each group of duplicates to the output instead of only
+    /// writing records without duplicates
+    pub keep: Option<Keep>,
+    /// Write one record per distinct sequence instead of partitioning them
+    pub collapse: bool,
+    /// Write the copy numbers to a table instead of the record headers
+    pub counts: Option<String>,
+    /// Sort the collapsed records by decreasing copy number
+    pub sort_by_abundance: bool,
+}
+
+/// Where duplicates are determined
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DedupMode {
+    /// All records are held in memory keyed by their sequence
+    #[default]
+    InMemory,
+    /// Only 128-bit fingerprints of the sequences are held in memory and the input is read
+    /// a second time to write the records
+    Fingerprint,
+    /// Records are spilled to hash partitions on disk once they exceed the memory limit
+    /// (in bytes) and each partition is deduplicated in memory
+    Partitioned { max_memory: usize },
+}
+
+/// Partitions records (or pairs) into those with unique and those with duplicated sequences
+pub struct Unique<T = Record> {
+    map: UniqMap<T>,
+    null: NullMap<T>,
+}
+impl<T: SequenceKey> Unique<T> {
     /// Initializes the Unique Set
-    pub fn from_reader(reader: Box<dyn FastxRead<Item = Record>>) -> Self {
-        let (map, null) = Self::build(reader);
+    pub fn from_reader<I: Iterator<Item = T>>(reader: I) -> Self {
+        Self::from_reader_by(reader, &DedupKey::Sequence)
+    }
+
+    /// Initializes the Unique Set keyed by the provided key
+    pub fn from_reader_by<I: Iterator<Item = T>>(reader: I, key: &DedupKey) -> Self {
+        let (map, null) = Self::build(reader, key);
         Self { map, null }
     }
 
     /// Return all records with unique sequences
-    pub fn passing_records(&self) -> impl Iterator<Item = &Record> {
+    pub fn passing_records(&self) -> impl Iterator<Item = &T> {
         self.map.values()
     }
 
     /// Return all records with non-unique sequences
-    pub fn null_records(&self) -> impl Iterator<Item = &Record> {
+    pub fn null_records(&self) -> impl Iterator<Item = &T> {
         self.null.values().flatten()
     }
 
@@ -45,138 +323,560 @@ impl Unique {
     }
 
     /// Reads in the records and performs the unique matching
-    fn build(reader: Box<dyn FastxRead<Item = Record>>) -> (UniqMap, NullMap) {
-        reader.fold(
-            (HashMap::new(), HashMap::new()),
-            |(mut map, mut null), x| {
-                // nullify record with duplicate sequence
-                if Self::in_null(&mut null, &x) {
-                    Self::insert_to_null(&mut null, x);
-                }
-                // continues if not already nulled
-                else {
-                    // nullify if in map already
-                    if Self::in_map(&mut map, &x) {
-                        Self::nullify_existing(&mut null, &mut map, x);
-                    }
-                    // insert to map
-                    else {
-                        Self::insert_to_map(&mut map, x);
-                    }
-                }
-                (map, null)
-            },
-        )
+    fn build<I: Iterator<Item = T>>(reader: I, key: &DedupKey) -> (UniqMap<T>, NullMap<T>) {
+        let mut map = UniqMap::new();
+        let mut null = NullMap::new();
+        for x in reader {
+            let key = x.dedup_key(key);
+
+            // nullify record with duplicate sequence
+            if let Some(nulled) = null.get_mut(&key) {
+                nulled.push(x);
+            }
+            // nullify if in map already
+            else if let Some(duplicate) = map.remove(&key) {
+                null.insert(key, vec![duplicate, x]);
+            }
+            // insert to map
+            else {
+                map.insert(key, x);
+            }
+        }
+        (map, null)
     }
+}
+
+/// Returns a 128-bit fingerprint of the sequence key
+///
+/// Distinct sequences share a fingerprint with negligible probability.
+pub fn fingerprint(key: &[u8]) -> u128 {
+    let hash = |salt: u64| {
+        let mut hasher = DefaultHasher::new();
+        hasher.write_u64(salt);
+        hasher.write(key);
+        hasher.finish()
+    };
+    (u128::from(hash(0)) << 64) | u128::from(hash(1))
+}
 
-    /// checks whether the record's sequence exists in the current
-    /// positive set
-    fn in_map(map: &mut UniqMap, record: &Record) -> bool {
-        map.contains_key(record.seq())
+/// Records or pairs which can be deduplicated out of memory
+pub trait DedupItem: SequenceKey + Collapsible + Spill + 'static {
+    /// Opens the input, counting its records in `stats` if `counted` is set
+    fn open(
+        input: Option<String>,
+        paired: &PairedOptions,
+        stats: &Stats,
+        counted: bool,
+    ) -> Result<Items<Self>>;
+
+    /// Checks the item for invalid nucleotides
+    fn validate(&self) -> Result<()>;
+
+    /// Mean Phred quality of the item (zero without qualities)
+    fn mean_quality(&self) -> f64;
+}
+impl DedupItem for Record {
+    fn open(
+        input: Option<String>,
+        _paired: &PairedOptions,
+        stats: &Stats,
+        counted: bool,
+    ) -> Result<Items<Self>> {
+        let reader = if counted {
+            match_fastx_reader(input, stats)?
+        } else {
+            fastx_reader(match_input_stream(input, stats)?)?
+        };
+        Ok(Box::new(reader.map(Ok)))
     }
 
-    /// Checks whether the records sequence exists in the current
-    /// null set
-    fn in_null(null: &mut NullMap, record: &Record) -> bool {
-        null.contains_key(record.seq())
+    fn validate(&self) -> Result<()> {
+        validate_record(self)
     }
 
-    /// Inserts a null sequence to the set and removes it from the map
-    fn nullify_existing(null: &mut NullMap, map: &mut UniqMap, record: Record) {
-        let duplicate = map.remove(record.seq()).expect("unexpected empty value");
-        Self::insert_to_null(null, duplicate);
-        Self::insert_to_null(null, record);
+    fn mean_quality(&self) -> f64 {
+        mean_quality(self)
+    }
+}
+impl DedupItem for RecordPair {
+    fn open(
+        input: Option<String>,
+        paired: &PairedOptions,
+        stats: &Stats,
+        counted: bool,
+    ) -> Result<Items<Self>> {
+        if counted {
+            return Ok(Box::new(match_paired_reader(input, paired, stats)?));
+        }
+        let reader_r1 = fastx_reader(match_input_stream(input, stats)?)?;
+        let reader = match &paired.input_r2 {
+            Some(input_r2) => PairedReader::new(
+                reader_r1,
+                fastx_reader(match_input_stream(Some(input_r2.clone()), stats)?)?,
+            ),
+            None => PairedReader::interleaved(reader_r1),
+        };
+        Ok(Box::new(reader))
     }
 
-    /// Inserts a sequence to null
-    fn insert_to_null(null: &mut NullMap, record: Record) {
-        null.entry(record.seq().to_owned())
-            .or_default()
-            .push(record);
+    fn validate(&self) -> Result<()> {
+        validate_record(&self.0)?;
+        validate_record(&self.1)
     }
 
-    /// Inserts a sequence to the map
-    fn insert_to_map(map: &mut UniqMap, record: Record) {
-        map.insert(record.seq().to_owned(), record);
+    /// Mean quality across both mates
+    fn mean_quality(&self) -> f64 {
+        let (n1, n2) = (self.0.seq().len() as f64, self.1.seq().len() as f64);
+        if n1 + n2 == 0.0 {
+            return 0.0;
+        }
+        (self.0.mean_quality() * n1 + self.1.mean_quality() * n2) / (n1 + n2)
     }
 }
 
-/// Format Prints the record for standard fasta/fastq output
-fn format_print(record: &Record) -> &str {
-    record.as_str()
+/// Number of partitions items are spilled to once they exceed the memory limit
+const NUM_PARTITIONS: usize = 64;
+
+/// Partitioning depth after which partitions are deduplicated in memory regardless of
+/// their size (e.g. a single highly duplicated sequence)
+const MAX_DEPTH: usize = 3;
+
+/// Temporary file holding a hash partition of spilled items
+struct SpillFile {
+    guard: TempPath,
+    writer: BufWriter<File>,
+    num_items: usize,
 }
+impl SpillFile {
+    /// Creates an empty spill file in the temporary directory
+    fn create() -> Result<Self> {
+        let (guard, file) = TempPath::create(&spill_path("unique"))?;
+        Ok(Self {
+            guard,
+            writer: BufWriter::new(file),
+            num_items: 0,
+        })
+    }
 
-pub fn run(
-    path: Option<String>,
-    output: Option<String>,
-    null: Option<String>,
-    compression_threads: Option<usize>,
-    compression_level: Option<usize>,
+    /// Reads back the items written to the file
+    fn items<T: DedupItem>(&mut self) -> Result<Items<T>> {
+        self.writer.flush()?;
+        T::unspill(File::open(self.guard.temp_path())?)
+    }
+}
+
+/// Spills the items to hash partitions so that equal sequences share a partition
+fn spill<T: DedupItem>(
+    items: impl Iterator<Item = Result<T>>,
+    key: &DedupKey,
+    depth: usize,
+) -> Result<Vec<SpillFile>> {
+    let mut partitions = (0..NUM_PARTITIONS)
+        .map(|_| SpillFile::create())
+        .collect::<Result<Vec<_>>>()?;
+    for item in items {
+        let item = item?;
+        let mut hasher = DefaultHasher::new();
+        hasher.write_usize(depth);
+        hasher.write(&item.dedup_key(key));
+        let partition = &mut partitions[hasher.finish() as usize % NUM_PARTITIONS];
+        item.spill(&mut partition.writer)?;
+        partition.num_items += 1;
+    }
+    partitions.retain(|partition| partition.num_items > 0);
+    Ok(partitions)
+}
+
+/// Passes groups of items which fit in memory to `f`, where all items with the same
+/// key share a group
+///
+/// Items are buffered until they exceed `max_memory` bytes, after which they are spilled
+/// to hash partitions on disk which are each grouped recursively.
+fn partition<T, F>(
+    mut items: Items<T>,
+    key: &DedupKey,
+    max_memory: usize,
+    depth: usize,
+    f: &mut F,
+) -> Result<()>
+where
+    T: DedupItem,
+    F: FnMut(Vec<T>) -> Result<()>,
+{
+    let mut buffer = Vec::new();
+    let mut size = 0;
+    while let Some(item) = items.next() {
+        let item = item?;
+        size += item.size();
+        buffer.push(item);
+        if size > max_memory && depth < MAX_DEPTH {
+            let mut partitions = spill(buffer.into_iter().map(Ok).chain(items), key, depth)?;
+            for spilled in partitions.iter_mut() {
+                partition(spilled.items()?, key, max_memory, depth + 1, f)?;
+            }
+            return Ok(());
+        }
+    }
+    f(buffer)
+}
+
+/// Writer of deduplicated records or pairs
+pub trait Output<T> {
+    /// Writes a record or pair
+    fn write_item(&mut self, item: &T, options: &OutputOptions) -> Result<()>;
+
+    /// Completes the output
+    fn close(self) -> Result<()>;
+}
+impl Output<Record> for OutputStream {
+    fn write_item(&mut self, item: &Record, options: &OutputOptions) -> Result<()> {
+        write_record(self, item, options)
+    }
+
+    fn close(self) -> Result<()> {
+        self.finish()
+    }
+}
+impl Output<RecordPair> for PairedWriter<OutputStream> {
+    fn write_item(&mut self, item: &RecordPair, options: &OutputOptions) -> Result<()> {
+        self.write_pair(item, options)
+    }
+
+    fn close(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+/// Writes the deduplicated records and tallies them for the run report
+struct Dedup<O> {
+    output: O,
+    null: Option<O>,
+    counts: Option<OutputStream>,
+    unique: UniqueOptions,
     allow_invalid: bool,
-) -> Result<()> {
-    let reader = if let Some(path) = path {
-        initialize_reader(&path)
-    } else {
-        initialize_stdin_reader(stdin().lock())
-    }?;
+    options: OutputOptions,
+    num_records: usize,
+    num_unique: usize,
+    num_sequences: usize,
+    num_duplicate_sequences: usize,
+    num_duplicate_records: usize,
+}
+impl<O> Dedup<O> {
+    fn new(
+        output: O,
+        null: Option<O>,
+        unique: UniqueOptions,
+        allow_invalid: bool,
+        options: OutputOptions,
+    ) -> Result<Self> {
+        let counts = match &unique.counts {
+            Some(path) => {
+                let mut writer = match_output_stream(Some(path.clone()), OutputOptions::default())?;
+                writeln!(writer, "{COUNTS_HEADER}")?;
+                Some(writer)
+            }
+            None => None,
+        };
+        Ok(Self {
+            output,
+            null,
+            counts,
+            unique,
+            allow_invalid,
+            options,
+            num_records: 0,
+            num_unique: 0,
+            num_sequences: 0,
+            num_duplicate_sequences: 0,
+            num_duplicate_records: 0,
+        })
+    }
+
+    /// Writes an item whose sequence occurs once
+    fn write_unique<T: DedupItem>(&mut self, x: &T) -> Result<()>
+    where
+        O: Output<T>,
+    {
+        self.num_records += 1;
+        self.num_unique += 1;
+        self.num_sequences += 1;
+        if !self.allow_invalid {
+            x.validate()?;
+        }
+        self.output.write_item(x, &self.options)
+    }
+
+    /// Writes an item whose key occurs multiple times to the output if it is kept and
+    /// to the null output otherwise
+    fn write_duplicate<T: DedupItem>(&mut self, x: &T, first: bool, kept: bool) -> Result<()>
+    where
+        O: Output<T>,
+    {
+        self.num_records += 1;
+        self.num_duplicate_records += 1;
+        if first {
+            self.num_sequences += 1;
+            self.num_duplicate_sequences += 1;
+        }
+        let writer = if kept {
+            Some(&mut self.output)
+        } else {
+            self.null.as_mut()
+        };
+        if let Some(writer) = writer {
+            if !self.allow_invalid {
+                x.validate()?;
+            }
+            writer.write_item(x, &self.options)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the first item of a sequence occurring `count` times as a collapsed record
+    fn write_group<T: DedupItem>(&mut self, x: &T, count: usize) -> Result<()>
+    where
+        O: Output<T>,
+    {
+        self.num_records += count;
+        self.num_sequences += 1;
+        if count == 1 {
+            self.num_unique += 1;
+        } else {
+            self.num_duplicate_sequences += 1;
+            self.num_duplicate_records += count;
+        }
+        if !self.allow_invalid {
+            x.validate()?;
+        }
+        match self.counts.as_mut() {
+            Some(counts) => {
+                write_count_row(counts, x, count)?;
+                self.output.write_item(x, &self.options)
+            }
+            None => {
+                let tagged = x.tagged(&format!("count={count}"))?;
+                self.output.write_item(&tagged, &self.options)
+            }
+        }
+    }
+
+    /// Deduplicates a group of items which contains every occurrence of its sequences
+    fn write_partition<T: DedupItem>(&mut self, items: Vec<T>) -> Result<()>
+    where
+        O: Output<T>,
+    {
+        if self.unique.collapse {
+            let mut collapsed = Collapsed::from_reader_by(items.into_iter(), &self.unique.key);
+            if self.unique.sort_by_abundance {
+                collapsed.sort_by_abundance();
+            }
+            for (x, count) in collapsed.groups() {
+                self.write_group(x, count)?;
+            }
+        } else {
+            let unique = Unique::from_reader_by(items.into_iter(), &self.unique.key);
+            for x in unique.passing_records() {
+                self.write_unique(x)?;
+            }
+            for group in unique.null.values() {
+                let kept = self.unique.keep.map(|keep| keep.select(group));
+                for (idx, x) in group.iter().enumerate() {
+                    self.write_duplicate(x, idx == 0, kept == Some(idx))?;
+                }
+            }
+        }
+        Ok(())
+    }
 
+    /// Completes all outputs and adds the tallies to the run report
+    fn finish<T>(self) -> Result<String>
+    where
+        O: Output<T>,
+    {
+        self.output.close()?;
+        if let Some(null) = self.null {
+            null.close()?;
+        }
+        if let Some(counts) = self.counts {
+            counts.finish()?;
+        }
+        if self.unique.collapse {
+            self.options.stats.section(
+                "unique",
+                json!({
+                    "num_records": self.num_records,
+                    "num_distinct_sequences": self.num_sequences,
+                }),
+            )?;
+            Ok(format!(
+                "Collapsed {} records into {} distinct sequences",
+                self.num_records, self.num_sequences
+            ))
+        } else {
+            self.options.stats.section(
+                "unique",
+                json!({
+                    "num_unique": self.num_unique,
+                    "num_duplicate_sequences": self.num_duplicate_sequences,
+                    "num_duplicate_records": self.num_duplicate_records,
+                }),
+            )?;
+            Ok(format!(
+                "Found {} unique records, {} duplicate sequences with {} records affected",
+                self.num_unique, self.num_duplicate_sequences, self.num_duplicate_records
+            ))
+        }
+    }
+}
+
+/// Offset of the number of records of a fingerprint written in the second pass
+const SEEN: u64 = 1 << 32;
+
+/// Deduplicates the input by fingerprints of its sequences, reading it twice
+fn dedup_fingerprints<T, O>(
+    input: Option<String>,
+    paired: &PairedOptions,
+    dedup: &mut Dedup<O>,
+) -> Result<()>
+where
+    T: DedupItem,
+    O: Output<T>,
+{
+    if input.is_none() {
+        bail!("Fingerprint deduplication reads the input twice and cannot read from stdin");
+    }
+    if dedup.unique.keep == Some(Keep::BestQuality) {
+        bail!("Keeping the best quality record is not supported with fingerprints");
+    }
+    let key = dedup.unique.key.clone();
+    let mut counts: HashMap<u128, u64> = HashMap::new();
+    for x in T::open(input.clone(), paired, &dedup.options.stats, false)? {
+        *counts.entry(fingerprint(&x?.dedup_key(&key))).or_default() += 1;
+    }
+    for x in T::open(input, paired, &dedup.options.stats, true)? {
+        let x = x?;
+        let Some(entry) = counts.get_mut(&fingerprint(&x.dedup_key(&key))) else {
+            bail!("Input changed between passes of the fingerprint deduplication");
+        };
+        let seen = (*entry / SEEN) as usize;
+        let count = (*entry % SEEN) as usize;
+        *entry += SEEN;
+        if dedup.unique.collapse {
+            if seen == 0 {
+                dedup.write_group(&x, count)?;
+            }
+        } else if count == 1 {
+            dedup.write_unique(&x)?;
+        } else {
+            let kept = match dedup.unique.keep {
+                Some(Keep::First) => seen == 0,
+                Some(Keep::Last) => seen + 1 == count,
+                Some(Keep::BestQuality) | None => false,
+            };
+            dedup.write_duplicate(&x, seen == 0, kept)?;
+        }
+    }
+    Ok(())
+}
+
+/// Deduplicates the input with the selected mode and writes the results
+fn run_dedup<T, O>(
+    input: Option<String>,
+    paired: &PairedOptions,
+    mut dedup: Dedup<O>,
+    mode: DedupMode,
+) -> Result<()>
+where
+    T: DedupItem,
+    O: Output<T>,
+{
     let mut spinner = Spinner::new_with_stream(
         Dots12,
-        "Determining Unique Records".to_string(),
+        if dedup.unique.collapse {
+            "Collapsing Duplicate Records"
+        } else {
+            "Determining Unique Records"
+        },
         Color::Green,
         Streams::Stderr,
     );
-    let unique = Unique::from_reader(reader);
-    spinner.stop_and_persist(
-        "✔",
-        &format!(
-            "Found {} unique records, {} duplicate sequences with {} records affected",
-            unique.num_passing(),
-            unique.num_null_sequences(),
-            unique.num_null_records()
-        ),
-    );
-
-    // write unique sequences
-    let mut unique_writer = match_output_stream(output, compression_threads, compression_level)?;
-    if allow_invalid {
-        write_output_with_invalid(
-            &mut unique_writer,
-            Box::new(unique.passing_records()),
-            &format_print,
-        );
-    } else {
-        write_output(
-            &mut unique_writer,
-            Box::new(unique.passing_records()),
-            &format_print,
-        );
-    }
-
-    // write null sequences if required
-    if null.is_some() {
-        let mut null_writer = match_output_stream(null, compression_threads, compression_level)?;
-        if allow_invalid {
-            write_output_with_invalid(
-                &mut null_writer,
-                Box::new(unique.null_records()),
-                &format_print,
-            );
-        } else {
-            write_output(
-                &mut null_writer,
-                Box::new(unique.null_records()),
-                &format_print,
-            );
+    match mode {
+        DedupMode::Fingerprint => dedup_fingerprints(input, paired, &mut dedup)?,
+        DedupMode::InMemory => {
+            let key = dedup.unique.key.clone();
+            partition(
+                T::open(input, paired, &dedup.options.stats, true)?,
+                &key,
+                usize::MAX,
+                0,
+                &mut |items| dedup.write_partition(items),
+            )?
+        }
+        DedupMode::Partitioned { max_memory } => {
+            let key = dedup.unique.key.clone();
+            partition(
+                T::open(input, paired, &dedup.options.stats, true)?,
+                &key,
+                max_memory,
+                0,
+                &mut |items| dedup.write_partition(items),
+            )?
         }
     }
+    let message = dedup.finish()?;
+    spinner.stop_and_persist("✔", &message);
     Ok(())
 }
 
+/// Runs the `unique` command.
+///
+/// Paired records are unique if the combination of both mate keys is unique. Null
+/// pairs are written interleaved.
+#[allow(clippy::too_many_arguments)]
+pub fn run(
+    path: Option<String>,
+    output: Option<String>,
+    null: Option<String>,
+    paired: PairedOptions,
+    options: OutputOptions,
+    allow_invalid: bool,
+    unique: UniqueOptions,
+    mode: DedupMode,
+) -> Result<()> {
+    if paired.is_paired() {
+        let writer = match_paired_writer(output, &paired, options.clone())?;
+        let null_writer = null
+            .map(|null| {
+                match_output_stream(Some(null), options.clone()).map(PairedWriter::interleaved)
+            })
+            .transpose()?;
+        let dedup = Dedup::new(writer, null_writer, unique, allow_invalid, options)?;
+        run_dedup::<RecordPair, _>(path, &paired, dedup, mode)
+    } else {
+        let writer = match_output_stream(output, options.clone())?;
+        let null_writer = null
+            .map(|null| match_output_stream(Some(null), options.clone()))
+            .transpose()?;
+        let dedup = Dedup::new(writer, null_writer, unique, allow_invalid, options)?;
+        run_dedup::<Record, _>(path, &paired, dedup, mode)
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::Unique;
-    use fxread::{FastaReader, FastqReader, FastxRead, Record};
+    use super::*;
+    use fxread::{FastaReader, FastqReader, FastxRead};
+    use std::io::Cursor;
+
+    impl Output<Record> for Vec<u8> {
+        fn write_item(&mut self, item: &Record, options: &OutputOptions) -> Result<()> {
+            write_record(self, item, options)
+        }
+
+        fn close(self) -> Result<()> {
+            Ok(())
+        }
+    }
 
     fn fasta_reader() -> Box<dyn FastxRead<Item = Record>> {
         let sequence: &'static [u8] = b">seq.0\nACT\n>seq.1\nACC\n>seq.2\nACT\n";
@@ -206,4 +906,216 @@ mod test {
         assert_eq!(unique.num_null_sequences(), 1);
         assert_eq!(unique.num_passing(), 1);
     }
+
+    #[test]
+    fn unique_pairs() {
+        let pairs = fasta_reader().zip(fastq_reader()).collect::<Vec<_>>();
+        let unique = Unique::from_reader(pairs.into_iter());
+        assert_eq!(unique.num_null_records(), 2);
+        assert_eq!(unique.num_passing(), 1);
+
+        // pairs are only duplicates if both mates match
+        let r2: &'static [u8] = b">seq.0\nAAA\n>seq.1\nACC\n>seq.2\nCCC\n";
+        let pairs = fasta_reader().zip(FastaReader::new(r2)).collect::<Vec<_>>();
+        let unique = Unique::from_reader(pairs.into_iter());
+        assert_eq!(unique.num_null_records(), 0);
+        assert_eq!(unique.num_passing(), 3);
+    }
+
+    #[test]
+    fn collapse_fasta() {
+        let sequence: &'static [u8] =
+            b">seq.0\nACC\n>seq.1\nACT\n>seq.2\nACT\n>seq.3\nACC\n>seq.4\nACT\n>seq.5\nGGG\n";
+        let mut collapsed = Collapsed::from_reader(FastaReader::new(sequence));
+        assert_eq!(collapsed.num_sequences(), 3);
+        assert_eq!(collapsed.num_records(), 6);
+        let groups = |c: &Collapsed| {
+            c.groups()
+                .map(|(r, count)| (r.id_str().to_string(), count))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            groups(&collapsed),
+            vec![
+                ("seq.0".into(), 2),
+                ("seq.1".into(), 3),
+                ("seq.5".into(), 1)
+            ]
+        );
+        collapsed.sort_by_abundance();
+        assert_eq!(
+            groups(&collapsed),
+            vec![
+                ("seq.1".into(), 3),
+                ("seq.0".into(), 2),
+                ("seq.5".into(), 1)
+            ]
+        );
+
+        let collapse = UniqueOptions {
+            collapse: true,
+            sort_by_abundance: true,
+            ..Default::default()
+        };
+        let mut dedup =
+            Dedup::new(Vec::new(), None, collapse, false, OutputOptions::default()).unwrap();
+        let records = FastaReader::new(sequence).collect::<Vec<_>>();
+        dedup.write_partition(records).unwrap();
+        assert_eq!(
+            dedup.output,
+            b">seq.1 count=3\nACT\n>seq.0 count=2\nACC\n>seq.5 count=1\nGGG\n"
+        );
+        assert_eq!(dedup.num_duplicate_records, 5);
+    }
+
+    #[test]
+    fn collapse_counts_table() {
+        let pairs = fasta_reader().zip(fastq_reader()).collect::<Vec<_>>();
+        let collapsed = Collapsed::from_reader(pairs.into_iter());
+        let mut output = Vec::new();
+        collapsed.write_counts(&mut output).unwrap();
+        assert_eq!(
+            output,
+            b"sequence\tcount\tid\nACT+ACT\t2\tseq.0\nACC+ACC\t1\tseq.1\n"
+        );
+    }
+
+    #[test]
+    fn sequence_fingerprints() {
+        assert_eq!(fingerprint(b"ACGT"), fingerprint(b"ACGT"));
+        assert_ne!(fingerprint(b"ACGT"), fingerprint(b"ACGA"));
+        assert_ne!(
+            fingerprint(b"ACGT") >> 64,
+            fingerprint(b"ACGT") & u128::from(u64::MAX)
+        );
+    }
+
+    #[test]
+    fn partitions_keep_duplicates_together() {
+        let sequence = (0..2000)
+            .map(|i| {
+                format!(
+                    ">seq.{i}\n{}\n",
+                    ["ACGT", "AAAA", "CCCC", "GGTT", "TTGA"][i % 5]
+                )
+            })
+            .collect::<String>();
+        let items: Items<Record> = Box::new(FastaReader::new(Cursor::new(sequence)).map(Ok));
+        let mut groups = Vec::new();
+        partition(items, &DedupKey::Sequence, 1000, 0, &mut |items: Vec<
+            Record,
+        >| {
+            groups.push(items);
+            Ok(())
+        })
+        .unwrap();
+        assert!(groups.len() > 1);
+        assert_eq!(groups.iter().map(Vec::len).sum::<usize>(), 2000);
+        // every sequence occurs in a single partition
+        let mut seen = HashMap::new();
+        for (idx, group) in groups.iter().enumerate() {
+            for record in group {
+                assert_eq!(*seen.entry(record.seq().to_vec()).or_insert(idx), idx);
+            }
+        }
+        assert_eq!(seen.len(), 5);
+    }
+
+    #[test]
+    fn partitioned_unique() {
+        let sequence: &'static [u8] = b">seq.0\nACC\n>seq.1\nACT\n>seq.2\nACT\n>seq.3\nGGG\n";
+        let items: Items<Record> = Box::new(FastaReader::new(sequence).map(Ok));
+        let mut dedup = Dedup::new(
+            Vec::new(),
+            Some(Vec::new()),
+            UniqueOptions::default(),
+            false,
+            OutputOptions::default(),
+        )
+        .unwrap();
+        partition(items, &DedupKey::Sequence, 1, 0, &mut |items| {
+            dedup.write_partition(items)
+        })
+        .unwrap();
+        assert_eq!(dedup.num_unique, 2);
+        assert_eq!(dedup.num_duplicate_sequences, 1);
+        assert_eq!(dedup.num_duplicate_records, 2);
+        assert_eq!(dedup.null.unwrap(), b">seq.1\nACT\n>seq.2\nACT\n");
+    }
+
+    #[test]
+    fn dedup_keys() {
+        assert_eq!(DedupKey::new(false, None).unwrap(), DedupKey::Sequence);
+        assert_eq!(DedupKey::new(true, None).unwrap(), DedupKey::Id);
+        assert!(DedupKey::new(true, Some("..2".into())).is_err());
+        let prefix = DedupKey::new(false, Some("..2".into())).unwrap();
+
+        let sequence: &'static [u8] = b">seq.0\nACC\n>seq.1\nACT\n>seq.0\nGGG\n>seq.2\nA\n";
+        let unique = Unique::from_reader_by(FastaReader::new(sequence), &DedupKey::Id);
+        assert_eq!(unique.num_passing(), 2);
+        assert_eq!(unique.num_null_records(), 2);
+
+        // ranges are truncated to the sequence length
+        let unique = Unique::from_reader_by(FastaReader::new(sequence), &prefix);
+        assert_eq!(unique.num_passing(), 2);
+        assert_eq!(unique.num_null_records(), 2);
+
+        let r2: &'static [u8] = b">seq.0\nTTA\n>seq.1\nTTC\n>seq.0\nGGA\n>seq.2\nTTT\n";
+        let pairs = FastaReader::new(sequence).zip(FastaReader::new(r2));
+        let keys = pairs.map(|p| p.dedup_key(&prefix)).collect::<Vec<_>>();
+        assert_eq!(keys[0], b"AC\nTT");
+        assert_eq!(keys[0], keys[1]);
+        assert_eq!(keys[3], b"A\nTT");
+    }
+
+    #[test]
+    fn keep_policies() {
+        let sequence: &'static [u8] =
+            b"@seq.0\nACT\n+\n#I#\n@seq.1\nGGG\n+\nIII\n@seq.2\nACT\n+\nIII\n@seq.3\nACT\n+\n###\n";
+        let kept = |keep: Keep| {
+            let unique = UniqueOptions {
+                keep: Some(keep),
+                ..Default::default()
+            };
+            let mut dedup = Dedup::new(
+                Vec::new(),
+                Some(Vec::new()),
+                unique,
+                false,
+                OutputOptions::default(),
+            )
+            .unwrap();
+            dedup
+                .write_partition(FastqReader::new(sequence).collect())
+                .unwrap();
+            assert_eq!(dedup.num_duplicate_records, 3);
+            let ids = |x: &[u8]| {
+                FastqReader::new(x)
+                    .map(|r| r.id_str().to_string())
+                    .collect::<Vec<_>>()
+            };
+            (ids(&dedup.output), ids(dedup.null.as_ref().unwrap()))
+        };
+        assert_eq!(
+            kept(Keep::First),
+            (
+                vec!["seq.1".into(), "seq.0".into()],
+                vec!["seq.2".into(), "seq.3".into()]
+            )
+        );
+        assert_eq!(
+            kept(Keep::Last),
+            (
+                vec!["seq.1".into(), "seq.3".into()],
+                vec!["seq.0".into(), "seq.2".into()]
+            )
+        );
+        assert_eq!(
+            kept(Keep::BestQuality),
+            (
+                vec!["seq.1".into(), "seq.2".into()],
+                vec!["seq.0".into(), "seq.3".into()]
+            )
+        );
+    }
 }
@@ -1,11 +1,12 @@
-use std::io::stdin;
-
-use super::{match_output_stream, write_mut_output, write_mut_output_with_invalid};
+use super::{
+    match_fastx_reader, match_output_stream, match_paired_reader, match_paired_writer,
+    process_pairs, process_records, validate_record, OutputOptions, PairedOptions,
+};
 use anyhow::Result;
-use fxread::{initialize_reader, initialize_stdin_reader, Record};
+use fxread::Record;
 
 /// Format prints the sequence as uppercase
-fn format_print(record: &mut Record) -> &str {
+pub fn format_print(record: &mut Record) -> &str {
     record.upper();
     record.as_str()
 }
@@ -14,28 +15,49 @@ fn format_print(record: &mut Record) -> &str {
 pub fn run(
     input: Option<String>,
     output: Option<String>,
-    compression_threads: Option<usize>,
-    compression_level: Option<usize>,
+    paired: PairedOptions,
+    options: OutputOptions,
+    threads: usize,
     allow_invalid: bool,
 ) -> Result<()> {
-    let reader = if let Some(path) = input {
-        initialize_reader(&path)
-    } else {
-        initialize_stdin_reader(stdin().lock())
-    }?;
-    let mut writer = match_output_stream(output, compression_threads, compression_level)?;
-    if allow_invalid {
-        write_mut_output_with_invalid(&mut writer, reader, &format_print);
-    } else {
-        write_mut_output(&mut writer, reader, &format_print);
+    if paired.is_paired() {
+        let reader = match_paired_reader(input, &paired, &options.stats)?;
+        let mut writer = match_paired_writer(output, &paired, options.clone())?;
+        process_pairs(
+            reader,
+            &mut writer,
+            threads,
+            &options,
+            |(mut r1, mut r2)| {
+                if !allow_invalid {
+                    validate_record(&r1)?;
+                    validate_record(&r2)?;
+                }
+                r1.upper();
+                r2.upper();
+                Ok(Some((r1, r2)))
+            },
+        )?;
+        return writer.finish();
     }
-    Ok(())
+    let reader = match_fastx_reader(input, &options.stats)?;
+    let mut writer = match_output_stream(output, options.clone())?;
+    process_records(reader, &mut writer, threads, &options, |mut record| {
+        if !allow_invalid {
+            validate_record(&record)?;
+        }
+        record.upper();
+        Ok(Some(record))
+    })?;
+    writer.finish()
 }
 
 #[cfg(test)]
 mod test {
-    use super::{format_print, match_output_stream, write_mut_output};
+    use super::{format_print, match_output_stream, OutputOptions};
+    use crate::commands::io::write_mut_output;
     use crate::commands::io::write_mut_output_with_invalid;
+    use crate::error::FxError;
     use fxread::{FastaReader, FastqReader, FastxRead, Record};
     use std::fs::File;
 
@@ -78,32 +100,60 @@ mod test {
     }
 
     #[test]
-    #[should_panic]
     fn run_invalid_fasta() {
         let reader = invalid_fasta_reader();
-        let mut writer = match_output_stream(None, None, None).unwrap();
-        write_mut_output(&mut writer, reader, &format_print)
+        let mut writer = match_output_stream(None, OutputOptions::default()).unwrap();
+        let result = write_mut_output(
+            &mut writer,
+            reader,
+            &Record::upper,
+            &OutputOptions::default(),
+        );
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<FxError>(),
+            Some(FxError::InvalidNucleotide { .. })
+        ));
     }
 
     #[test]
     fn run_invalid_fasta_allow_invalid() {
         let reader = invalid_fasta_reader();
         let mut writer = File::create("/dev/null").unwrap();
-        write_mut_output_with_invalid(&mut writer, reader, &format_print)
+        write_mut_output_with_invalid(
+            &mut writer,
+            reader,
+            &Record::upper,
+            &OutputOptions::default(),
+        )
+        .unwrap();
     }
 
     #[test]
-    #[should_panic]
     fn run_invalid_fastq() {
         let reader = invalid_fastq_reader();
-        let mut writer = match_output_stream(None, None, None).unwrap();
-        write_mut_output(&mut writer, reader, &format_print)
+        let mut writer = match_output_stream(None, OutputOptions::default()).unwrap();
+        let result = write_mut_output(
+            &mut writer,
+            reader,
+            &Record::upper,
+            &OutputOptions::default(),
+        );
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<FxError>(),
+            Some(FxError::InvalidNucleotide { .. })
+        ));
     }
 
     #[test]
     fn run_invalid_fastq_allow_invalid() {
         let reader = invalid_fastq_reader();
         let mut writer = File::create("/dev/null").unwrap();
-        write_mut_output_with_invalid(&mut writer, reader, &format_print)
+        write_mut_output_with_invalid(
+            &mut writer,
+            reader,
+            &Record::upper,
+            &OutputOptions::default(),
+        )
+        .unwrap();
     }
 }
@@ -0,0 +1,106 @@
+use std::io;
+use thiserror::Error;
+
+/// Exit code used when a record contains invalid nucleotides
+pub const EXIT_INVALID_NUCLEOTIDE: i32 = 3;
+
+/// Exit code used when a header or input field cannot be parsed
+pub const EXIT_MALFORMED_HEADER: i32 = 4;
+
+/// Exit code used for any other I/O failure
+pub const EXIT_IO: i32 = 5;
+
+/// Exit code used when the downstream reader closes the pipe (128 + SIGPIPE)
+pub const EXIT_BROKEN_PIPE: i32 = 141;
+
+/// Exit code used for all other errors
+pub const EXIT_FAILURE: i32 = 1;
+
+/// Errors raised while reading, validating, or writing records
+#[derive(Error, Debug)]
+pub enum FxError {
+    /// A record contains a character that is not a recognized nucleotide
+    #[error("Invalid nucleotide in record {id} at position {index}")]
+    InvalidNucleotide { id: String, index: usize },
+
+    /// A header or input field does not match the expected format
+    #[error("Malformed header: {0}")]
+    MalformedHeader(String),
+
+    /// Reading or writing failed
+    #[error(transparent)]
+    Io(io::Error),
+
+    /// The output was closed by the downstream reader
+    #[error("Broken pipe")]
+    BrokenPipe,
+}
+impl FxError {
+    /// Returns the process exit code associated with the error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::InvalidNucleotide { .. } => EXIT_INVALID_NUCLEOTIDE,
+            Self::MalformedHeader(_) => EXIT_MALFORMED_HEADER,
+            Self::Io(_) => EXIT_IO,
+            Self::BrokenPipe => EXIT_BROKEN_PIPE,
+        }
+    }
+}
+impl From<io::Error> for FxError {
+    fn from(error: io::Error) -> Self {
+        if error.kind() == io::ErrorKind::BrokenPipe {
+            Self::BrokenPipe
+        } else {
+            Self::Io(error)
+        }
+    }
+}
+
+/// Returns the process exit code for an error by searching its chain for a known cause
+pub fn exit_code(error: &anyhow::Error) -> i32 {
+    for cause in error.chain() {
+        if let Some(error) = cause.downcast_ref::<FxError>() {
+            return error.exit_code();
+        }
+        if let Some(error) = cause.downcast_ref::<io::Error>() {
+            return match error.kind() {
+                io::ErrorKind::BrokenPipe => EXIT_BROKEN_PIPE,
+                _ => EXIT_IO,
+            };
+        }
+    }
+    EXIT_FAILURE
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use anyhow::{anyhow, Context};
+
+    #[test]
+    fn exit_codes() {
+        let invalid = FxError::InvalidNucleotide {
+            id: "seq.0".to_string(),
+            index: 2,
+        };
+        assert_eq!(exit_code(&invalid.into()), EXIT_INVALID_NUCLEOTIDE);
+        let malformed = FxError::MalformedHeader("seq.0".to_string());
+        assert_eq!(exit_code(&malformed.into()), EXIT_MALFORMED_HEADER);
+        assert_eq!(exit_code(&anyhow!("other")), EXIT_FAILURE);
+    }
+
+    #[test]
+    fn io_exit_codes() {
+        let pipe = FxError::from(io::Error::from(io::ErrorKind::BrokenPipe));
+        assert!(matches!(pipe, FxError::BrokenPipe));
+        assert_eq!(exit_code(&pipe.into()), EXIT_BROKEN_PIPE);
+
+        let missing: anyhow::Error = io::Error::from(io::ErrorKind::NotFound).into();
+        assert_eq!(exit_code(&missing.context("Unable to open")), EXIT_IO);
+
+        let raw_pipe = Err::<(), _>(io::Error::from(io::ErrorKind::BrokenPipe))
+            .context("writing")
+            .unwrap_err();
+        assert_eq!(exit_code(&raw_pipe), EXIT_BROKEN_PIPE);
+    }
+}
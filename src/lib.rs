@@ -0,0 +1,29 @@
+//! # fxtools
+//!
+//! A collection of Fasta/Fastq utility tools.
+//!
+//! The `fxtools` binary is a thin wrapper around this library. Each subcommand is
+//! implemented in its own module under [`commands`] and exposes its transform as
+//! functions or structs that operate on [`fxread::Record`] iterators and generic
+//! [`std::io::Write`] sinks, so they can be embedded directly in other pipelines.
+//! Command runs count their records in the [`report::Stats`] handle of their
+//! [`commands::OutputOptions`] rather than in any process-wide state.
+//!
+//! ```
+//! use fxread::{FastaReader, Record};
+//! use fxtools::Unique;
+//!
+//! let fasta: &[u8] = b">seq.0\nACT\n>seq.1\nACC\n>seq.2\nACT\n";
+//! let unique = Unique::from_reader(FastaReader::new(fasta));
+//! assert_eq!(unique.num_passing(), 1);
+//! ```
+
+pub mod commands;
+pub mod error;
+pub mod report;
+
+pub use commands::extract::VariableRegion;
+pub use commands::sgrna_table::Table;
+pub use commands::trim::Trimmer;
+pub use commands::unique::Unique;
+pub use error::FxError;
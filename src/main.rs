@@ -1,12 +1,52 @@
 use anyhow::Result;
-use clap::Parser;
+use clap::{ArgMatches, CommandFactory, FromArgMatches};
 use cli::{Cli, Commands};
+use fxtools::{commands, error::exit_code, error::EXIT_BROKEN_PIPE, report};
+use serde_json::Value;
+use std::{collections::BTreeMap, process::ExitCode, time::Instant};
 
 mod cli;
-mod commands;
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(why) => {
+            let code = exit_code(&why);
+            // A closed downstream pipe is not an error worth reporting
+            if code != EXIT_BROKEN_PIPE {
+                eprintln!("Error: {:#}", why);
+            }
+            ExitCode::from(code as u8)
+        }
+    }
+}
+
+/// Collects the raw values of all arguments given to the subcommand
+fn parameters(matches: &ArgMatches) -> BTreeMap<String, Value> {
+    let mut parameters = BTreeMap::new();
+    for id in matches.ids() {
+        let Ok(Some(raw)) = matches.try_get_raw(id.as_str()) else {
+            continue;
+        };
+        let mut values = raw
+            .map(|value| Value::from(value.to_string_lossy()))
+            .collect::<Vec<_>>();
+        let value = if values.len() == 1 {
+            values.remove(0)
+        } else {
+            Value::from(values)
+        };
+        parameters.insert(id.to_string(), value);
+    }
+    parameters
+}
+
+fn run() -> Result<()> {
+    let start = Instant::now();
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|why| why.exit());
+    let stats = report::Stats::new();
+    let options = cli.output_options(&stats);
 
     match cli.command {
         Commands::Cat {
@@ -22,12 +62,29 @@ fn main() -> Result<()> {
                 sequence_only,
                 headers_only,
                 single_line,
-                cli.compression_threads,
-                cli.compression_level,
+                options,
             )?;
         }
-        Commands::Count { input } => {
-            commands::count::run(input)?;
+        Commands::Count {
+            input,
+            output,
+            stats,
+            format,
+            validate,
+            group_by_header,
+            group_by_seq,
+        } => {
+            let group_by = commands::count::GroupBy::new(group_by_header, group_by_seq)?;
+            commands::count::run(
+                input,
+                output,
+                stats,
+                format,
+                validate,
+                group_by,
+                options,
+                cli.threads,
+            )?;
         }
         Commands::Clip {
             input,
@@ -35,15 +92,41 @@ fn main() -> Result<()> {
             start,
             end,
             range,
+            quality_cutoff,
+            quality_cutoff_5p,
+            window_size,
+            window_quality,
+            poly_3p,
+            poly_5p,
+            poly_min_length,
+            poly_error_rate,
+            min_length,
+            paired,
         } => {
+            let quality = commands::clip::QualityTrim {
+                cutoff_3p: quality_cutoff,
+                cutoff_5p: quality_cutoff_5p,
+                window: window_size
+                    .zip(window_quality)
+                    .map(|(w, q)| (w as usize, q)),
+                poly: commands::clip::PolyTrim {
+                    bases_3p: poly_3p.unwrap_or_default().into_bytes(),
+                    bases_5p: poly_5p.unwrap_or_default().into_bytes(),
+                    min_length: poly_min_length,
+                    error_rate: poly_error_rate,
+                },
+                min_length,
+            };
             commands::clip::run(
                 input,
                 output,
                 start,
                 end,
                 range,
-                cli.compression_threads,
-                cli.compression_level,
+                quality,
+                paired,
+                options,
+                cli.threads,
             )?;
         }
         Commands::CsvToFasta {
@@ -53,28 +136,14 @@ fn main() -> Result<()> {
             sequence_col,
             delim,
         } => {
-            commands::csv::run(
-                input,
-                output,
-                header_col,
-                sequence_col,
-                delim,
-                cli.compression_threads,
-                cli.compression_level,
-            )?;
+            commands::csv::run(input, output, header_col, sequence_col, delim, options)?;
         }
         Commands::Disambiseq {
             input,
             output,
             include_parents,
         } => {
-            commands::disambiseq::run(
-                input,
-                output,
-                include_parents,
-                cli.compression_threads,
-                cli.compression_level,
-            )?;
+            commands::disambiseq::run(input, output, include_parents, options)?;
         }
         Commands::ExtractVariable {
             input,
@@ -82,14 +151,7 @@ fn main() -> Result<()> {
             num_samples,
             zscore_threshold,
         } => {
-            commands::extract::run(
-                &input,
-                output,
-                num_samples,
-                zscore_threshold,
-                cli.compression_threads,
-                cli.compression_level,
-            )?;
+            commands::extract::run(&input, output, num_samples, zscore_threshold, options)?;
         }
         Commands::Filter {
             input,
@@ -97,6 +159,8 @@ fn main() -> Result<()> {
             pattern,
             invert,
             header,
+            pair_filter,
+            paired,
         } => {
             commands::filter::run(
                 input,
@@ -104,17 +168,14 @@ fn main() -> Result<()> {
                 pattern,
                 invert,
                 header,
-                cli.compression_threads,
-                cli.compression_level,
+                pair_filter,
+                paired,
+                options,
+                cli.threads,
             )?;
         }
         Commands::Fix { input, output } => {
-            commands::fix::run(
-                input,
-                output,
-                cli.compression_threads,
-                cli.compression_level,
-            )?;
+            commands::fix::run(input, output, options, cli.threads)?;
         }
         Commands::Multiplex {
             input,
@@ -135,17 +196,11 @@ fn main() -> Result<()> {
                 barcode_size,
                 seed,
                 timeout,
-                cli.compression_threads,
-                cli.compression_level,
+                options,
             )?;
         }
         Commands::Reverse { input, output } => {
-            commands::reverse::run(
-                input,
-                output,
-                cli.compression_threads,
-                cli.compression_level,
-            )?;
+            commands::reverse::run(input, output, options, cli.threads)?;
         }
         Commands::Sample {
             input,
@@ -153,16 +208,9 @@ fn main() -> Result<()> {
             frequency,
             seed,
             quiet,
+            paired,
         } => {
-            commands::sample::run(
-                input,
-                output,
-                frequency,
-                seed,
-                quiet,
-                cli.compression_threads,
-                cli.compression_level,
-            )?;
+            commands::sample::run(input, output, frequency, seed, quiet, paired, options)?;
         }
         Commands::SgrnaTable {
             input,
@@ -179,23 +227,30 @@ fn main() -> Result<()> {
                 tss_ignore,
                 delim,
                 reorder,
+                &stats,
             )?;
         }
         Commands::Sort {
             r1,
             r2,
             prefix,
+            output_r1,
+            output_r2,
+            interleaved,
             gzip,
             sort_by_r1,
+            keys,
+            capture,
+            max_memory,
         } => {
-            commands::sort::run(
-                r1,
-                r2,
+            let output = commands::sort::SortOutput {
                 prefix,
+                paths: output_r1.zip(output_r2),
+                interleaved,
                 gzip,
-                sort_by_r1,
-                cli.compression_threads,
-                cli.compression_level,
+            };
+            commands::sort::run(
+                r1, r2, output, sort_by_r1, keys, capture, max_memory, options,
             )?;
         }
         Commands::T2g {
@@ -204,43 +259,54 @@ fn main() -> Result<()> {
             symbol,
             dot_version,
         } => {
-            commands::t2g::run(
-                input,
-                output,
-                symbol,
-                dot_version,
-                cli.compression_threads,
-                cli.compression_level,
-            )?;
+            commands::t2g::run(input, output, symbol, dot_version, options)?;
         }
         Commands::Take {
             input,
             output,
             num_records,
             skip,
+            paired,
         } => {
-            commands::take::run(
-                input,
-                output,
-                num_records,
-                skip,
-                cli.compression_threads,
-                cli.compression_level,
-            )?;
+            commands::take::run(input, output, num_records, skip, paired, options)?;
         }
         Commands::Trim {
             input,
             output,
             adapter,
+            adapter_file,
             trim_adapter,
+            three_prime,
+            error_rate,
+            indels,
+            min_overlap,
+            tag_errors,
+            untrimmed,
+            untrimmed_output,
+            untrimmed_output_r2,
+            pair_filter,
+            paired,
         } => {
+            let matching = commands::adapter::MatchOptions {
+                error_rate,
+                indels,
+                min_overlap: min_overlap.unwrap_or(usize::MAX),
+            };
+            let adapters = commands::trim::load_adapters(&adapter, adapter_file, &stats)?;
             commands::trim::run(
                 input,
-                &adapter,
+                adapters,
                 output,
-                trim_adapter,
-                cli.compression_threads,
-                cli.compression_level,
+                commands::adapter::KeepSide::new(trim_adapter, three_prime),
+                matching,
+                tag_errors,
+                untrimmed,
+                untrimmed_output,
+                untrimmed_output_r2,
+                pair_filter,
+                paired,
+                options,
+                cli.threads,
             )?;
         }
         Commands::Unique {
@@ -248,30 +314,73 @@ fn main() -> Result<()> {
             output,
             null,
             allow_invalid,
+            collapse,
+            counts,
+            sort_by_abundance,
+            fingerprint,
+            max_memory,
+            by_id,
+            by_range,
+            keep,
+            umi_header,
+            umi_range,
+            umi_distance,
+            umi_method,
+            umi_clusters,
+            paired,
         } => {
-            commands::unique::run(
-                input,
-                output,
-                null,
-                cli.compression_threads,
-                cli.compression_level,
-                allow_invalid,
-            )?;
+            let umi_source = commands::umi::UmiSource::new(umi_header, umi_range)?;
+            if let Some(source) = umi_source {
+                let umi = commands::umi::UmiOptions {
+                    source,
+                    distance: umi_distance,
+                    method: umi_method,
+                    clusters: umi_clusters,
+                };
+                commands::umi::run(input, output, paired, options, allow_invalid, umi)?;
+            } else {
+                let unique = commands::unique::UniqueOptions {
+                    key: commands::unique::DedupKey::new(by_id, by_range)?,
+                    keep,
+                    collapse,
+                    counts,
+                    sort_by_abundance,
+                };
+                let mode = match (fingerprint, max_memory) {
+                    (true, _) => commands::unique::DedupMode::Fingerprint,
+                    (false, Some(max_memory)) => {
+                        commands::unique::DedupMode::Partitioned { max_memory }
+                    }
+                    (false, None) => commands::unique::DedupMode::InMemory,
+                };
+                commands::unique::run(
+                    input,
+                    output,
+                    null,
+                    paired,
+                    options,
+                    allow_invalid,
+                    unique,
+                    mode,
+                )?;
+            }
         }
         Commands::Upper {
             input,
             output,
             allow_invalid,
+            paired,
         } => {
-            commands::upper::run(
-                input,
-                output,
-                cli.compression_threads,
-                cli.compression_level,
-                allow_invalid,
-            )?;
+            commands::upper::run(input, output, paired, options, cli.threads, allow_invalid)?;
         }
     };
 
+    if let Some(path) = cli.report {
+        let (command, submatches) = matches.subcommand().expect("subcommand is required");
+        stats
+            .report(command, parameters(submatches), start.elapsed())
+            .write(&path)?;
+    }
+
     Ok(())
 }
@@ -0,0 +1,228 @@
+use crate::commands::{match_output_stream, OutputOptions};
+use anyhow::Result;
+use fxread::{FastxRead, Record};
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// Record and base counters shared by all readers and writers of a run
+///
+/// Clones share the same counters. A run collects its statistics in the handle of its
+/// [`OutputOptions::stats`], so separate runs in the same process never mix counts.
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    counters: Arc<Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    records_read: AtomicU64,
+    bases_in: AtomicU64,
+    records_written: AtomicU64,
+    bases_out: AtomicU64,
+    inputs: Mutex<Vec<String>>,
+    sections: Mutex<BTreeMap<String, Value>>,
+}
+impl Stats {
+    /// Creates an empty set of counters
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts a record read from an input
+    pub fn read(&self, num_bases: usize) {
+        let counters = &self.counters;
+        counters.records_read.fetch_add(1, Ordering::Relaxed);
+        counters
+            .bases_in
+            .fetch_add(num_bases as u64, Ordering::Relaxed);
+    }
+
+    /// Counts records read without parsing (their bases are not counted)
+    pub fn read_unparsed(&self, num_records: usize) {
+        self.counters
+            .records_read
+            .fetch_add(num_records as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a record written to an output
+    pub fn written(&self, num_bases: usize) {
+        let counters = &self.counters;
+        counters.records_written.fetch_add(1, Ordering::Relaxed);
+        counters
+            .bases_out
+            .fetch_add(num_bases as u64, Ordering::Relaxed);
+    }
+
+    /// Registers an input path, ignoring inputs which were already opened
+    pub fn input(&self, path: &str) {
+        let mut inputs = self.counters.inputs.lock().unwrap();
+        if !inputs.iter().any(|x| x == path) {
+            inputs.push(path.to_string());
+        }
+    }
+
+    /// Sets the command-specific section of the report under the given name
+    pub fn section<S: Serialize>(&self, name: &str, section: S) -> Result<()> {
+        let value = serde_json::to_value(section)?;
+        self.counters
+            .sections
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), value);
+        Ok(())
+    }
+
+    /// Builds the report from the current counters
+    pub fn report(
+        &self,
+        command: &str,
+        parameters: BTreeMap<String, Value>,
+        wall_time: Duration,
+    ) -> Report {
+        let counters = &self.counters;
+        let records_read = counters.records_read.load(Ordering::Relaxed);
+        let records_written = counters.records_written.load(Ordering::Relaxed);
+        Report {
+            program: env!("CARGO_PKG_NAME"),
+            version: env!("CARGO_PKG_VERSION"),
+            command: command.to_string(),
+            inputs: counters.inputs.lock().unwrap().clone(),
+            parameters,
+            records_read,
+            records_written,
+            records_dropped: records_read.saturating_sub(records_written),
+            bases_in: counters.bases_in.load(Ordering::Relaxed),
+            bases_out: counters.bases_out.load(Ordering::Relaxed),
+            wall_time_seconds: wall_time.as_secs_f64(),
+            sections: counters.sections.lock().unwrap().clone(),
+        }
+    }
+}
+
+/// Machine-readable summary of a run
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    /// Name of the program
+    pub program: &'static str,
+    /// Version of the program
+    pub version: &'static str,
+    /// Subcommand which was run
+    pub command: String,
+    /// Input paths in the order they were opened (`-` for stdin)
+    pub inputs: Vec<String>,
+    /// Subcommand arguments including defaults
+    pub parameters: BTreeMap<String, Value>,
+    /// Records read across all inputs
+    pub records_read: u64,
+    /// Records written across all outputs
+    pub records_written: u64,
+    /// Records read which were not written to any output
+    pub records_dropped: u64,
+    /// Nucleotides read across all inputs
+    pub bases_in: u64,
+    /// Nucleotides written across all outputs
+    pub bases_out: u64,
+    /// Elapsed time of the run
+    pub wall_time_seconds: f64,
+    /// Command-specific statistics keyed by command name
+    pub sections: BTreeMap<String, Value>,
+}
+impl Report {
+    /// Writes the report as pretty-printed JSON to the provided path
+    pub fn write(&self, path: &str) -> Result<()> {
+        let mut writer = match_output_stream(Some(path.to_string()), OutputOptions::default())?;
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writer.finish()
+    }
+}
+
+/// Fastx reader which counts every record it yields in the run statistics
+pub struct CountingReader {
+    inner: Box<dyn FastxRead<Item = Record>>,
+    stats: Stats,
+}
+impl CountingReader {
+    /// Wraps the reader, counting its records in `stats`
+    pub fn new(inner: Box<dyn FastxRead<Item = Record>>, stats: Stats) -> Self {
+        Self { inner, stats }
+    }
+}
+impl Iterator for CountingReader {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = self.inner.next()?;
+        self.stats.read(record.seq().len());
+        Some(record)
+    }
+}
+impl FastxRead for CountingReader {
+    fn next_record(&mut self) -> Result<Option<Record>> {
+        let record = self.inner.next_record()?;
+        if let Some(record) = record.as_ref() {
+            self.stats.read(record.seq().len());
+        }
+        Ok(record)
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn report_counts() {
+        let stats = Stats::new();
+        stats.input("a.fq");
+        stats.input("b.fq");
+        stats.input("a.fq");
+        stats.read(4);
+        stats.read(6);
+        stats.read(2);
+        stats.written(3);
+        stats.section("trim", json!({"num_trimmed": 1})).unwrap();
+
+        let report = stats.report("trim", BTreeMap::new(), Duration::from_millis(1500));
+        assert_eq!(report.inputs, vec!["a.fq", "b.fq"]);
+        assert_eq!(report.records_read, 3);
+        assert_eq!(report.records_written, 1);
+        assert_eq!(report.records_dropped, 2);
+        assert_eq!(report.bases_in, 12);
+        assert_eq!(report.bases_out, 3);
+        assert_eq!(report.wall_time_seconds, 1.5);
+        assert_eq!(report.sections["trim"]["num_trimmed"], 1);
+    }
+
+    #[test]
+    fn report_handles() {
+        let stats = Stats::new();
+        let reader = crate::commands::io::fastx_reader(&b">a\nACGT\n>b\nAC\n"[..]).unwrap();
+        let records = CountingReader::new(reader, stats.clone()).count();
+        assert_eq!(records, 2);
+
+        // clones share their counters while separate handles are independent
+        stats.clone().written(2);
+        Stats::new().written(4);
+        let report = stats.report("cat", BTreeMap::new(), Duration::ZERO);
+        assert_eq!((report.records_read, report.bases_in), (2, 6));
+        assert_eq!((report.records_written, report.bases_out), (1, 2));
+    }
+
+    #[test]
+    fn report_json() {
+        let report = Stats::new().report("cat", BTreeMap::new(), Duration::ZERO);
+        let value = serde_json::to_value(&report).unwrap();
+        assert_eq!(value["command"], "cat");
+        assert_eq!(value["version"], env!("CARGO_PKG_VERSION"));
+        assert!(value["sections"].as_object().unwrap().is_empty());
+    }
+}